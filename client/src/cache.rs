@@ -0,0 +1,304 @@
+//! Local Hash Cache
+//!
+//! Hashes of local files are kept in `hash_cache.json` next to the config, keyed by the path
+//! of the file together with its size, modification time and inode. As long as none of them
+//! changed the file isn't read again, any change drops the hashes kept for it.
+
+use common::hashing::{self, HashAlgorithm};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::sync::{Mutex, OnceCell};
+
+// Cache file, next to config.toml
+const CACHE_FILE: &str = "hash_cache.json";
+
+// The cache of this process, shared by all transfers
+static SHARED: OnceCell<HashCache> = OnceCell::const_new();
+
+// What a file looked like when it was hashed
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+struct Stamp {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+    inode: u64,
+}
+
+impl Stamp {
+    async fn of(path: &Path) -> common::Result<Stamp> {
+        let metadata = tokio::fs::metadata(path).await?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Stamp {
+            size: metadata.len(),
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+            inode: inode(&metadata),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+// not available, size and modification time have to do
+#[cfg(not(unix))]
+fn inode(_metadata: &std::fs::Metadata) -> u64 {
+    0
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Entry {
+    #[serde(flatten)]
+    stamp: Stamp,
+    hashes: HashMap<HashAlgorithm, String>,
+}
+
+/// Hashes a local file with `algorithm`, through the shared cache if `cached`
+pub async fn hash_file<F>(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    cached: bool,
+    buffer_size: usize,
+    on_progress: F,
+) -> common::Result<String>
+where
+    F: FnMut(usize),
+{
+    if cached {
+        HashCache::shared()
+            .await
+            .hash_file(path, algorithm, buffer_size, on_progress)
+            .await
+    } else {
+        hashing::hash_file_with(path, algorithm, buffer_size, on_progress).await
+    }
+}
+
+/// Hashes of local files remembered between runs
+pub struct HashCache {
+    file: PathBuf,
+    // by absolute path
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl HashCache {
+    /// Opens the cache kept in `file`, an unreadable one starts out empty
+    pub async fn open(file: PathBuf) -> HashCache {
+        let entries = match tokio::fs::read(&file).await {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_default(),
+            Err(_) => HashMap::new(),
+        };
+        HashCache {
+            file,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// The cache next to the config
+    pub async fn shared() -> &'static HashCache {
+        SHARED
+            .get_or_init(|| HashCache::open(PathBuf::from(CACHE_FILE)))
+            .await
+    }
+
+    /// Hashes the file at `path` with `algorithm`, unless it is known unchanged
+    pub async fn hash_file<F>(
+        &self,
+        path: &Path,
+        algorithm: HashAlgorithm,
+        buffer_size: usize,
+        on_progress: F,
+    ) -> common::Result<String>
+    where
+        F: FnMut(usize),
+    {
+        // stamped before hashing, a change meanwhile makes the entry stale right away
+        let (key, stamp) = match self.lookup(path, algorithm).await? {
+            Lookup::Hit(hash) => return Ok(hash),
+            Lookup::Miss(key, stamp) => (key, stamp),
+        };
+        let hash = hashing::hash_file_with(path, algorithm, buffer_size, on_progress).await?;
+        if let Some(key) = key {
+            self.store(vec![(key, stamp, hash.clone())], algorithm)
+                .await?;
+        }
+        Ok(hash)
+    }
+
+    /// Hashes several files at once, the ones known unchanged aren't read
+    pub async fn hash_files(
+        &self,
+        paths: &[PathBuf],
+        algorithm: HashAlgorithm,
+        buffer_size: usize,
+    ) -> common::Result<Vec<common::Result<String>>> {
+        let mut results = Vec::with_capacity(paths.len());
+        let mut missing = Vec::new();
+        for path in paths {
+            match self.lookup(path, algorithm).await {
+                Ok(Lookup::Hit(hash)) => results.push(Some(Ok(hash))),
+                Ok(Lookup::Miss(key, stamp)) => {
+                    missing.push((results.len(), key, stamp));
+                    results.push(None);
+                }
+                Err(e) => results.push(Some(Err(e))),
+            }
+        }
+
+        let to_hash = missing
+            .iter()
+            .map(|&(index, ..)| paths[index].clone())
+            .collect();
+        let hashes = hashing::hash_files(to_hash, algorithm, buffer_size).await?;
+        let mut hashed = Vec::new();
+        for ((index, key, stamp), hash) in missing.into_iter().zip(hashes) {
+            if let (Some(key), Ok(hash)) = (key, &hash) {
+                hashed.push((key, stamp, hash.clone()));
+            }
+            results[index] = Some(hash);
+        }
+        if !hashed.is_empty() {
+            self.store(hashed, algorithm).await?;
+        }
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    // The cached hash of `path`, or its key (None for paths that can't be kept) and stamp
+    async fn lookup(&self, path: &Path, algorithm: HashAlgorithm) -> common::Result<Lookup> {
+        let stamp = Stamp::of(path).await?;
+        let key = tokio::fs::canonicalize(path)
+            .await?
+            .to_str()
+            .map(str::to_string);
+        let entries = self.entries.lock().await;
+        let cached = key
+            .as_ref()
+            .and_then(|key| entries.get(key))
+            .filter(|entry| entry.stamp == stamp)
+            .and_then(|entry| entry.hashes.get(&algorithm));
+        Ok(match cached {
+            Some(hash) => Lookup::Hit(hash.clone()),
+            None => Lookup::Miss(key, stamp),
+        })
+    }
+
+    // Remembers the `algorithm` hashes of stamped files and writes the cache back
+    async fn store(
+        &self,
+        hashed: Vec<(String, Stamp, String)>,
+        algorithm: HashAlgorithm,
+    ) -> common::Result<()> {
+        let mut entries = self.entries.lock().await;
+        for (key, stamp, hash) in hashed {
+            let entry = entries.entry(key).or_insert_with(|| Entry {
+                stamp,
+                hashes: HashMap::new(),
+            });
+            // hashes of what the file was before don't hold anymore
+            if entry.stamp != stamp {
+                entry.stamp = stamp;
+                entry.hashes.clear();
+            }
+            entry.hashes.insert(algorithm, hash);
+        }
+
+        // written aside then renamed, a crash never leaves half a cache
+        let staged = self.file.with_extension("json.tmp");
+        tokio::fs::write(&staged, serde_json::to_vec(&*entries)?).await?;
+        tokio::fs::rename(&staged, &self.file).await?;
+        Ok(())
+    }
+}
+
+enum Lookup {
+    Hit(String),
+    Miss(Option<String>, Stamp),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hash_cache() -> common::Result<()> {
+        let dir = std::env::temp_dir().join(format!("veriflow-cache-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let cache_file = dir.join("hash_cache.json");
+        let path = dir.join("notes.txt");
+        tokio::fs::write(&path, b"first").await?;
+
+        let cache = HashCache::open(cache_file.clone()).await;
+        let mut read = 0;
+        let hash = cache
+            .hash_file(
+                &path,
+                HashAlgorithm::Sha256,
+                hashing::DEFAULT_BUFFER_SIZE,
+                |bytes| read += bytes,
+            )
+            .await?;
+        assert_eq!(hash, hashing::hash_bytes(b"first"));
+        assert_eq!(read, 5);
+
+        // unchanged, it isn't read again, not even after reopening the cache
+        let cache = HashCache::open(cache_file.clone()).await;
+        let mut read = 0;
+        let cached = cache
+            .hash_file(
+                &path,
+                HashAlgorithm::Sha256,
+                hashing::DEFAULT_BUFFER_SIZE,
+                |bytes| read += bytes,
+            )
+            .await?;
+        assert_eq!((cached, read), (hash, 0));
+
+        // another algorithm is hashed on its own
+        let blake3 = cache
+            .hash_file(
+                &path,
+                HashAlgorithm::Blake3,
+                hashing::DEFAULT_BUFFER_SIZE,
+                |_| {},
+            )
+            .await?;
+        assert_eq!(
+            blake3,
+            hashing::hash_bytes_with(b"first", HashAlgorithm::Blake3)
+        );
+
+        // a changed size or inode makes it stale
+        tokio::fs::write(&path, b"second").await?;
+        let hashes = cache
+            .hash_files(
+                &[path.clone(), dir.join("missing")],
+                HashAlgorithm::Sha256,
+                hashing::DEFAULT_BUFFER_SIZE,
+            )
+            .await?;
+        assert_eq!(
+            hashes[0].as_ref().ok(),
+            Some(&hashing::hash_bytes(b"second"))
+        );
+        assert!(hashes[1].is_err());
+
+        let replaced = dir.join("replaced.txt");
+        tokio::fs::write(&replaced, b"second").await?;
+        tokio::fs::rename(&replaced, &path).await?;
+        assert!(matches!(
+            cache.lookup(&path, HashAlgorithm::Sha256).await?,
+            Lookup::Miss(..)
+        ));
+
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+//! Hashes of local files
+//!
+//! Prints the hash of every given file, directories are walked, in the `<hash>  <path>`
+//! format of sha256sum. The files are hashed at once (see 'hashing::hash_files'), unless the
+//! hash cache knows them unchanged.
+
+use crate::cache::HashCache;
+use common::hashing::{self, HashAlgorithm};
+use common::VeriflowError;
+use std::path::PathBuf;
+
+/// Prints the hashes of `paths` and the files below directories among them
+///
+/// Unchanged files are taken from the hash cache if `cached`, the others are read
+/// `buffer_size` bytes at once
+pub async fn print_hashes(
+    paths: Vec<PathBuf>,
+    algorithm: HashAlgorithm,
+    cached: bool,
+    buffer_size: usize,
+) -> common::Result<()> {
+    let files = expand(paths).await?;
+    let hashes = if cached {
+        HashCache::shared()
+            .await
+            .hash_files(&files, algorithm, buffer_size)
+            .await?
+    } else {
+        hashing::hash_files(files.clone(), algorithm, buffer_size).await?
+    };
+
+    let mut failed = 0;
+    for (path, hash) in files.iter().zip(hashes) {
+        match hash {
+            Ok(hash) => println!("{hash}  {}", path.display()),
+            Err(e) => {
+                eprintln!("{}: {e}", path.display());
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(VeriflowError::Io(std::io::Error::other(format!(
+            "{failed} of {} files couldn't be hashed",
+            files.len()
+        ))));
+    }
+    Ok(())
+}
+
+// Files in `paths`, directories replaced by the files below them in sorted order
+async fn expand(paths: Vec<PathBuf>) -> common::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !tokio::fs::metadata(&path).await?.is_dir() {
+            files.push(path);
+            continue;
+        }
+        let mut found = Vec::new();
+        let mut stack = vec![path];
+        while let Some(dir) = stack.pop() {
+            let mut dir_content = tokio::fs::read_dir(dir).await?;
+            while let Some(entry) = dir_content.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    stack.push(entry.path());
+                } else if file_type.is_file() {
+                    found.push(entry.path());
+                }
+            }
+        }
+        found.sort();
+        files.extend(found);
+    }
+    Ok(files)
+}
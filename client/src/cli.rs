@@ -1,6 +1,7 @@
 //! CLI Arg Parsing Struct
 
 use clap::{Parser, Subcommand};
+use common::hashing::HashAlgorithm;
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -23,13 +24,13 @@ pub enum Commands {
         #[arg(short, long)]
         ip: Option<String>,
 
-        /// Upload file to server
-        #[arg(short, long, group = "operation")]
-        upload: Option<PathBuf>,
+        /// Upload files to server (several are transferred in a queue)
+        #[arg(short, long, group = "operation", num_args = 1..)]
+        upload: Vec<PathBuf>,
 
-        /// Download file from server
-        #[arg(short, long, group = "operation")]
-        download: Option<PathBuf>,
+        /// Download files from server (several are transferred in a queue)
+        #[arg(short, long, group = "operation", num_args = 1..)]
+        download: Vec<PathBuf>,
 
         /// Delete file from server (full flag required for precaution)
         #[arg(long, group = "operation")]
@@ -38,6 +39,100 @@ pub enum Commands {
         /// List all files on server
         #[arg(short, long, group = "operation")]
         list: bool,
+
+        /// Encrypt uploads / decrypt downloads client-side (server only stores ciphertext)
+        #[arg(short, long)]
+        encrypt: bool,
+
+        /// Key file used for encryption instead of a passphrase (overrides config)
+        #[arg(short, long, requires = "encrypt")]
+        key_file: Option<PathBuf>,
+
+        /// Upload in content-defined chunks, only chunks the server is missing are sent
+        #[arg(short, long, requires = "upload")]
+        chunked: bool,
+
+        /// Compress the transfer with zstd at LEVEL (1-22, 3 if omitted), skipped for compressed file types
+        #[arg(
+            short = 'z',
+            long,
+            value_name = "LEVEL",
+            num_args = 0..=1,
+            default_missing_value = "3",
+            value_parser = clap::value_parser!(i32).range(1..=22),
+            conflicts_with_all = ["encrypt", "chunked"]
+        )]
+        compress: Option<i32>,
+
+        /// Transfer over N connections at once, each carrying a byte range of the file (2-16,
+        /// capped to stay within the server's connection limits)
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            value_parser = clap::value_parser!(u32).range(2..=16),
+            conflicts_with_all = ["encrypt", "chunked"]
+        )]
+        streams: Option<u32>,
+
+        /// Hash uploads while sending them, the hash follows the body (reads each file once)
+        #[arg(short, long, requires = "upload", conflicts_with_all = ["chunked", "streams"])]
+        trailer: bool,
+
+        /// Send file bodies in checksummed frames, corrupted chunks are sent again
+        #[arg(long, conflicts_with = "chunked")]
+        integrity: bool,
+
+        /// Hash uploads with ALGO (sha256, sha512 or blake3), SHA256 if the server doesn't accept it
+        #[arg(
+            long,
+            value_name = "ALGO",
+            default_value_t = HashAlgorithm::Sha256,
+            requires = "upload",
+            conflicts_with = "chunked"
+        )]
+        hash: HashAlgorithm,
+
+        /// Files transferred at once when several are given
+        #[arg(
+            short,
+            long,
+            value_name = "N",
+            default_value_t = 4,
+            value_parser = clap::value_parser!(u32).range(1..=16)
+        )]
+        jobs: u32,
+
+        /// Retries of a file in a queue failing with a network error, waiting longer each time
+        #[arg(short, long, value_name = "N", default_value_t = 3)]
+        retries: u32,
+    },
+
+    /// Show storage used and available on the server
+    Usage {
+        ///  IP of the server (host is added automatically as per config)
+        #[arg(short, long)]
+        ip: Option<String>,
+    },
+
+    /// Deleted files waiting in the server's trash (list, restore, purge)
+    Trash {
+        ///  IP of the server (host is added automatically as per config)
+        #[arg(short, long, global = true)]
+        ip: Option<String>,
+
+        #[command(subcommand)]
+        action: TrashAction,
+    },
+
+    /// Previous versions of overwritten files (list, download, restore)
+    Versions {
+        ///  IP of the server (host is added automatically as per config)
+        #[arg(short, long, global = true)]
+        ip: Option<String>,
+
+        #[command(subcommand)]
+        action: VersionAction,
     },
 
     /// Set configuration file values (ip, port, dir)
@@ -53,5 +148,94 @@ pub enum Commands {
         /// Set new download directory
         #[arg(short, long)]
         dir: Option<String>,
+
+        /// Set default encryption key file
+        #[arg(short, long)]
+        key_file: Option<PathBuf>,
+
+        /// Set the key file uploads are signed with
+        #[arg(short, long)]
+        signing_key: Option<PathBuf>,
+
+        /// Trust downloads signed by this public key (hex)
+        #[arg(short, long)]
+        trust: Option<String>,
+    },
+
+    /// Print the hashes of local files, directories are walked
+    Hash {
+        /// Files and directories to hash
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+
+        /// Hash with ALGO (sha256, sha512 or blake3)
+        #[arg(long, value_name = "ALGO", default_value_t = HashAlgorithm::Sha256)]
+        hash: HashAlgorithm,
+    },
+
+    /// Generate a signing key, its public key is trusted right away
+    Keygen {
+        /// Where to write the secret key
+        output: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TrashAction {
+    /// List trashed files/folders with their ids
+    List,
+
+    /// Move a trashed file/folder back to where it was deleted from
+    Restore {
+        /// Id shown by `trash list`
+        id: u64,
+    },
+
+    /// Permanently remove a trash entry (the whole trash if no id is given)
+    Purge {
+        /// Id shown by `trash list`
+        id: Option<u64>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum VersionAction {
+    /// List the kept versions of a file, newest first
+    List {
+        /// File on the server
+        file: PathBuf,
+    },
+
+    /// Download a previous version of a file
+    Download {
+        /// File on the server
+        file: PathBuf,
+
+        /// Version hash (or its first characters) shown by `versions list`
+        hash: String,
+
+        /// Compress the transfer with zstd at LEVEL (1-22, 3 if omitted), skipped for compressed file types
+        #[arg(
+            short = 'z',
+            long,
+            value_name = "LEVEL",
+            num_args = 0..=1,
+            default_missing_value = "3",
+            value_parser = clap::value_parser!(i32).range(1..=22)
+        )]
+        compress: Option<i32>,
+
+        /// Send the file body in checksummed frames, corrupted chunks are sent again
+        #[arg(long)]
+        integrity: bool,
+    },
+
+    /// Make a previous version the current contents of a file
+    Restore {
+        /// File on the server
+        file: PathBuf,
+
+        /// Version hash (or its first characters) shown by `versions list`
+        hash: String,
     },
 }
@@ -1,5 +1,6 @@
 //! Client Config Struct
 
+use common::protocol::Buffers;
 use common::VeriflowError;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
@@ -11,6 +12,11 @@ pub struct ClientConfig {
     pub ip: String,
     pub port: String,
     pub download_dir: PathBuf,
+    pub key_file: Option<PathBuf>,    // default key for --encrypt
+    pub signing_key: Option<PathBuf>, // uploads are signed when set
+    pub trusted_keys: Vec<String>,    // hex public keys downloads must be signed by
+    pub hash_cache: bool,             // remember hashes of unchanged local files
+    pub buffers: Buffers,             // chunk sizes of file bodies and hashing
 }
 
 // Skeleton for the config file
@@ -20,6 +26,11 @@ impl Default for ClientConfig {
             ip: String::from("127.0.0.1"),
             port: String::from("8080"),
             download_dir: PathBuf::from("../Veriflow/Downloads"),
+            key_file: None,
+            signing_key: None,
+            trusted_keys: Vec::new(),
+            hash_cache: true,
+            buffers: Buffers::default(),
         }
     }
 }
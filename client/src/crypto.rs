@@ -0,0 +1,438 @@
+//! Client-side (End-to-End) Encryption
+//!
+//! Files are encrypted before they leave the client, so the server only ever stores and hashes ciphertext.
+//!
+//! Encrypted file layout:
+//! `MAGIC | KDF | SALT | NONCE PREFIX | METADATA LEN (u32 BE) | METADATA BLOCK | DATA CHUNKS`
+//!
+//! The metadata block (original name, size and SHA256 hash) is the first segment of a
+//! ChaCha20-Poly1305 STREAM, the data chunks follow it, so segments cannot be reordered,
+//! swapped or truncated without decryption failing.
+
+use crate::{cache, ui};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{KeyInit, OsRng, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use common::hashing::{Checksum, HashAlgorithm};
+use common::{hashing, VeriflowError};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Identifies a Veriflow encrypted file (and its format version)
+const MAGIC: &[u8; 8] = b"VFCRYPT1";
+
+// Key derivation function tags stored in the header
+const KDF_PASSPHRASE: u8 = 1;
+const KDF_KEY_FILE: u8 = 2;
+
+const SALT_LEN: usize = 16;
+// ChaCha20Poly1305 nonce (12B) minus the STREAM counter and last-block flag (5B)
+const NONCE_PREFIX_LEN: usize = 7;
+const HEADER_LEN: usize = MAGIC.len() + 1 + SALT_LEN + NONCE_PREFIX_LEN;
+
+// Plaintext bytes per encrypted chunk (64kb)
+const CHUNK_SIZE: usize = 65536;
+// Poly1305 authentication tag appended to every chunk
+const TAG_LEN: usize = 16;
+
+// Max metadata block size, the block only holds a name, a size and a hash
+const MAX_METADATA_SIZE: usize = 4096;
+
+// Minimum key file length (128 bits of key material)
+const MIN_KEY_FILE_LEN: usize = 16;
+
+/// Where the encryption key comes from
+pub enum KeySource {
+    /// Passphrase stretched with Argon2id
+    Passphrase(String),
+    /// Key file with high-entropy bytes, expanded with HKDF-SHA256
+    KeyFile(PathBuf),
+}
+
+/// Encrypted metadata block stored in front of the file data
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Metadata {
+    pub name: String,
+    pub size: u64,
+    pub hash: String, // SHA256 of the plaintext
+}
+
+impl KeySource {
+    /// Picks the key file if one is configured, otherwise asks for a passphrase
+    ///
+    /// The passphrase is taken from `VERIFLOW_PASSPHRASE` when set, else prompted for (twice when `confirm` is set)
+    pub fn resolve(key_file: Option<PathBuf>, confirm: bool) -> common::Result<KeySource> {
+        if let Some(path) = key_file {
+            return Ok(KeySource::KeyFile(path));
+        }
+
+        if let Ok(passphrase) = std::env::var("VERIFLOW_PASSPHRASE") {
+            return Ok(KeySource::Passphrase(passphrase));
+        }
+
+        let passphrase = rpassword::prompt_password("Passphrase: ")?;
+        if confirm {
+            let repeated = rpassword::prompt_password("Confirm passphrase: ")?;
+            if passphrase != repeated {
+                return Err(VeriflowError::Crypto(
+                    "Passphrases do not match".to_string(),
+                ));
+            }
+        }
+        if passphrase.is_empty() {
+            return Err(VeriflowError::Crypto("Passphrase is empty".to_string()));
+        }
+
+        Ok(KeySource::Passphrase(passphrase))
+    }
+
+    // header tag for this key source
+    fn kdf_tag(&self) -> u8 {
+        match self {
+            KeySource::Passphrase(_) => KDF_PASSPHRASE,
+            KeySource::KeyFile(_) => KDF_KEY_FILE,
+        }
+    }
+
+    /// Derive the 256-bit file key for the given salt
+    async fn derive_key(&self, salt: &[u8]) -> common::Result<[u8; 32]> {
+        let mut key = [0u8; 32];
+        match self {
+            KeySource::Passphrase(passphrase) => {
+                Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+                    .map_err(|e| VeriflowError::Crypto(format!("Key derivation failed: {e}")))?;
+            }
+            KeySource::KeyFile(path) => {
+                let key_material = tokio::fs::read(path).await?;
+                if key_material.len() < MIN_KEY_FILE_LEN {
+                    return Err(VeriflowError::Crypto(format!(
+                        "Key file must contain at least {MIN_KEY_FILE_LEN} bytes"
+                    )));
+                }
+                Hkdf::<Sha256>::new(Some(salt), &key_material)
+                    .expand(b"veriflow file encryption", &mut key)
+                    .map_err(|e| VeriflowError::Crypto(format!("Key derivation failed: {e}")))?;
+            }
+        }
+        Ok(key)
+    }
+}
+
+// Maps the opaque AEAD error into ours
+fn aead_error(_: chacha20poly1305::aead::Error) -> VeriflowError {
+    VeriflowError::Crypto("Decryption failed: wrong key or tampered data".to_string())
+}
+
+/// Unguessable path in `dir` to stage the ciphertext of `file_name` at
+pub fn staging_path(dir: &Path, file_name: &str) -> PathBuf {
+    let mut random = [0u8; 16];
+    OsRng.fill_bytes(&mut random);
+    dir.join(format!(".{file_name}.{}.vfcrypt", hex::encode(random)))
+}
+
+/// Encrypts `input` into `output`, returns the size of the encrypted file
+///
+/// `output` must not exist yet, it is created readable by the owner only.
+/// The plaintext hash comes from the hash cache if `cached`, files are hashed `hash_buffer_size` bytes at once
+pub async fn encrypt_file(
+    input: &Path,
+    output: &Path,
+    key: &KeySource,
+    cached: bool,
+    hash_buffer_size: usize,
+) -> common::Result<u64> {
+    // get file name -- Strict error handling (Allow ONLY UTF-8 characters)
+    let name = input
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(VeriflowError::InvalidPath)?
+        .to_string();
+
+    let mut plain_file = File::open(input).await?;
+    let size = plain_file.metadata().await?.len();
+
+    // Hash plaintext, the hash travels inside the encrypted metadata block
+    let progress_bar = ui::create_progress_bar(size, "Hashing ...");
+    let hash = cache::hash_file(
+        input,
+        HashAlgorithm::Sha256,
+        cached,
+        hash_buffer_size,
+        |bytes_read| progress_bar.inc(bytes_read as u64),
+    )
+    .await?;
+    progress_bar.finish_with_message("Hashing Complete!");
+
+    // Fresh salt and nonce prefix for every file
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce_prefix);
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.push(key.kdf_tag());
+    header.extend_from_slice(&salt);
+    header.extend_from_slice(&nonce_prefix);
+
+    let file_key = key.derive_key(&salt).await?;
+    let cipher = ChaCha20Poly1305::new(&file_key.into());
+    let mut encryptor = EncryptorBE32::from_aead(cipher, nonce_prefix.as_slice().into());
+
+    // Metadata block, bound to the header via associated data
+    let metadata = serde_json::to_vec(&Metadata { name, size, hash })?;
+    let encrypted_metadata = encryptor
+        .encrypt_next(Payload {
+            msg: &metadata,
+            aad: &header,
+        })
+        .map_err(aead_error)?;
+
+    // never an existing file or a link planted at the path
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut encrypted_file = options.open(output).await?;
+    encrypted_file.write_all(&header).await?;
+    encrypted_file
+        .write_all(&(encrypted_metadata.len() as u32).to_be_bytes())
+        .await?;
+    encrypted_file.write_all(&encrypted_metadata).await?;
+
+    let progress_bar = ui::create_progress_bar(size, "Encrypting ...");
+
+    // Every full chunk, then the (possibly empty) remainder sealed as the last segment
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let full_chunks = size / CHUNK_SIZE as u64;
+    let remainder = (size % CHUNK_SIZE as u64) as usize;
+
+    for _ in 0..full_chunks {
+        plain_file.read_exact(&mut buffer).await?;
+        let chunk = encryptor
+            .encrypt_next(buffer.as_slice())
+            .map_err(aead_error)?;
+        encrypted_file.write_all(&chunk).await?;
+        progress_bar.inc(CHUNK_SIZE as u64);
+    }
+
+    plain_file.read_exact(&mut buffer[..remainder]).await?;
+    let last_chunk = encryptor
+        .encrypt_last(&buffer[..remainder])
+        .map_err(aead_error)?;
+    encrypted_file.write_all(&last_chunk).await?;
+    progress_bar.inc(remainder as u64);
+
+    encrypted_file.flush().await?;
+    progress_bar.finish_with_message("Encryption Complete!");
+
+    Ok(encrypted_file.metadata().await?.len())
+}
+
+/// Decrypts `input` into `output` and verifies the plaintext against the hash in the metadata block
+pub async fn decrypt_file(
+    input: &Path,
+    output: &Path,
+    key: &KeySource,
+    hash_buffer_size: usize,
+) -> common::Result<Metadata> {
+    let mut encrypted_file = File::open(input).await?;
+
+    // Header
+    let mut header = [0u8; HEADER_LEN];
+    encrypted_file
+        .read_exact(&mut header)
+        .await
+        .map_err(|_| VeriflowError::Crypto("File is not encrypted".to_string()))?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(VeriflowError::Crypto("File is not encrypted".to_string()));
+    }
+    if header[MAGIC.len()] != key.kdf_tag() {
+        let expected = match header[MAGIC.len()] {
+            KDF_PASSPHRASE => "a passphrase",
+            KDF_KEY_FILE => "a key file",
+            _ => "an unknown key type",
+        };
+        return Err(VeriflowError::Crypto(format!(
+            "File was encrypted with {expected}"
+        )));
+    }
+    let salt = &header[MAGIC.len() + 1..MAGIC.len() + 1 + SALT_LEN];
+    let nonce_prefix = &header[HEADER_LEN - NONCE_PREFIX_LEN..];
+
+    let file_key = key.derive_key(salt).await?;
+    let cipher = ChaCha20Poly1305::new(&file_key.into());
+    let mut decryptor = DecryptorBE32::from_aead(cipher, nonce_prefix.into());
+
+    // Metadata block
+    let mut len_buf = [0u8; 4];
+    encrypted_file.read_exact(&mut len_buf).await?;
+    let metadata_len = u32::from_be_bytes(len_buf) as usize;
+    if metadata_len > MAX_METADATA_SIZE {
+        return Err(VeriflowError::HeaderSizeExceeded(metadata_len));
+    }
+    let mut encrypted_metadata = vec![0u8; metadata_len];
+    encrypted_file.read_exact(&mut encrypted_metadata).await?;
+    let metadata_bytes = decryptor
+        .decrypt_next(Payload {
+            msg: &encrypted_metadata,
+            aad: &header,
+        })
+        .map_err(aead_error)?;
+    let metadata: Metadata = serde_json::from_slice(&metadata_bytes)?;
+
+    let progress_bar = ui::create_progress_bar(metadata.size, "Decrypting ...");
+
+    let mut plain_file = File::create(output).await?;
+    let mut buffer = vec![0u8; CHUNK_SIZE + TAG_LEN];
+    let full_chunks = metadata.size / CHUNK_SIZE as u64;
+    let remainder = (metadata.size % CHUNK_SIZE as u64) as usize;
+
+    let result: common::Result<()> = async {
+        for _ in 0..full_chunks {
+            encrypted_file.read_exact(&mut buffer).await?;
+            let chunk = decryptor
+                .decrypt_next(buffer.as_slice())
+                .map_err(aead_error)?;
+            plain_file.write_all(&chunk).await?;
+            progress_bar.inc(CHUNK_SIZE as u64);
+        }
+
+        encrypted_file
+            .read_exact(&mut buffer[..remainder + TAG_LEN])
+            .await?;
+        let last_chunk = decryptor
+            .decrypt_last(&buffer[..remainder + TAG_LEN])
+            .map_err(aead_error)?;
+        plain_file.write_all(&last_chunk).await?;
+        progress_bar.inc(remainder as u64);
+
+        // nothing may follow the last segment
+        if encrypted_file.read(&mut buffer[..1]).await? != 0 {
+            return Err(VeriflowError::Crypto(
+                "Unexpected data after the last encrypted chunk".to_string(),
+            ));
+        }
+
+        plain_file.flush().await?;
+        Ok(())
+    }
+    .await;
+
+    // clean up partially decrypted output
+    if let Err(e) = result {
+        drop(plain_file);
+        tokio::fs::remove_file(output).await?;
+        return Err(e);
+    }
+    progress_bar.finish_with_message("Decryption Complete!");
+
+    // Verify plaintext against the hash sealed in the metadata block
+    let algorithm = Checksum::algorithm_of(&metadata.hash);
+    let plain_hash = hashing::hash_file_with(output, algorithm, hash_buffer_size, |_| {}).await?;
+    if plain_hash != metadata.hash {
+        tokio::fs::remove_file(output).await?;
+        return Err(VeriflowError::HashMismatch);
+    }
+
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encrypt and decrypt a file spanning several chunks, then tamper with it
+    #[tokio::test]
+    async fn test_encrypt_decrypt_round_trip() -> common::Result<()> {
+        let dir = std::env::temp_dir().join(format!("veriflow-crypto-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let plain_path = dir.join("plain.bin");
+        let encrypted_path = dir.join("plain.bin.enc");
+        let decrypted_path = dir.join("decrypted.bin");
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 123).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&plain_path, &data).await?;
+
+        let key = KeySource::Passphrase("correct horse battery staple".to_string());
+        let encrypted_size = encrypt_file(
+            &plain_path,
+            &encrypted_path,
+            &key,
+            false,
+            hashing::DEFAULT_BUFFER_SIZE,
+        )
+        .await?;
+        let encrypted = tokio::fs::read(&encrypted_path).await?;
+        assert_eq!(encrypted_size, encrypted.len() as u64);
+        assert!(!encrypted.windows(64).any(|w| w == &data[..64]));
+        // an existing output isn't overwritten
+        assert!(encrypt_file(
+            &plain_path,
+            &encrypted_path,
+            &key,
+            false,
+            hashing::DEFAULT_BUFFER_SIZE
+        )
+        .await
+        .is_err());
+
+        let metadata = decrypt_file(
+            &encrypted_path,
+            &decrypted_path,
+            &key,
+            hashing::DEFAULT_BUFFER_SIZE,
+        )
+        .await?;
+        assert_eq!(metadata.name, "plain.bin");
+        assert_eq!(metadata.size, data.len() as u64);
+        assert_eq!(tokio::fs::read(&decrypted_path).await?, data);
+
+        // wrong passphrase
+        let wrong_key = KeySource::Passphrase("wrong".to_string());
+        assert!(decrypt_file(
+            &encrypted_path,
+            &decrypted_path,
+            &wrong_key,
+            hashing::DEFAULT_BUFFER_SIZE
+        )
+        .await
+        .is_err());
+
+        // flip a ciphertext byte
+        let mut tampered = encrypted.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        tokio::fs::write(&encrypted_path, &tampered).await?;
+        assert!(decrypt_file(
+            &encrypted_path,
+            &decrypted_path,
+            &key,
+            hashing::DEFAULT_BUFFER_SIZE
+        )
+        .await
+        .is_err());
+
+        // truncate to whole chunks only
+        tokio::fs::write(&encrypted_path, &encrypted[..encrypted.len() - 139]).await?;
+        assert!(decrypt_file(
+            &encrypted_path,
+            &decrypted_path,
+            &key,
+            hashing::DEFAULT_BUFFER_SIZE
+        )
+        .await
+        .is_err());
+        assert!(!tokio::fs::try_exists(&decrypted_path).await?);
+
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
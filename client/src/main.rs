@@ -1,10 +1,20 @@
 use clap::Parser;
 
-use crate::cli::{Args, Commands};
+use crate::cli::{Args, Commands, TrashAction, VersionAction};
+use crate::crypto::KeySource;
+use crate::queue::QueueOptions;
+use crate::transfer::TransferOptions;
 use common::VeriflowError;
+use std::path::PathBuf;
+use std::sync::Arc;
 
+mod cache;
+mod checksums;
 mod cli;
 mod config;
+mod crypto;
+mod queue;
+mod signing;
 mod transfer;
 mod ui;
 
@@ -16,11 +26,19 @@ async fn main() -> Result<(), VeriflowError> {
 
     // Load config
     let mut config = config::ClientConfig::load();
+    config.buffers.validate()?;
 
     // Handle CLI arguments
     match args.command {
         // Config
-        Commands::Config { ip, port, dir } => {
+        Commands::Config {
+            ip,
+            port,
+            dir,
+            key_file,
+            signing_key,
+            trust,
+        } => {
             if let Some(new_ip) = ip {
                 config.ip = new_ip;
             }
@@ -30,11 +48,97 @@ async fn main() -> Result<(), VeriflowError> {
             if let Some(new_dir) = dir {
                 config.download_dir = new_dir.into();
             }
+            if let Some(new_key_file) = key_file {
+                config.key_file = Some(new_key_file);
+            }
+            if let Some(new_signing_key) = signing_key {
+                config.signing_key = Some(new_signing_key);
+            }
+            if let Some(public_key) = trust {
+                if !config.trusted_keys.contains(&public_key) {
+                    config.trusted_keys.push(public_key);
+                }
+            }
 
             config.save()?;
             println!("Configuration saved.")
         }
 
+        // Keygen
+        Commands::Keygen { output } => {
+            let public_key = signing::generate_key(&output).await?;
+
+            // sign with the new key and trust your own uploads
+            config.signing_key = Some(output);
+            if !config.trusted_keys.contains(&public_key) {
+                config.trusted_keys.push(public_key.clone());
+            }
+            config.save()?;
+
+            println!("Public Key: {public_key}");
+            println!("Share it with everyone who should trust your uploads.");
+        }
+
+        // Hash
+        Commands::Hash { paths, hash } => {
+            checksums::print_hashes(
+                paths,
+                hash,
+                config.hash_cache,
+                config.buffers.hash_buffer_size,
+            )
+            .await?
+        }
+
+        // Trash
+        Commands::Trash { ip, action } => {
+            let target_ip = ip.unwrap_or_else(|| config.address());
+            match action {
+                TrashAction::List => transfer::list_trash(&target_ip).await?,
+                TrashAction::Restore { id } => transfer::restore_trash(id, &target_ip).await?,
+                TrashAction::Purge { id } => transfer::purge_trash(id, &target_ip).await?,
+            }
+        }
+
+        // Versions
+        Commands::Versions { ip, action } => {
+            let target_ip = ip.unwrap_or_else(|| config.address());
+            match action {
+                VersionAction::List { file } => transfer::list_versions(&file, &target_ip).await?,
+                VersionAction::Download {
+                    file,
+                    hash,
+                    compress,
+                    integrity,
+                } => {
+                    let options = TransferOptions {
+                        compression: compress,
+                        integrity,
+                        buffers: config.buffers,
+                        ..Default::default()
+                    };
+                    transfer::download_version(
+                        &file,
+                        &hash,
+                        &target_ip,
+                        &config.download_dir,
+                        &config.trusted_keys,
+                        &options,
+                    )
+                    .await?
+                }
+                VersionAction::Restore { file, hash } => {
+                    transfer::restore_version(&file, &hash, &target_ip).await?
+                }
+            }
+        }
+
+        // Usage
+        Commands::Usage { ip } => {
+            let target_ip = ip.unwrap_or_else(|| config.address());
+            transfer::show_usage(&target_ip).await?;
+        }
+
         // Transfer
         Commands::Transfer {
             ip,
@@ -42,18 +146,135 @@ async fn main() -> Result<(), VeriflowError> {
             download,
             delete,
             list,
+            encrypt,
+            key_file,
+            chunked,
+            compress,
+            streams,
+            trailer,
+            integrity,
+            hash,
+            jobs,
+            retries,
         } => {
             // See if CLI argument was passed otherwise use config
             let target_ip = ip.unwrap_or_else(|| config.address());
 
+            // Resolve the encryption key only when encryption was requested
+            let key = if encrypt && (!upload.is_empty() || !download.is_empty()) {
+                let key_file = key_file.or(config.key_file.clone());
+                Some(KeySource::resolve(key_file, !upload.is_empty())?)
+            } else {
+                None
+            };
+
+            // Uploads are signed whenever a signing key is configured
+            let signing_key = match &config.signing_key {
+                Some(path) if !upload.is_empty() => Some(signing::load_key(path).await?),
+                _ => None,
+            };
+
+            // How each file is sent, the same for all of them
+            let options = TransferOptions {
+                chunked,
+                compression: compress,
+                streams,
+                trailer,
+                integrity,
+                hash,
+                cache: config.hash_cache,
+                buffers: config.buffers,
+            };
+
+            // Several files go through a queue, shared between its transfers. Parallel transfers
+            // open several connections each, together they stay within the server's limits
+            let max_jobs = transfer::max_jobs(&options);
+            if (jobs as usize).min(upload.len().max(download.len())) > max_jobs {
+                ui::status!("Transferring {max_jobs} files at once to stay within the server's connection limits");
+            }
+            let queue_options = QueueOptions {
+                jobs: (jobs as usize).min(max_jobs),
+                retries,
+                backoff: queue::DEFAULT_BACKOFF,
+            };
+            let key = Arc::new(key);
+
             // Let the result of the function that is called via cli args be handled by VeriflowError
-            // Use Some operator for Option
-            if let Some(path) = upload {
+            if !upload.is_empty() {
                 // Upload
-                transfer::upload_file(&path, &target_ip).await?;
-            } else if let Some(path) = download {
+                let signing_key = Arc::new(signing_key);
+                let upload_one = move |path: PathBuf| {
+                    let (ip, key, signing_key) = (
+                        target_ip.clone(),
+                        Arc::clone(&key),
+                        Arc::clone(&signing_key),
+                    );
+                    async move {
+                        match key.as_ref() {
+                            Some(key) => {
+                                transfer::upload_encrypted(
+                                    &path,
+                                    &ip,
+                                    key,
+                                    signing_key.as_ref().as_ref(),
+                                    &options,
+                                )
+                                .await?
+                            }
+                            None => {
+                                transfer::upload_file(
+                                    &path,
+                                    &ip,
+                                    signing_key.as_ref().as_ref(),
+                                    &options,
+                                )
+                                .await?
+                            }
+                        }
+                        Ok(tokio::fs::metadata(&path).await?.len())
+                    }
+                };
+                queue::transfer_all(upload, &queue_options, upload_one).await?;
+            } else if !download.is_empty() {
                 // Download
-                transfer::download_file(&path, &target_ip, &config.download_dir).await?;
+                let download_dir = config.download_dir.clone();
+                let trusted_keys = Arc::new(config.trusted_keys.clone());
+                let download_one = move |path: PathBuf| {
+                    let (ip, key, download_dir, trusted_keys) = (
+                        target_ip.clone(),
+                        Arc::clone(&key),
+                        download_dir.clone(),
+                        Arc::clone(&trusted_keys),
+                    );
+                    async move {
+                        match key.as_ref() {
+                            Some(key) => {
+                                transfer::download_encrypted(
+                                    &path,
+                                    &ip,
+                                    &download_dir,
+                                    key,
+                                    &trusted_keys,
+                                    &options,
+                                )
+                                .await?
+                            }
+                            None => {
+                                transfer::download_file(
+                                    &path,
+                                    &ip,
+                                    &download_dir,
+                                    &trusted_keys,
+                                    &options,
+                                )
+                                .await?
+                            }
+                        }
+                        let name = path.file_name().ok_or(VeriflowError::InvalidPath)?;
+                        Ok(tokio::fs::metadata(download_dir.join(name)).await?.len())
+                    }
+                };
+                queue::transfer_all(download, &queue_options, download_one).await?;
             } else if let Some(path) = delete {
                 // Delete
                 transfer::delete_file(&path, &target_ip).await?;
@@ -0,0 +1,339 @@
+//! Multi-File Transfer Queue
+//!
+//! Several files are transferred at once, each on its own line of a multi progress display
+//! under an overall bar. Failed files are retried after a growing delay as long as the error
+//! may go away (network, timeouts, corrupted transfers) and a table sums up how each went.
+
+use crate::ui;
+use common::VeriflowError;
+use indicatif::{HumanBytes, MultiProgress};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+// comfy table
+use comfy_table::presets::NOTHING;
+use comfy_table::Table;
+
+/// Delay before the first retry, doubled for every further one
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+// Longest delay between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How a queue runs its files
+#[derive(Clone, Debug)]
+pub struct QueueOptions {
+    /// Files transferred at once
+    pub jobs: usize,
+    /// Attempts after the first one for files failing with a transient error
+    pub retries: u32,
+    /// Delay before the first retry
+    pub backoff: Duration,
+}
+
+/// How the transfer of one file ended
+#[derive(Debug)]
+pub struct Outcome {
+    pub path: PathBuf,
+    /// Bytes transferred, or why the last attempt failed
+    pub result: Result<u64, String>,
+    pub attempts: u32,
+    pub elapsed: Duration,
+}
+
+/// Transfers `paths`, several in a queue with a summary, each retried like in 'run'
+pub async fn transfer_all<T, F>(
+    paths: Vec<PathBuf>,
+    options: &QueueOptions,
+    transfer: T,
+) -> common::Result<()>
+where
+    T: Fn(PathBuf) -> F + Send + Sync + 'static,
+    F: Future<Output = common::Result<u64>> + Send + 'static,
+{
+    // a single file needs no summary, but is retried all the same
+    if let [path] = &paths[..] {
+        let outcome = run_one(path.clone(), options, &MultiProgress::new(), &transfer).await;
+        return outcome
+            .result
+            .map(|_| ())
+            .map_err(VeriflowError::TransferFailed);
+    }
+
+    let count = paths.len();
+    let outcomes = run(paths, options, transfer).await;
+    print_summary(&outcomes);
+    let failed = outcomes
+        .iter()
+        .filter(|outcome| outcome.result.is_err())
+        .count();
+    if failed > 0 {
+        return Err(VeriflowError::TransferFailed(format!(
+            "{failed} of {count} files failed"
+        )));
+    }
+    Ok(())
+}
+
+/// Transfers every path with `transfer` in a queue
+///
+/// # Arguments
+/// * 'transfer' - transfers one file, returning its size
+///
+/// # Returns
+/// The outcome of every path, in the given order. A file whose transfer panicked failed
+pub async fn run<T, F>(paths: Vec<PathBuf>, options: &QueueOptions, transfer: T) -> Vec<Outcome>
+where
+    T: Fn(PathBuf) -> F + Send + Sync + 'static,
+    F: Future<Output = common::Result<u64>> + Send + 'static,
+{
+    let multi = MultiProgress::new();
+    let overall = ui::create_overall_bar(&multi, paths.len() as u64);
+    let count = paths.len();
+    let pending = Arc::new(Mutex::new(
+        paths.iter().cloned().enumerate().collect::<VecDeque<_>>(),
+    ));
+    // kept as they finish, a panicking worker only loses the file it was on
+    let finished = Arc::new(Mutex::new(Vec::with_capacity(count)));
+    let transfer = Arc::new(transfer);
+
+    // every worker takes the next file until none are left
+    let mut workers = JoinSet::new();
+    for _ in 0..options.jobs.clamp(1, count.max(1)) {
+        let pending = Arc::clone(&pending);
+        let finished = Arc::clone(&finished);
+        let transfer = Arc::clone(&transfer);
+        let multi = multi.clone();
+        let overall = overall.clone();
+        let options = options.clone();
+        workers.spawn(async move {
+            loop {
+                let next = pending
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .pop_front();
+                let Some((index, path)) = next else {
+                    break;
+                };
+                let outcome = run_one(path, &options, &multi, &*transfer).await;
+                if let Err(e) = &outcome.result {
+                    let _ = multi.println(format!("{} failed: {e}", outcome.path.display()));
+                }
+                overall.inc(1);
+                finished
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push((index, outcome));
+            }
+        });
+    }
+
+    while let Some(worker) = workers.join_next().await {
+        if let Err(e) = worker {
+            let _ = multi.println(format!("Transfer worker failed: {e}"));
+        }
+    }
+    overall.finish_with_message("Transfers Complete!");
+
+    // files missing here were lost with a panicking worker
+    let mut outcomes: Vec<Option<Outcome>> = (0..count).map(|_| None).collect();
+    let finished = std::mem::take(&mut *finished.lock().unwrap_or_else(|e| e.into_inner()));
+    for (index, outcome) in finished {
+        outcomes[index] = Some(outcome);
+    }
+    outcomes
+        .into_iter()
+        .zip(paths)
+        .map(|(outcome, path)| {
+            outcome.unwrap_or_else(|| Outcome {
+                path,
+                result: Err("transfer panicked".to_string()),
+                attempts: 1,
+                elapsed: Duration::ZERO,
+            })
+        })
+        .collect()
+}
+
+// Transfers one file, retrying transient failures
+async fn run_one<T, F>(
+    path: PathBuf,
+    options: &QueueOptions,
+    multi: &MultiProgress,
+    transfer: &T,
+) -> Outcome
+where
+    T: Fn(PathBuf) -> F,
+    F: Future<Output = common::Result<u64>>,
+{
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.display().to_string());
+    let progress_bar = ui::create_queue_bar(multi, &name);
+    let started = Instant::now();
+    let mut attempts = 0;
+
+    let result = loop {
+        attempts += 1;
+        match ui::queued(progress_bar.clone(), transfer(path.clone())).await {
+            Ok(size) => break Ok(size),
+            Err(e) if attempts <= options.retries && is_transient(&e) => {
+                let delay = backoff(options.backoff, attempts);
+                progress_bar.set_message(format!("Retrying in {}s ...", delay.as_secs_f32()));
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => break Err(e.to_string()),
+        }
+    };
+
+    progress_bar.finish_and_clear();
+    multi.remove(&progress_bar);
+    Outcome {
+        path,
+        result,
+        attempts,
+        elapsed: started.elapsed(),
+    }
+}
+
+/// Whether a failed transfer may succeed when tried again
+pub fn is_transient(error: &VeriflowError) -> bool {
+    matches!(
+        error,
+        VeriflowError::Io(_) | VeriflowError::Timeout(_) | VeriflowError::HashMismatch
+    )
+}
+
+// Delay before the attempt after `attempts`, doubling from `base`
+fn backoff(base: Duration, attempts: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+/// Prints a table with the outcome, size, duration and throughput of every file
+pub fn print_summary(outcomes: &[Outcome]) {
+    let mut table = Table::new();
+    table.load_preset(NOTHING).set_header(vec![
+        "#",
+        "File",
+        "Outcome",
+        "Size",
+        "Duration",
+        "Throughput",
+    ]);
+
+    for (index, outcome) in outcomes.iter().enumerate() {
+        let secs = outcome.elapsed.as_secs_f64();
+        let (result, size, throughput) = match &outcome.result {
+            Ok(size) => {
+                let result = match outcome.attempts {
+                    1 => "ok".to_string(),
+                    attempts => format!("ok after {attempts} attempts"),
+                };
+                let throughput = if secs > 0.0 {
+                    format!("{}/s", HumanBytes((*size as f64 / secs) as u64))
+                } else {
+                    "-".to_string()
+                };
+                (result, HumanBytes(*size).to_string(), throughput)
+            }
+            Err(e) => (format!("failed: {e}"), "-".to_string(), "-".to_string()),
+        };
+        table.add_row(vec![
+            (index + 1).to_string(),
+            outcome.path.display().to_string(),
+            result,
+            size,
+            format!("{secs:.1}s"),
+            throughput,
+        ]);
+    }
+
+    println!("\n{table}\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_queue_retries() {
+        let options = QueueOptions {
+            jobs: 2,
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let tries: Arc<Mutex<HashMap<PathBuf, u32>>> = Arc::default();
+        let counted = Arc::clone(&tries);
+        let paths = [
+            "ok.bin",
+            "flaky.bin",
+            "rejected.bin",
+            "down.bin",
+            "panic.bin",
+        ];
+        let transfer = move |path: PathBuf| {
+            let tries = Arc::clone(&counted);
+            async move {
+                let attempt = {
+                    let mut tries = tries.lock().unwrap();
+                    let attempt = tries.entry(path.clone()).or_default();
+                    *attempt += 1;
+                    *attempt
+                };
+                match path.to_str() {
+                    Some("flaky.bin") if attempt < 2 => Err(VeriflowError::HashMismatch),
+                    Some("rejected.bin") => Err(VeriflowError::ServerError("no".to_string())),
+                    Some("down.bin") => Err(std::io::Error::other("refused").into()),
+                    Some("panic.bin") => panic!("transfer bug"),
+                    _ => Ok(10),
+                }
+            }
+        };
+        let outcomes = run(
+            paths.map(PathBuf::from).to_vec(),
+            &options,
+            transfer.clone(),
+        )
+        .await;
+
+        // outcomes keep the given order
+        let paths: Vec<_> = outcomes
+            .iter()
+            .map(|outcome| outcome.path.clone())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "ok.bin",
+                "flaky.bin",
+                "rejected.bin",
+                "down.bin",
+                "panic.bin"
+            ]
+            .map(PathBuf::from)
+        );
+
+        // transient failures are retried, others aren't, a panicked transfer failed
+        let attempts: Vec<_> = outcomes.iter().map(|outcome| outcome.attempts).collect();
+        assert_eq!(attempts, [1, 2, 1, 3, 1]);
+        assert_eq!(outcomes[1].result, Ok(10));
+        assert!(outcomes[2].result.is_err());
+        assert!(outcomes[3].result.is_err());
+        assert!(outcomes[4].result.is_err());
+
+        // a single file is retried as well
+        tries.lock().unwrap().clear();
+        let single = transfer_all(vec![PathBuf::from("flaky.bin")], &options, transfer).await;
+        assert!(single.is_ok());
+        assert_eq!(tries.lock().unwrap()[&PathBuf::from("flaky.bin")], 2);
+
+        assert_eq!(backoff(Duration::from_secs(1), 3), Duration::from_secs(4));
+        assert_eq!(backoff(Duration::from_secs(1), 10), MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,150 @@
+//! Signed upload manifests
+//!
+//! Uploads are signed with the user's Ed25519 key, downloads are checked against the
+//! trusted public keys from the config. Key files hold the 32 byte secret key as hex.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use common::{Signature, VeriflowError};
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+// Wrap a signature failure
+fn invalid(reason: impl Into<String>) -> VeriflowError {
+    VeriflowError::Signature(reason.into())
+}
+
+/// Creates a new key file at `path`
+///
+/// # Returns
+/// The hex encoded public key to share with the people who should trust your uploads
+pub async fn generate_key(path: &Path) -> common::Result<String> {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let key = SigningKey::from_bytes(&secret);
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    // never overwrite an existing key, signatures made with it would become unverifiable,
+    // and only the owner may ever read the secret key
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = match options.open(path).await {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(invalid(format!("Key file {path:?} already exists")))
+        }
+        result => result?,
+    };
+    file.write_all(hex::encode(secret).as_bytes()).await?;
+    file.sync_all().await?;
+
+    Ok(hex::encode(key.verifying_key().as_bytes()))
+}
+
+/// Reads the signing key from a key file
+pub async fn load_key(path: &Path) -> common::Result<SigningKey> {
+    let content = tokio::fs::read_to_string(path).await?;
+    let secret: [u8; 32] = hex::decode(content.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid(format!("{path:?} is not a valid signing key file")))?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+/// Signs the manifest (name, size and hash) of a file
+pub fn sign(key: &SigningKey, name: &str, size: u64, hash: &str) -> Signature {
+    let signature = key.sign(&Signature::manifest(name, size, hash));
+    Signature {
+        public_key: hex::encode(key.verifying_key().as_bytes()),
+        signature: hex::encode(signature.to_bytes()),
+    }
+}
+
+/// Checks a downloaded file's signature against the trusted keys
+///
+/// Nothing is checked while no keys are trusted, otherwise the file must be
+/// signed by one of them.
+pub fn verify(
+    signature: Option<&Signature>,
+    name: &str,
+    size: u64,
+    hash: &str,
+    trusted_keys: &[String],
+) -> common::Result<()> {
+    if trusted_keys.is_empty() {
+        return Ok(());
+    }
+    let signature = signature.ok_or_else(|| invalid("File is not signed"))?;
+
+    if !trusted_keys
+        .iter()
+        .any(|key| key.trim().eq_ignore_ascii_case(&signature.public_key))
+    {
+        return Err(invalid(format!(
+            "Signed by untrusted key {}",
+            signature.public_key
+        )));
+    }
+
+    let public_key: [u8; 32] = hex::decode(&signature.public_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid("Malformed public key"))?;
+    let signature_bytes: [u8; 64] = hex::decode(&signature.signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid("Malformed signature"))?;
+
+    let verifying_key =
+        VerifyingKey::from_bytes(&public_key).map_err(|_| invalid("Malformed public key"))?;
+    verifying_key
+        .verify_strict(
+            &Signature::manifest(name, size, hash),
+            &ed25519_dalek::Signature::from_bytes(&signature_bytes),
+        )
+        .map_err(|_| invalid("Signature doesn't match the file"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sign_and_verify_manifest() -> common::Result<()> {
+        let path = std::env::temp_dir().join(format!("veriflow-sign-{}.key", std::process::id()));
+        let public_key = generate_key(&path).await?;
+        let key = load_key(&path).await?;
+        assert!(generate_key(&path).await.is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = tokio::fs::metadata(&path).await?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        tokio::fs::remove_file(&path).await?;
+
+        let signature = sign(&key, "report.pdf", 42, "abc");
+        let trusted = vec![public_key];
+
+        assert!(verify(Some(&signature), "report.pdf", 42, "abc", &trusted).is_ok());
+        // any change to the manifest breaks the signature
+        assert!(verify(Some(&signature), "report.pdf", 43, "abc", &trusted).is_err());
+        assert!(verify(Some(&signature), "other.pdf", 42, "abc", &trusted).is_err());
+        // unsigned or untrusted files are refused once keys are trusted
+        assert!(verify(None, "report.pdf", 42, "abc", &trusted).is_err());
+        assert!(verify(
+            Some(&signature),
+            "report.pdf",
+            42,
+            "abc",
+            &["00".repeat(32)]
+        )
+        .is_err());
+        assert!(verify(None, "report.pdf", 42, "abc", &[]).is_ok());
+        Ok(())
+    }
+}
@@ -1,20 +1,157 @@
 //! File Upload, Delete, List & Download Logic
 
+use crate::cache;
+use crate::crypto::{self, KeySource};
+use crate::signing;
 use crate::ui;
+use common::hashing::{Checksum, HashAlgorithm, MerkleTree};
 use common::{
-    hashing, protocol::ProtocolConnection, protocol::BUFFER_SIZE, FileHeader, VeriflowError,
+    chunking, hashing, protocol::Buffers, protocol::ProtocolConnection, FileHeader, FileVersion,
+    ListEntry, Signature, TrashEntry, Usage, VeriflowError,
 };
-use std::path::Path;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use ed25519_dalek::SigningKey;
+use indicatif::{HumanBytes, ProgressBar};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::task::JoinSet;
 
 // comfy table
 use comfy_table::presets::NOTHING;
 use comfy_table::Table;
 
-/// Upload to Server
-pub async fn upload_file(path: &Path, ip: &str) -> common::Result<()> {
+// Hash characters shown for versions, enough to pick one
+const VERSION_HASH_LEN: usize = 12;
+// Smallest byte range worth its own connection in parallel transfers
+const MIN_RANGE_SIZE: u64 = 1024 * 1024;
+
+/// Connections a client keeps open to the server at once, well below the server's default
+/// cap per address (16) and request burst (20)
+pub const MAX_CONNECTIONS: usize = 8;
+
+/// How files are sent and received
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TransferOptions {
+    /// Upload in content-defined chunks, only the chunks the server is missing are sent
+    pub chunked: bool,
+    /// zstd level file bodies are compressed with on the wire, if the server agrees
+    pub compression: Option<i32>,
+    /// Connections a file is split over, a byte range each
+    pub streams: Option<u32>,
+    /// Hash uploads while sending them, the hash follows the body
+    pub trailer: bool,
+    /// Send file bodies in checksummed frames, corrupted ones are sent again
+    pub integrity: bool,
+    /// Algorithm uploads are hashed with, if the server accepts it
+    pub hash: HashAlgorithm,
+    /// Take hashes of unchanged files from the local hash cache
+    pub cache: bool,
+    /// Sizes file bodies are sent, received and hashed in
+    pub buffers: Buffers,
+}
+
+/// Files a queue transfers at once with `options`, keeping within 'MAX_CONNECTIONS'
+pub fn max_jobs(options: &TransferOptions) -> usize {
+    (MAX_CONNECTIONS / connections(options)).max(1)
+}
+
+// Connections a single transfer with `options` keeps open at once
+fn connections(options: &TransferOptions) -> usize {
+    // parallel transfers announce the file on a connection of their own
+    options
+        .streams
+        .map_or(1, |streams| range_streams(streams) as usize + 1)
+}
+
+// Range connections of a parallel transfer asking for `streams`
+fn range_streams(streams: u32) -> u32 {
+    streams.min(MAX_CONNECTIONS as u32 - 1)
+}
+
+/// Upload to Server, signing the manifest when a signing key is given
+pub async fn upload_file(
+    path: &Path,
+    ip: &str,
+    signing_key: Option<&SigningKey>,
+    options: &TransferOptions,
+) -> common::Result<()> {
+    // get file name -- Strict error handling (Allow ONLY UTF-8 characters)
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(VeriflowError::InvalidPath)?;
+
+    if options.chunked {
+        send_chunked(path, file_name, ip, signing_key, options.buffers).await
+    } else if let Some(streams) = options.streams {
+        send_parallel(path, file_name, ip, signing_key, options, streams).await
+    } else {
+        send_file(path, file_name, ip, signing_key, options).await
+    }
+}
+
+/// Encrypt client-side, then upload the ciphertext under the original file name
+pub async fn upload_encrypted(
+    path: &Path,
+    ip: &str,
+    key: &KeySource,
+    signing_key: Option<&SigningKey>,
+    options: &TransferOptions,
+) -> common::Result<()> {
+    // get file name -- Strict error handling (Allow ONLY UTF-8 characters)
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(VeriflowError::InvalidPath)?;
+
+    ui::status!("Starting Encryption...");
+
+    // encrypt into a temporary file, the server only ever sees this one
+    let encrypted_path = crypto::staging_path(&std::env::temp_dir(), file_name);
+    let encrypted = crypto::encrypt_file(
+        path,
+        &encrypted_path,
+        key,
+        options.cache,
+        options.buffers.hash_buffer_size,
+    )
+    .await;
+
+    // upload ciphertext and always remove the temporary file
+    let result = match encrypted {
+        Ok(_) if options.chunked => {
+            send_chunked(&encrypted_path, file_name, ip, signing_key, options.buffers).await
+        }
+        // ciphertext doesn't compress, and is new every time
+        Ok(_) => {
+            let options = TransferOptions {
+                compression: None,
+                cache: false,
+                ..*options
+            };
+            send_file(&encrypted_path, file_name, ip, signing_key, &options).await
+        }
+        Err(e) => Err(e),
+    };
+    let _ = tokio::fs::remove_file(&encrypted_path).await;
+
+    result
+}
+
+/// Upload the file at `path` to the server as `file_name`
+///
+/// With a trailer the file is read once, hashed while it is sent
+async fn send_file(
+    path: &Path,
+    file_name: &str,
+    ip: &str,
+    signing_key: Option<&SigningKey>,
+    options: &TransferOptions,
+) -> common::Result<()> {
     // Offline Logic (Validation)
 
     // get file with tokio (VeriflowError if it doesn't exist)
@@ -24,27 +161,1064 @@ pub async fn upload_file(path: &Path, ip: &str) -> common::Result<()> {
     let file_metadata = file.metadata().await?;
     let file_size = file_metadata.len();
 
+    // with a trailer the file is hashed while it is sent, the hash and signature follow the body
+    let (file_hash, signature) = if options.trailer {
+        (String::new(), None)
+    } else {
+        // Hashing
+        ui::status!("Starting Hashing...");
+
+        // create progress bar
+        // set max to len of file and operation description
+        let progress_bar = ui::create_progress_bar(file_size, "Hashing ...");
+
+        let file_hash = cache::hash_file(
+            path,
+            options.hash,
+            options.cache,
+            options.buffers.hash_buffer_size,
+            |bytes_read| progress_bar.inc(bytes_read as u64),
+        )
+        .await?;
+
+        // finish progress bar
+        progress_bar.finish_with_message("Hashing Complete!");
+
+        ui::status!("File Hash: {file_hash}");
+
+        // Sign the manifest so downloaders can tell who produced the file
+        let signature = sign_manifest(signing_key, file_name, file_size, &file_hash);
+        (file_hash, signature)
+    };
+
+    // Connect to server
+    ui::status!("Connecting to {ip}...");
+
+    // connect via TCP stream
+    let stream = TcpStream::connect(ip).await?;
+
+    // move ownership of stream into ProtocolConnection
+    let mut connection = ProtocolConnection::new(stream).await?;
+    connection.set_buffers(options.buffers);
+
+    // Setup FileHeader
+    let file_header: FileHeader = FileHeader::Upload {
+        name: String::from(file_name),
+        size: file_size,
+        hash: file_hash,
+        signature,
+        root: None,
+        compression: options.compression,
+        integrity: options.integrity,
+        algorithm: options.hash,
+    };
+
+    // Serialise the body
+    // JSON string
+    let header_json = serde_json::to_string(&file_header)?;
+
+    // send header via helper
+    connection.send_header(&header_json).await?;
+
+    // wait for the server to accept the upload (policy, quota and free space are checked first)
+    // get prefix
+    let prefix_len = connection.read_prefix().await?;
+    // get JSON bytes from stream
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    // convert bytes into json
+    let response: FileHeader = serde_json::from_slice(&header)?;
+
+    let (preview_len, compression, integrity, algorithm) = match response {
+        FileHeader::Ready {
+            preview,
+            compression,
+            integrity,
+            algorithm,
+        } => (preview, compression, integrity, algorithm),
+        // the server already has these bytes, nothing to send ("instant upload")
+        FileHeader::Success(msg) => {
+            ui::status!("Server: {msg}");
+            return Ok(());
+        }
+        FileHeader::Error(e) => return Err(VeriflowError::ServerError(e)),
+        other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+    };
+
+    // File Upload
+    ui::status!("Starting Uploading...");
+    if let Some(level) = compression {
+        ui::status!("Compressing with zstd level {level}...");
+    }
+
+    // create progress bar
+    // set max to len of file and operation description
+    let progress_bar = ui::create_progress_bar(file_size, "Uploading ...");
+    if options.trailer {
+        connection.start_hashing(algorithm);
+    } else if algorithm != options.hash {
+        // the server didn't take the hash made above, one it does follows the body
+        ui::status!(
+            "Server doesn't accept {} hashes, sending a {algorithm} one",
+            options.hash
+        );
+        connection.start_hashing(algorithm);
+    }
+
+    // Send the preview the server checks the content type of, then wait for its verdict
+    if preview_len > 0 {
+        let mut preview = vec![0u8; preview_len as usize];
+        file.read_exact(&mut preview).await?;
+        connection.send_data(&preview).await?;
+        connection.update_hash(&preview);
+        progress_bar.inc(preview_len);
+
+        // get prefix
+        let prefix_len = connection.read_prefix().await?;
+        // get JSON bytes from stream
+        let header: Vec<u8> = connection.read_body(prefix_len).await?;
+        // convert bytes into json
+        let verdict: FileHeader = serde_json::from_slice(&header)?;
+
+        match verdict {
+            FileHeader::Success(_) => {}
+            FileHeader::Error(e) => {
+                progress_bar.abandon_with_message("Upload Rejected!");
+                return Err(VeriflowError::ServerError(e));
+            }
+            other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+        }
+    }
+
+    // Stream the body, compressed and checksummed as the server agreed to
+    connection.set_compression(compression);
+    connection.set_integrity(integrity);
+    let mut reader = progress_bar.wrap_async_read(&mut file);
+    connection
+        .write_file_to_stream(&mut reader, file_size - preview_len)
+        .await?;
+
+    // finish progress bar
+    progress_bar.finish_with_message("Upload Complete!");
+
+    // the hash of what was sent, and the manifest signed over it
+    if let Some(file_hash) = connection.take_hash() {
+        ui::status!("File Hash: {file_hash}");
+        let signature = sign_manifest(signing_key, file_name, file_size, &file_hash);
+        let trailer = FileHeader::Trailer {
+            hash: file_hash,
+            signature,
+        };
+        let trailer_json = serde_json::to_string(&trailer)?;
+        connection.send_header(&trailer_json).await?;
+    }
+
+    // wait for server response that the file has been successfully uploaded
+    ui::status!("Waiting for server confirmation...");
+
+    // get prefix
+    let prefix_len = connection.read_prefix().await?;
+    // get JSON bytes from stream
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    // convert bytes into json
+    let response: FileHeader = serde_json::from_slice(&header)?;
+
+    // Check response
+    ui::status!("Server: {}", confirm(response)?);
+
+    Ok(())
+}
+
+/// Signed manifest of an upload when a signing key is given
+fn sign_manifest(
+    signing_key: Option<&SigningKey>,
+    file_name: &str,
+    file_size: u64,
+    file_hash: &str,
+) -> Option<Signature> {
+    let signature = signing_key.map(|key| signing::sign(key, file_name, file_size, file_hash));
+    if let Some(signature) = &signature {
+        ui::status!("Signed by: {}", signature.public_key);
+    }
+    signature
+}
+
+/// Upload the file at `path` to the server as `file_name` in content-defined chunks
+async fn send_chunked(
+    path: &Path,
+    file_name: &str,
+    ip: &str,
+    signing_key: Option<&SigningKey>,
+    buffers: Buffers,
+) -> common::Result<()> {
+    let mut file = File::open(path).await?;
+    let file_size = file.metadata().await?.len();
+
+    // Chunking (hashes every chunk and the whole file in one pass)
+    ui::status!("Starting Chunking...");
+    let progress_bar = ui::create_progress_bar(file_size, "Chunking ...");
+    let chunk_progress = progress_bar.clone();
+    let chunked = chunking::chunk_file(path, move |bytes_read| {
+        chunk_progress.inc(bytes_read as u64)
+    })
+    .await?;
+    progress_bar.finish_with_message("Chunking Complete!");
+
+    ui::status!("File Hash: {}", chunked.hash);
+    ui::status!("Chunks: {}", chunked.chunks.len());
+
+    // Sign the manifest so downloaders can tell who produced the file
+    let signature = sign_manifest(signing_key, file_name, file_size, &chunked.hash);
+
+    // Connect to server
+    ui::status!("Connecting to {ip}...");
+    let stream = TcpStream::connect(ip).await?;
+    let mut connection = ProtocolConnection::new(stream).await?;
+    connection.set_buffers(buffers);
+
+    let manifest = serde_json::to_vec(&chunked.chunks)?;
+    let file_header: FileHeader = FileHeader::ChunkedUpload {
+        name: String::from(file_name),
+        size: file_size,
+        hash: chunked.hash.clone(),
+        signature,
+        manifest_len: manifest.len() as u64,
+    };
+    let header_json = serde_json::to_string(&file_header)?;
+    connection.send_header(&header_json).await?;
+
+    // wait for the server to accept the upload (policy, quota and free space are checked first)
+    let prefix_len = connection.read_prefix().await?;
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    let response: FileHeader = serde_json::from_slice(&header)?;
+    let preview_len = match response {
+        FileHeader::Ready { preview, .. } => preview,
+        FileHeader::Error(e) => return Err(VeriflowError::ServerError(e)),
+        other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+    };
+
+    // Send the preview the server checks the content type of, then wait for its verdict
+    if preview_len > 0 {
+        let mut preview = vec![0u8; preview_len as usize];
+        file.read_exact(&mut preview).await?;
+        connection.send_data(&preview).await?;
+
+        let prefix_len = connection.read_prefix().await?;
+        let header: Vec<u8> = connection.read_body(prefix_len).await?;
+        let verdict: FileHeader = serde_json::from_slice(&header)?;
+        match verdict {
+            FileHeader::Success(_) => {}
+            FileHeader::Error(e) => return Err(VeriflowError::ServerError(e)),
+            other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+        }
+    }
+
+    // Send the chunk list, the server answers with the chunks it is missing
+    connection.send_data(&manifest).await?;
+    let prefix_len = connection.read_prefix().await?;
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    let response: FileHeader = serde_json::from_slice(&header)?;
+    let received_size = match response {
+        FileHeader::Upload { size, .. } => size as usize,
+        FileHeader::Error(e) => return Err(VeriflowError::ServerError(e)),
+        other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+    };
+    let payload_bytes = connection.read_payload(received_size).await?;
+    let missing: Vec<u64> = serde_json::from_slice(&payload_bytes)?;
+
+    // offset of every chunk in the file
+    let offsets: Vec<u64> = chunked
+        .chunks
+        .iter()
+        .scan(0, |offset, chunk| {
+            let start = *offset;
+            *offset += chunk.size;
+            Some(start)
+        })
+        .collect();
+    let to_send: u64 = missing
+        .iter()
+        .filter_map(|index| chunked.chunks.get(*index as usize))
+        .map(|chunk| chunk.size)
+        .sum();
+
+    // Stream the missing chunks in the order the server asked for them
+    ui::status!("Starting Uploading...");
+    let progress_bar = ui::create_progress_bar(to_send, "Uploading ...");
+    let mut buffer = vec![0u8; buffers.chunk_size];
+    for index in &missing {
+        let index = *index as usize;
+        let chunk = chunked.chunks.get(index).ok_or_else(|| {
+            VeriflowError::UnexpectedFileHeader(format!("Server asked for unknown chunk {index}"))
+        })?;
+        file.seek(SeekFrom::Start(offsets[index])).await?;
+        let mut remaining = chunk.size;
+        while remaining > 0 {
+            let want = std::cmp::min(remaining, buffers.chunk_size as u64) as usize;
+            let bytes_read = file.read(&mut buffer[..want]).await?;
+            if bytes_read == 0 {
+                return Err(VeriflowError::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "File changed while uploading",
+                )));
+            }
+            connection.send_data(&buffer[..bytes_read]).await?;
+            progress_bar.inc(bytes_read as u64);
+            remaining -= bytes_read as u64;
+        }
+    }
+    progress_bar.finish_with_message("Upload Complete!");
+
+    ui::status!(
+        "Sent {} of {} chunks ({} of {}), {} reused from the server",
+        missing.len(),
+        chunked.chunks.len(),
+        HumanBytes(to_send),
+        HumanBytes(file_size),
+        HumanBytes(file_size - to_send)
+    );
+
+    // wait for server response that the file has been successfully uploaded
+    ui::status!("Waiting for server confirmation...");
+    let prefix_len = connection.read_prefix().await?;
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    let response: FileHeader = serde_json::from_slice(&header)?;
+    ui::status!("Server: {}", confirm(response)?);
+
+    Ok(())
+}
+
+/// Upload the file at `path` to the server as `file_name`, a byte range per connection
+async fn send_parallel(
+    path: &Path,
+    file_name: &str,
+    ip: &str,
+    signing_key: Option<&SigningKey>,
+    options: &TransferOptions,
+    streams: u32,
+) -> common::Result<()> {
+    let file_size = tokio::fs::metadata(path).await?.len();
+    let streams = range_streams(streams);
+
+    // Hashing, the server verifies the whole file once every range arrived
+    ui::status!("Starting Hashing...");
+    let progress_bar = ui::create_progress_bar(file_size, "Hashing ...");
+    let file_hash = cache::hash_file(
+        path,
+        options.hash,
+        options.cache,
+        options.buffers.hash_buffer_size,
+        |bytes_read| progress_bar.inc(bytes_read as u64),
+    )
+    .await?;
+    progress_bar.finish_with_message("Hashing Complete!");
+    ui::status!("File Hash: {file_hash}");
+
+    // Sign the manifest so downloaders can tell who produced the file
+    let signature = sign_manifest(signing_key, file_name, file_size, &file_hash);
+
+    // Connect to server, this connection stays open until the server confirms the whole file
+    ui::status!("Connecting to {ip}...");
+    let stream = TcpStream::connect(ip).await?;
+    let mut connection = ProtocolConnection::new(stream).await?;
+
+    let file_header = FileHeader::ParallelUpload {
+        name: String::from(file_name),
+        size: file_size,
+        hash: file_hash,
+        signature,
+    };
+    let header_json = serde_json::to_string(&file_header)?;
+    connection.send_header(&header_json).await?;
+
+    // wait for the server to accept the upload (policy, quota and free space are checked first)
+    let prefix_len = connection.read_prefix().await?;
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    let response: FileHeader = serde_json::from_slice(&header)?;
+    let id = match response {
+        FileHeader::Accepted { id } => id,
+        FileHeader::Error(e) => return Err(VeriflowError::ServerError(e)),
+        other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+    };
+
+    // Stream every range over its own connection
+    let ranges = split_ranges(file_size, streams);
+    ui::status!("Starting Uploading over {} streams...", ranges.len());
+    if let Some(level) = options.compression {
+        ui::status!("Compressing with zstd level {level}...");
+    }
+    let progress_bar = ui::create_progress_bar(file_size, "Uploading ...");
+    let mut tasks = JoinSet::new();
+    for (offset, length) in ranges {
+        tasks.spawn(send_range(
+            ip.to_string(),
+            path.to_path_buf(),
+            id.clone(),
+            (offset, length),
+            *options,
+            progress_bar.clone(),
+        ));
+    }
+    // a failed range aborts the others, the server drops the upload with this connection
+    while let Some(sent) = tasks.join_next().await {
+        if let Err(e) = sent.map_err(std::io::Error::other)? {
+            progress_bar.abandon_with_message("Upload Failed!");
+            return Err(e);
+        }
+    }
+    progress_bar.finish_with_message("Upload Complete!");
+
+    // wait for server response that the whole file has been verified
+    ui::status!("Waiting for server confirmation...");
+    let prefix_len = connection.read_prefix().await?;
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    let response: FileHeader = serde_json::from_slice(&header)?;
+    ui::status!("Server: {}", confirm(response)?);
+
+    Ok(())
+}
+
+/// Send the byte `range` (offset, length) of the file at `path` for the parallel upload `id`
+async fn send_range(
+    ip: String,
+    path: PathBuf,
+    id: String,
+    range: (u64, u64),
+    options: TransferOptions,
+    progress_bar: ProgressBar,
+) -> common::Result<()> {
+    let (offset, length) = range;
+    let stream = TcpStream::connect(&ip).await?;
+    let mut connection = ProtocolConnection::new(stream).await?;
+    connection.set_buffers(options.buffers);
+
+    let file_header = FileHeader::UploadRange {
+        id,
+        offset,
+        length,
+        compression: options.compression,
+        integrity: options.integrity,
+    };
+    let header_json = serde_json::to_string(&file_header)?;
+    connection.send_header(&header_json).await?;
+
+    let prefix_len = connection.read_prefix().await?;
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    let response: FileHeader = serde_json::from_slice(&header)?;
+    let (compression, integrity) = match response {
+        FileHeader::Ready {
+            compression,
+            integrity,
+            ..
+        } => (compression, integrity),
+        FileHeader::Error(e) => return Err(VeriflowError::ServerError(e)),
+        other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+    };
+
+    let mut file = File::open(&path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    connection.set_compression(compression);
+    connection.set_integrity(integrity);
+    let mut reader = progress_bar.wrap_async_read(file);
+    connection.write_file_to_stream(&mut reader, length).await?;
+
+    let prefix_len = connection.read_prefix().await?;
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    let response: FileHeader = serde_json::from_slice(&header)?;
+    confirm(response)?;
+
+    Ok(())
+}
+
+/// The message of the server's final 'Success', like 'FileHeader::unpack_response' without printing it
+fn confirm(response: FileHeader) -> common::Result<String> {
+    match response {
+        FileHeader::Success(msg) => Ok(msg),
+        FileHeader::Error(e) => Err(VeriflowError::ServerError(e)),
+        other => Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+    }
+}
+
+/// Splits `size` bytes into at most `streams` ranges (offset, length) of similar size
+///
+/// Ranges are at least 'MIN_RANGE_SIZE' long, small files use fewer streams
+fn split_ranges(size: u64, streams: u32) -> Vec<(u64, u64)> {
+    let count = size
+        .div_ceil(MIN_RANGE_SIZE)
+        .clamp(1, u64::from(streams.max(1)));
+    let length = size.div_ceil(count).max(1);
+    (0..size)
+        .step_by(length as usize)
+        .map(|offset| (offset, length.min(size - offset)))
+        .collect()
+}
+
+/// Splits the `missing` ranges of a download into ranges for up to `streams` connections
+///
+/// Ranges are cut at multiples of `block_size`, so every one can be verified on its own
+fn plan_ranges(missing: &[(u64, u64)], streams: u32, block_size: u64) -> Vec<(u64, u64)> {
+    let total: u64 = missing.iter().map(|(_, length)| length).sum();
+    let count = total
+        .div_ceil(MIN_RANGE_SIZE)
+        .clamp(1, u64::from(streams.max(1)));
+    let piece = total
+        .div_ceil(count)
+        .max(1)
+        .next_multiple_of(block_size.max(1));
+    missing
+        .iter()
+        .flat_map(|&(offset, length)| {
+            (offset..offset + length)
+                .step_by(piece as usize)
+                .map(move |start| (start, piece.min(offset + length - start)))
+        })
+        .collect()
+}
+
+/// Download from Server, checking the signature against the trusted keys
+pub async fn download_file(
+    path: &Path,
+    ip: &str,
+    download_dir: &Path,
+    trusted_keys: &[String],
+    options: &TransferOptions,
+) -> common::Result<()> {
+    // get file name -- Strict error handling (Allow ONLY UTF-8 characters)
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(VeriflowError::InvalidPath)?;
+
+    // Ensure download dir exists
+    tokio::fs::create_dir_all(download_dir).await?;
+
+    // combine into a single valid path
+    let full_download_path = download_dir.join(file_name);
+    if let Some(streams) = options.streams {
+        return fetch_parallel(
+            file_name,
+            ip,
+            &full_download_path,
+            trusted_keys,
+            options,
+            streams,
+        )
+        .await;
+    }
+    let request = FileHeader::Download {
+        name: String::from(file_name),
+        compression: options.compression,
+        integrity: options.integrity,
+    };
+    fetch_file(
+        request,
+        ip,
+        &full_download_path,
+        trusted_keys,
+        options.buffers,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Download an encrypted file from Server and decrypt it client-side
+pub async fn download_encrypted(
+    path: &Path,
+    ip: &str,
+    download_dir: &Path,
+    key: &KeySource,
+    trusted_keys: &[String],
+    options: &TransferOptions,
+) -> common::Result<()> {
+    // get file name -- Strict error handling (Allow ONLY UTF-8 characters)
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(VeriflowError::InvalidPath)?;
+
+    // Ensure download dir exists
+    tokio::fs::create_dir_all(download_dir).await?;
+
+    // ciphertext is verified against the server hash first
+    let encrypted_path = crypto::staging_path(download_dir, file_name);
+    let request = FileHeader::Download {
+        name: String::from(file_name),
+        compression: None,
+        integrity: options.integrity,
+    };
+    fetch_file(request, ip, &encrypted_path, trusted_keys, options.buffers).await?;
+
+    // then decrypted and verified against the plaintext hash in the metadata block
+    ui::status!("Decrypting...");
+    let result = crypto::decrypt_file(
+        &encrypted_path,
+        &download_dir.join(file_name),
+        key,
+        options.buffers.hash_buffer_size,
+    )
+    .await;
+    tokio::fs::remove_file(&encrypted_path).await?;
+
+    let metadata = result?;
+    ui::status!("Plaintext Hash: {}", metadata.hash);
+
+    Ok(())
+}
+
+/// Send a download `request` to the server, save the file into `full_download_path` and verify its hash and signature
+///
+/// # Returns
+/// The hash of the downloaded file, as the server sent it
+async fn fetch_file(
+    request: FileHeader,
+    ip: &str,
+    full_download_path: &Path,
+    trusted_keys: &[String],
+    buffers: Buffers,
+) -> common::Result<String> {
+    // Connect to server
+    ui::status!("Connecting to {ip}...");
+
+    // connect via TCP stream
+    let stream = TcpStream::connect(ip).await?;
+
+    // move ownership of stream into ProtocolConnection
+    let mut connection = ProtocolConnection::new(stream).await?;
+    connection.set_buffers(buffers);
+
+    // Serialise the body
+    // JSON string
+    let header_json = serde_json::to_string(&request)?;
+
+    // send header via helper
+    connection.send_header(&header_json).await?;
+
+    ui::status!("Waiting for server response...");
+
+    // get prefix
+    let prefix_len = connection.read_prefix().await?;
+    // get JSON bytes from stream
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    // convert bytes into json
+    let file_header: FileHeader = serde_json::from_slice(&header)?;
+
+    // extract size, hash and signature from header
+    let (received_name, received_size, received_hash, signature) = match &file_header {
+        FileHeader::Upload {
+            name,
+            size,
+            hash,
+            signature,
+            ..
+        } => (name.clone(), *size, hash.clone(), signature.clone()),
+        FileHeader::Error(e) => return Err(VeriflowError::ServerError(e.clone())),
+        other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+    };
+
+    // Downloading to disk
+
+    // create file on disk
+    let mut download_file = File::create(full_download_path).await?;
+
+    // the hash below is of the decompressed bytes, as on the uploader's disk
+    set_body_format(&mut connection, &file_header);
+    // hashed as it arrives with the algorithm the upload was hashed with, the file isn't read back
+    connection.start_hashing(Checksum::algorithm_of(&received_hash));
+    let progress_bar = ui::create_progress_bar(received_size, "Downloading ...");
+    let mut writer = progress_bar.wrap_async_write(&mut download_file);
+    connection
+        .read_file_to_disk(&mut writer, received_size)
+        .await?;
+    writer.flush().await?;
+    progress_bar.finish_with_message("Download Complete!");
+
+    // hashing started above, so a missing hash can't be verified and fails like a mismatch
+    let file_hash = connection.take_hash().ok_or(VeriflowError::HashMismatch)?;
+    verify_download(
+        full_download_path,
+        &received_name,
+        received_size,
+        &received_hash,
+        file_hash,
+        signature,
+        trusted_keys,
+    )
+    .await?;
+    Ok(received_hash)
+}
+
+/// Reads the body after a download response as the server sends it (compressed, checksummed)
+fn set_body_format(connection: &mut ProtocolConnection, response: &FileHeader) {
+    if let FileHeader::Upload {
+        compression,
+        integrity,
+        ..
+    } = response
+    {
+        if let Some(level) = compression {
+            ui::status!("Receiving zstd level {level} compressed...");
+        }
+        connection.set_compression(*compression);
+        connection.set_integrity(*integrity);
+    }
+}
+
+/// Download `file_name` into `full_download_path`, a byte range per connection
+async fn fetch_parallel(
+    file_name: &str,
+    ip: &str,
+    full_download_path: &Path,
+    trusted_keys: &[String],
+    options: &TransferOptions,
+    streams: u32,
+) -> common::Result<()> {
+    let streams = range_streams(streams);
+
+    // Connect to server, an empty range answers with the size, hash and signature
+    ui::status!("Connecting to {ip}...");
+    let stream = TcpStream::connect(ip).await?;
+    let mut connection = ProtocolConnection::new(stream).await?;
+
+    let request = FileHeader::DownloadRange {
+        name: String::from(file_name),
+        offset: 0,
+        length: 0,
+        compression: None,
+        integrity: false,
+    };
+    let header_json = serde_json::to_string(&request)?;
+    connection.send_header(&header_json).await?;
+
+    ui::status!("Waiting for server response...");
+    let prefix_len = connection.read_prefix().await?;
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    let file_header: FileHeader = serde_json::from_slice(&header)?;
+    let (received_name, received_size, received_hash, signature, root) = match file_header {
+        FileHeader::Upload {
+            name,
+            size,
+            hash,
+            signature,
+            root,
+            ..
+        } => (name, size, hash, signature, root),
+        FileHeader::Error(e) => return Err(VeriflowError::ServerError(e)),
+        other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+    };
+    drop(connection);
+
+    // ranges are checked block by block against the file's Merkle tree, if the server recorded its root
+    let tree = match &root {
+        Some(root) => Some(Arc::new(
+            fetch_tree(ip, file_name, received_size, root).await?,
+        )),
+        None => None,
+    };
+
+    // every range is written at its offset into a file of the final size
+    let download_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(full_download_path)
+        .await?;
+    let existing = download_file.metadata().await?.len();
+    download_file.set_len(received_size).await?;
+    drop(download_file);
+
+    // blocks left by an earlier attempt that already match aren't downloaded again
+    let missing = match &tree {
+        Some(tree) if existing > 0 => {
+            ui::status!("Checking the blocks already downloaded...");
+            let mut file = File::open(full_download_path).await?;
+            let mut missing: Vec<(u64, u64)> = Vec::new();
+            for index in tree.mismatched_blocks(&mut file).await? {
+                let (offset, length) = tree.block(index);
+                match missing.last_mut() {
+                    Some((start, len)) if *start + *len == offset => *len += length,
+                    _ => missing.push((offset, length)),
+                }
+            }
+            missing
+        }
+        _ => vec![(0, received_size)],
+    };
+    let left: u64 = missing.iter().map(|(_, length)| length).sum();
+    if left < received_size {
+        ui::status!(
+            "Resuming, {} of {} left...",
+            HumanBytes(left),
+            HumanBytes(received_size)
+        );
+    }
+
+    let block_size = tree.as_ref().map_or(MIN_RANGE_SIZE, |tree| tree.block_size);
+    let mut ranges = plan_ranges(&missing, streams, block_size).into_iter();
+    ui::status!(
+        "Starting Downloading over {} streams...",
+        ranges.len().min(streams as usize)
+    );
+    let progress_bar = ui::create_progress_bar(received_size, "Downloading ...");
+    progress_bar.set_position(received_size - left);
+    let mut tasks = JoinSet::new();
+    loop {
+        // at most 'streams' ranges at once, the next starts when one is done
+        while tasks.len() < streams as usize {
+            let Some(range) = ranges.next() else {
+                break;
+            };
+            tasks.spawn(fetch_range(
+                ip.to_string(),
+                String::from(file_name),
+                full_download_path.to_path_buf(),
+                range,
+                *options,
+                tree.clone(),
+                progress_bar.clone(),
+            ));
+        }
+        let Some(fetched) = tasks.join_next().await else {
+            break;
+        };
+        let fetched = fetched.map_err(|e| VeriflowError::Io(std::io::Error::other(e)));
+        if let Err(e) = fetched.and_then(|fetched| fetched) {
+            tasks.abort_all();
+            progress_bar.abandon_with_message("Download Failed!");
+            if tree.is_some() {
+                ui::status!("Partial download kept, downloading it again resumes it");
+            } else {
+                // without block hashes a partial file is of no use
+                tokio::fs::remove_file(full_download_path).await?;
+            }
+            return Err(e);
+        }
+    }
+    progress_bar.finish_with_message("Download Complete!");
+
+    // the ranges only add up to the file if the whole hash matches
+    let file_hash = hash_download(
+        full_download_path,
+        received_size,
+        &received_hash,
+        options.buffers.hash_buffer_size,
+    )
+    .await?;
+    verify_download(
+        full_download_path,
+        &received_name,
+        received_size,
+        &received_hash,
+        file_hash,
+        signature,
+        trusted_keys,
+    )
+    .await
+}
+
+/// Download the byte `range` (offset, length) of `file_name` into the same range of `path`
+///
+/// With the file's 'tree' every block is verified as it arrives
+async fn fetch_range(
+    ip: String,
+    file_name: String,
+    path: PathBuf,
+    range: (u64, u64),
+    options: TransferOptions,
+    tree: Option<Arc<MerkleTree>>,
+    progress_bar: ProgressBar,
+) -> common::Result<()> {
+    let (offset, length) = range;
+    let stream = TcpStream::connect(&ip).await?;
+    let mut connection = ProtocolConnection::new(stream).await?;
+    connection.set_buffers(options.buffers);
+
+    let request = FileHeader::DownloadRange {
+        name: file_name,
+        offset,
+        length,
+        compression: options.compression,
+        integrity: options.integrity,
+    };
+    let header_json = serde_json::to_string(&request)?;
+    connection.send_header(&header_json).await?;
+
+    let prefix_len = connection.read_prefix().await?;
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    let file_header: FileHeader = serde_json::from_slice(&header)?;
+    let (compression, integrity) = match file_header {
+        FileHeader::Upload {
+            compression,
+            integrity,
+            ..
+        } => (compression, integrity),
+        FileHeader::Error(e) => return Err(VeriflowError::ServerError(e)),
+        other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+    };
+
+    let mut file = OpenOptions::new().write(true).open(&path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    connection.set_compression(compression);
+    connection.set_integrity(integrity);
+    if let Some(tree) = &tree {
+        connection.verify_blocks(tree.verifier(offset)?);
+    }
+    let mut writer = progress_bar.wrap_async_write(file);
+    connection.read_file_to_disk(&mut writer, length).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// Fetch the Merkle tree of `file_name`, it has to match the `root` recorded with the file
+async fn fetch_tree(
+    ip: &str,
+    file_name: &str,
+    size: u64,
+    root: &str,
+) -> common::Result<MerkleTree> {
+    let stream = TcpStream::connect(ip).await?;
+    let mut connection = ProtocolConnection::new(stream).await?;
+
+    let request = FileHeader::Tree {
+        name: String::from(file_name),
+    };
+    let header_json = serde_json::to_string(&request)?;
+    connection.send_header(&header_json).await?;
+
+    let prefix_len = connection.read_prefix().await?;
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    let file_header: FileHeader = serde_json::from_slice(&header)?;
+    let received_size = match file_header {
+        FileHeader::Upload { size, .. } => size as usize,
+        FileHeader::Error(e) => return Err(VeriflowError::ServerError(e)),
+        other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+    };
+
+    let payload_bytes = connection.read_payload(received_size).await?;
+    let tree: MerkleTree = serde_json::from_slice(&payload_bytes)?;
+    if !tree.matches(size, root) {
+        return Err(VeriflowError::HashMismatch);
+    }
+    Ok(tree)
+}
+
+/// Hash a downloaded file with the algorithm of `received_hash`, read `buffer_size` bytes at once
+async fn hash_download(
+    full_download_path: &Path,
+    received_size: u64,
+    received_hash: &str,
+    buffer_size: usize,
+) -> common::Result<String> {
+    // create progress bar
+    // set max to len of file and operation description
+    let progress_bar = ui::create_progress_bar(received_size, "Hashing ...");
+
+    let algorithm = Checksum::algorithm_of(received_hash);
+    let file_hash =
+        hashing::hash_file_with(full_download_path, algorithm, buffer_size, |bytes_read| {
+            progress_bar.inc(bytes_read as u64)
+        })
+        .await?;
+
+    // finish progress bar
+    progress_bar.finish_with_message("Hashing Complete!");
+    Ok(file_hash)
+}
+
+/// Check a downloaded file against the hash and signature the server sent, removing it if it fails
+///
+/// # Arguments
+/// * 'file_hash' - hash of the bytes as they were received
+async fn verify_download(
+    full_download_path: &Path,
+    received_name: &str,
+    received_size: u64,
+    received_hash: &str,
+    file_hash: String,
+    signature: Option<Signature>,
+    trusted_keys: &[String],
+) -> common::Result<()> {
+    // Verification (Hashing)
+    ui::status!("Verifying File Integrity...");
+
+    // check if hash is not the same
+    if file_hash != received_hash {
+        // clean up the corrupted file
+        tokio::fs::remove_file(full_download_path).await?;
+        ui::status!("File removed!");
+
+        // return error
+        return Err(VeriflowError::HashMismatch);
+    }
+
+    // Provenance (Signature)
+    let verified = signing::verify(
+        signature.as_ref(),
+        received_name,
+        received_size,
+        received_hash,
+        trusted_keys,
+    );
+    if let Err(e) = verified {
+        // don't keep files we can't trust
+        tokio::fs::remove_file(full_download_path).await?;
+        ui::status!("File removed!");
+
+        return Err(e);
+    }
+    if let Some(signature) = &signature {
+        ui::status!("Signed by: {}", signature.public_key);
+    }
+
+    Ok(())
+}
+
+/// Delete from Server
+pub async fn delete_file(path: &Path, ip: &str) -> common::Result<()> {
+    // Connect to server
+    println!("Connecting to {ip}...");
+
+    // connect via TCP stream
+    let stream = TcpStream::connect(ip).await?;
+
+    // move ownership of stream into ProtocolConnection
+    let mut connection = ProtocolConnection::new(stream).await?;
+
     // get file name -- Strict error handling (Allow ONLY UTF-8 characters)
     let file_name = path
         .file_name()
         .and_then(|name| name.to_str())
         .ok_or(VeriflowError::InvalidPath)?;
 
-    // Hashing
-    println!("Starting Hashing...");
+    // Setup FileHeader
+    let file_header: FileHeader = FileHeader::Delete {
+        name: String::from(file_name),
+    };
 
-    // create progress bar
-    // set max to len of file and operation description
-    let mut progress_bar = ui::create_progress_bar(file_size, "Hashing ...");
+    // Serialise the body
+    // JSON string
+    let header_json = serde_json::to_string(&file_header)?;
 
-    let file_hash =
-        hashing::hash_file(path, |bytes_read| progress_bar.inc(bytes_read as u64)).await?;
+    println!("Sending delete request to {ip}...");
 
-    // finish progress bar
-    progress_bar.finish_with_message("Hashing Complete!");
+    // send header via helper
+    connection.send_header(&header_json).await?;
+
+    // wait for server response
+    // get prefix
+    let prefix_len = connection.read_prefix().await?;
+    // get JSON bytes from stream
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    // convert bytes into json
+    let response: FileHeader = serde_json::from_slice(&header)?;
+
+    // Check response
+    response.unpack_response()?;
 
-    println!("File Hash: {file_hash}");
+    Ok(())
+}
 
+/// List Server Files
+pub async fn list_files(ip: &str) -> common::Result<()> {
     // Connect to server
     println!("Connecting to {ip}...");
 
@@ -55,73 +1229,140 @@ pub async fn upload_file(path: &Path, ip: &str) -> common::Result<()> {
     let mut connection = ProtocolConnection::new(stream).await?;
 
     // Setup FileHeader
-    let file_header: FileHeader = FileHeader::Upload {
-        name: String::from(file_name),
-        size: file_size,
-        hash: file_hash,
-    };
+    let file_header: FileHeader = FileHeader::List;
 
     // Serialise the body
     // JSON string
     let header_json = serde_json::to_string(&file_header)?;
 
+    println!("Sending list request to {ip}...");
+
     // send header via helper
     connection.send_header(&header_json).await?;
 
-    // File Upload
-    println!("Starting Uploading...");
+    // wait for server response
+    // get prefix
+    let prefix_len = connection.read_prefix().await?;
+    // get JSON bytes from stream
+    let header: Vec<u8> = connection.read_body(prefix_len).await?;
+    // convert bytes into json
+    let file_header: FileHeader = serde_json::from_slice(&header)?;
 
-    // create progress bar
-    // set max to len of file and operation description
-    progress_bar = ui::create_progress_bar(file_size, "Uploading ...");
+    // get size from enum
+    let received_size = match file_header {
+        FileHeader::Upload { size, .. } => size as usize,
+        FileHeader::Error(e) => return Err(VeriflowError::ServerError(e)),
+        other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+    };
 
-    // Stream the body
+    // read payload (one-shot)
+    let payload_bytes = connection.read_payload(received_size).await?;
 
-    // Buffer
-    let mut buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+    // deserialise into Vec<ListEntry>
+    let entries: Vec<ListEntry> = serde_json::from_slice(&payload_bytes)?;
 
-    // read file using buffer
-    loop {
-        // Read chunk from file (number of bytes successfully read)
-        let bytes_read: usize = file.read(&mut buffer).await?;
+    // output file tree
+    let mut table = Table::new();
+    table
+        .load_preset(NOTHING)
+        .set_header(vec!["#", "Type", "Path", "Size", "Stored"]);
 
-        // finish reading file
-        if bytes_read == 0 {
-            // break loop
-            break;
+    // manual counter
+    let mut display_id = 1;
+
+    for entry in &entries {
+        let is_dir = entry.path.ends_with("/");
+
+        // filter for empty directories (no other path in the list)
+        if is_dir {
+            let has_children = entries
+                .iter()
+                .any(|other| other.path != entry.path && other.path.starts_with(&entry.path));
+
+            if has_children {
+                continue; // skip directories that contain anything
+            }
         }
 
-        // update progress bar
-        progress_bar.inc(bytes_read as u64);
+        let type_of = if is_dir { "DIR" } else { "FILE" };
 
-        // load the chunk from file
-        let current_chunk: &[u8] = &buffer[..bytes_read];
+        table.add_row(vec![
+            display_id.to_string(),
+            type_of.to_string(),
+            entry.path.to_string(),
+            HumanBytes(entry.size).to_string(),
+            HumanBytes(entry.stored).to_string(),
+        ]);
 
-        // update stream with current chunk reference
-        connection.send_data(current_chunk).await?;
+        display_id += 1;
     }
 
-    // finish progress bar
-    progress_bar.finish_with_message("Upload Complete!");
+    println!("\n{table}\n");
 
-    // wait for server response that the file has been successfully uploaded
-    println!("Waiting for server confirmation...");
+    Ok(())
+}
+
+/// Show storage used and available on the server
+pub async fn show_usage(ip: &str) -> common::Result<()> {
+    // Connect to server
+    println!("Connecting to {ip}...");
+
+    // connect via TCP stream
+    let stream = TcpStream::connect(ip).await?;
+
+    // move ownership of stream into ProtocolConnection
+    let mut connection = ProtocolConnection::new(stream).await?;
 
+    // Setup FileHeader
+    let file_header: FileHeader = FileHeader::Usage;
+
+    // Serialise the body
+    // JSON string
+    let header_json = serde_json::to_string(&file_header)?;
+
+    // send header via helper
+    connection.send_header(&header_json).await?;
+
+    // wait for server response
     // get prefix
     let prefix_len = connection.read_prefix().await?;
     // get JSON bytes from stream
     let header: Vec<u8> = connection.read_body(prefix_len).await?;
     // convert bytes into json
-    let response: FileHeader = serde_json::from_slice(&header)?;
+    let file_header: FileHeader = serde_json::from_slice(&header)?;
 
-    // Check response
-    response.unpack_response()?;
+    // get size from enum
+    let received_size = match file_header {
+        FileHeader::Upload { size, .. } => size as usize,
+        FileHeader::Error(e) => return Err(VeriflowError::ServerError(e)),
+        other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+    };
+
+    // read payload (one-shot)
+    let payload_bytes = connection.read_payload(received_size).await?;
+    let usage: Usage = serde_json::from_slice(&payload_bytes)?;
+
+    // output usage
+    let mut table = Table::new();
+    table
+        .load_preset(NOTHING)
+        .set_header(vec!["User", "Used", "Quota", "Available"]);
+    table.add_row(vec![
+        usage.user,
+        HumanBytes(usage.used).to_string(),
+        usage.quota.map_or("unlimited".to_string(), |quota| {
+            HumanBytes(quota).to_string()
+        }),
+        HumanBytes(usage.available).to_string(),
+    ]);
+
+    println!("\n{table}\n");
 
     Ok(())
 }
 
-/// Download from Server
-pub async fn download_file(path: &Path, ip: &str, download_dir: &Path) -> common::Result<()> {
+/// List the server's trash
+pub async fn list_trash(ip: &str) -> common::Result<()> {
     // Connect to server
     println!("Connecting to {ip}...");
 
@@ -131,16 +1372,8 @@ pub async fn download_file(path: &Path, ip: &str, download_dir: &Path) -> common
     // move ownership of stream into ProtocolConnection
     let mut connection = ProtocolConnection::new(stream).await?;
 
-    // get file name -- Strict error handling (Allow ONLY UTF-8 characters)
-    let file_name = path
-        .file_name()
-        .and_then(|name| name.to_str())
-        .ok_or(VeriflowError::InvalidPath)?;
-
     // Setup FileHeader
-    let file_header: FileHeader = FileHeader::Download {
-        name: String::from(file_name),
-    };
+    let file_header: FileHeader = FileHeader::TrashList;
 
     // Serialise the body
     // JSON string
@@ -149,8 +1382,7 @@ pub async fn download_file(path: &Path, ip: &str, download_dir: &Path) -> common
     // send header via helper
     connection.send_header(&header_json).await?;
 
-    println!("Waiting for server response...");
-
+    // wait for server response
     // get prefix
     let prefix_len = connection.read_prefix().await?;
     // get JSON bytes from stream
@@ -158,59 +1390,64 @@ pub async fn download_file(path: &Path, ip: &str, download_dir: &Path) -> common
     // convert bytes into json
     let file_header: FileHeader = serde_json::from_slice(&header)?;
 
-    // extract size and hash from header
-    let (received_size, received_hash) = match file_header {
-        FileHeader::Upload { size, hash, .. } => (size, hash),
+    // get size from enum
+    let received_size = match file_header {
+        FileHeader::Upload { size, .. } => size as usize,
         FileHeader::Error(e) => return Err(VeriflowError::ServerError(e)),
         other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
     };
 
-    // Downloading to disk
-
-    // Ensure download dir exists
-    tokio::fs::create_dir_all(download_dir).await?;
-    // combine into a single valid path
-    let full_download_path = download_dir.join(file_name);
-
-    // create file on disk
-    let mut download_file = File::create(&full_download_path).await?;
-
-    connection
-        .read_file_to_disk(&mut download_file, received_size)
-        .await?; // add progress bar
-
-    println!("Download Complete!");
+    // read payload (one-shot)
+    let payload_bytes = connection.read_payload(received_size).await?;
+    let entries: Vec<TrashEntry> = serde_json::from_slice(&payload_bytes)?;
 
-    // Verification (Hashing)
-    println!("Verifying File Integrity...");
+    if entries.is_empty() {
+        println!("Trash is empty.");
+        return Ok(());
+    }
 
-    // create progress bar
-    // set max to len of file and operation description
-    let progress_bar = ui::create_progress_bar(received_size, "Hashing ...");
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
 
-    let file_hash = hashing::hash_file(&full_download_path, |bytes_read| {
-        progress_bar.inc(bytes_read as u64)
-    })
-    .await?;
+    // output trash
+    let mut table = Table::new();
+    table
+        .load_preset(NOTHING)
+        .set_header(vec!["Id", "Type", "Path", "Size", "Deleted", "Expires"]);
+    for entry in entries {
+        table.add_row(vec![
+            entry.id.to_string(),
+            if entry.is_dir { "DIR" } else { "FILE" }.to_string(),
+            entry.path,
+            HumanBytes(entry.size).to_string(),
+            format!(
+                "{} ago",
+                human_duration(now.saturating_sub(entry.deleted_at))
+            ),
+            entry.expires_at.map_or("never".to_string(), |expires| {
+                format!("in {}", human_duration(expires.saturating_sub(now)))
+            }),
+        ]);
+    }
 
-    // finish progress bar
-    progress_bar.finish_with_message("Hashing Complete!");
+    println!("\n{table}\n");
 
-    // check if hash is not the same
-    if file_hash != received_hash {
-        // clean up the corrupted file
-        tokio::fs::remove_file(&full_download_path).await?;
-        println!("File removed!");
+    Ok(())
+}
 
-        // return error
-        return Err(VeriflowError::HashMismatch);
-    }
+/// Restore a trashed file/folder on the server
+pub async fn restore_trash(id: u64, ip: &str) -> common::Result<()> {
+    simple_request(FileHeader::Restore { id }, ip).await
+}
 
-    Ok(())
+/// Permanently remove trash entries on the server
+pub async fn purge_trash(id: Option<u64>, ip: &str) -> common::Result<()> {
+    simple_request(FileHeader::Purge { id }, ip).await
 }
 
-/// Delete from Server
-pub async fn delete_file(path: &Path, ip: &str) -> common::Result<()> {
+// Send a request that is answered with Success/Error only
+async fn simple_request(file_header: FileHeader, ip: &str) -> common::Result<()> {
     // Connect to server
     println!("Connecting to {ip}...");
 
@@ -220,23 +1457,10 @@ pub async fn delete_file(path: &Path, ip: &str) -> common::Result<()> {
     // move ownership of stream into ProtocolConnection
     let mut connection = ProtocolConnection::new(stream).await?;
 
-    // get file name -- Strict error handling (Allow ONLY UTF-8 characters)
-    let file_name = path
-        .file_name()
-        .and_then(|name| name.to_str())
-        .ok_or(VeriflowError::InvalidPath)?;
-
-    // Setup FileHeader
-    let file_header: FileHeader = FileHeader::Delete {
-        name: String::from(file_name),
-    };
-
     // Serialise the body
     // JSON string
     let header_json = serde_json::to_string(&file_header)?;
 
-    println!("Sending delete request to {ip}...");
-
     // send header via helper
     connection.send_header(&header_json).await?;
 
@@ -254,8 +1478,14 @@ pub async fn delete_file(path: &Path, ip: &str) -> common::Result<()> {
     Ok(())
 }
 
-/// List Server Files
-pub async fn list_files(ip: &str) -> common::Result<()> {
+/// List the kept versions of a file on the server
+pub async fn list_versions(path: &Path, ip: &str) -> common::Result<()> {
+    // get file name -- Strict error handling (Allow ONLY UTF-8 characters)
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(VeriflowError::InvalidPath)?;
+
     // Connect to server
     println!("Connecting to {ip}...");
 
@@ -266,14 +1496,14 @@ pub async fn list_files(ip: &str) -> common::Result<()> {
     let mut connection = ProtocolConnection::new(stream).await?;
 
     // Setup FileHeader
-    let file_header: FileHeader = FileHeader::List;
+    let file_header: FileHeader = FileHeader::Versions {
+        name: String::from(file_name),
+    };
 
     // Serialise the body
     // JSON string
     let header_json = serde_json::to_string(&file_header)?;
 
-    println!("Sending list request to {ip}...");
-
     // send header via helper
     connection.send_header(&header_json).await?;
 
@@ -294,45 +1524,155 @@ pub async fn list_files(ip: &str) -> common::Result<()> {
 
     // read payload (one-shot)
     let payload_bytes = connection.read_payload(received_size).await?;
+    let versions: Vec<FileVersion> = serde_json::from_slice(&payload_bytes)?;
+
+    if versions.is_empty() {
+        println!("No previous versions of {file_name}.");
+        return Ok(());
+    }
 
-    // deserialise into Vec<String>
-    let path_list: Vec<String> = serde_json::from_slice(&payload_bytes)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
 
-    // output file tree
+    // output versions
     let mut table = Table::new();
     table
         .load_preset(NOTHING)
-        .set_header(vec!["#", "Type", "Path"]);
+        .set_header(vec!["Hash", "Size", "Replaced", "Signed"]);
+    for version in versions {
+        table.add_row(vec![
+            version.hash[..VERSION_HASH_LEN.min(version.hash.len())].to_string(),
+            HumanBytes(version.size).to_string(),
+            format!(
+                "{} ago",
+                human_duration(now.saturating_sub(version.saved_at))
+            ),
+            if version.signature.is_some() {
+                "yes"
+            } else {
+                "no"
+            }
+            .to_string(),
+        ]);
+    }
 
-    // manual counter
-    let mut display_id = 1;
+    println!("\n{table}\n");
 
-    for path in &path_list {
-        let is_dir = path.ends_with("/");
+    Ok(())
+}
 
-        // filter for empty directories (no other path in the list)
-        if is_dir {
-            let has_children = path_list
-                .iter()
-                .any(|other| other != path && other.starts_with(path));
+/// Download a previous version of a file, saved as `<hash>_<file name>` with the full hash of the version
+pub async fn download_version(
+    path: &Path,
+    hash: &str,
+    ip: &str,
+    download_dir: &Path,
+    trusted_keys: &[String],
+    options: &TransferOptions,
+) -> common::Result<()> {
+    // get file name -- Strict error handling (Allow ONLY UTF-8 characters)
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(VeriflowError::InvalidPath)?;
 
-            if has_children {
-                continue; // skip directories that contain anything
-            }
+    // Ensure download dir exists
+    tokio::fs::create_dir_all(download_dir).await?;
+
+    // keep the version apart from a download of the current file
+    let request = FileHeader::DownloadVersion {
+        name: String::from(file_name),
+        hash: String::from(hash),
+        compression: options.compression,
+        integrity: options.integrity,
+    };
+    // `hash` may only be the first characters, the file is named once the server resolved it
+    let staged_path = crypto::staging_path(download_dir, file_name);
+    let fetched = fetch_file(request, ip, &staged_path, trusted_keys, options.buffers).await;
+    let version_hash = match fetched {
+        Ok(version_hash) => version_hash,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&staged_path).await;
+            return Err(e);
         }
+    };
 
-        let type_of = if is_dir { "DIR" } else { "FILE" };
+    // plain hex, algorithm tags don't belong in file names
+    let digest = Checksum::parse(&version_hash).map_or(version_hash, |checksum| checksum.hex);
+    let full_download_path = download_dir.join(format!("{digest}_{file_name}"));
+    tokio::fs::rename(&staged_path, &full_download_path).await?;
+    ui::status!("Saved as {}", full_download_path.display());
+    Ok(())
+}
 
-        table.add_row(vec![
-            display_id.to_string(),
-            type_of.to_string(),
-            path.to_string(),
-        ]);
+/// Make a previous version the current contents of a file on the server
+pub async fn restore_version(path: &Path, hash: &str, ip: &str) -> common::Result<()> {
+    // get file name -- Strict error handling (Allow ONLY UTF-8 characters)
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(VeriflowError::InvalidPath)?;
 
-        display_id += 1;
-    }
+    let request = FileHeader::RestoreVersion {
+        name: String::from(file_name),
+        hash: String::from(hash),
+    };
+    simple_request(request, ip).await
+}
 
-    println!("\n{table}\n");
+// Coarse human readable duration ("3d", "5h", "12m")
+fn human_duration(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::limits::Limits;
+    use server::server::Listener;
+
+    #[tokio::test]
+    async fn test_parallel_streams_within_limits() -> common::Result<()> {
+        let root = std::env::temp_dir().join(format!("veriflow-streams-{}", std::process::id()));
+        let _ = tokio::fs::remove_dir_all(&root).await;
+        let (served, local, downloads) = (
+            root.join("served"),
+            root.join("local"),
+            root.join("downloads"),
+        );
+        tokio::fs::create_dir_all(&served).await?;
+        tokio::fs::create_dir_all(&local).await?;
+
+        // a server with the default limits
+        let mut server = Listener::new("127.0.0.1", "0").await?;
+        server.set_limits(Limits::default());
+        let ip = server.local_addr()?.to_string();
+        tokio::spawn(async move { server.listen(served).await });
+
+        // the most streams, the queue's default jobs still fit the cap per address
+        let options = TransferOptions {
+            streams: Some(16),
+            ..Default::default()
+        };
+        let jobs = max_jobs(&options).min(4);
+        assert!(jobs * connections(&options) < Limits::default().max_connections_per_ip);
+
+        let body: Vec<u8> = (0..16 * MIN_RANGE_SIZE as u32)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let path = local.join("large.bin");
+        tokio::fs::write(&path, &body).await?;
+        upload_file(&path, &ip, None, &options).await?;
+        download_file(&path, &ip, &downloads, &[], &options).await?;
+        assert_eq!(tokio::fs::read(downloads.join("large.bin")).await?, body);
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
 }
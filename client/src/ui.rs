@@ -1,6 +1,21 @@
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::fmt;
+use std::future::Future;
+
+tokio::task_local! {
+    // line of the multi progress display the queued transfer on this task reports to
+    static QUEUED: ProgressBar;
+}
 
 pub fn create_progress_bar(size: u64, operation_desc: &str) -> ProgressBar {
+    // queued transfers reuse their line for every step
+    if let Ok(progress_bar) = QUEUED.try_with(ProgressBar::clone) {
+        progress_bar.reset();
+        progress_bar.set_length(size);
+        progress_bar.set_message(operation_desc.to_string());
+        return progress_bar;
+    }
+
     // create progress bar and set bar max (length) to len of file
     let progress_bar: ProgressBar = ProgressBar::new(size);
     // style the progress bar
@@ -19,3 +34,53 @@ pub fn create_progress_bar(size: u64, operation_desc: &str) -> ProgressBar {
 
     progress_bar
 }
+
+/// Line of a queued transfer in `multi`, labelled with the file `name`
+pub fn create_queue_bar(multi: &MultiProgress, name: &str) -> ProgressBar {
+    let progress_bar = multi.add(ProgressBar::new(0));
+    // {prefix:24!} = file name, cut to 24 characters
+    // {msg:28!}     = current step, cut to 28 characters
+    progress_bar.set_style(
+        ProgressStyle::with_template(
+            "{prefix:24!} {msg:28!} [{bar:30.cyan/blue}] {bytes}/{total_bytes} ({eta})",
+        )
+        .unwrap(),
+    );
+    progress_bar.set_prefix(name.to_string());
+    progress_bar.set_message("Waiting ...");
+    progress_bar
+}
+
+/// Overall line of a queue of `count` files
+pub fn create_overall_bar(multi: &MultiProgress, count: u64) -> ProgressBar {
+    let progress_bar = multi.add(ProgressBar::new(count));
+    progress_bar.set_style(
+        ProgressStyle::with_template(
+            "{msg} {spinner:.green} [{elapsed_precise}] [{bar:40.green/white}] {pos}/{len} files",
+        )
+        .unwrap(),
+    );
+    progress_bar.set_message("Transferring ...");
+    progress_bar
+}
+
+/// Runs `transfer` as a queued transfer reporting to `progress_bar`
+pub async fn queued<F: Future>(progress_bar: ProgressBar, transfer: F) -> F::Output {
+    QUEUED.scope(progress_bar, transfer).await
+}
+
+/// Prints a status line, queued transfers show it on their progress bar instead
+pub fn print_status(line: fmt::Arguments) {
+    match QUEUED.try_with(ProgressBar::clone) {
+        Ok(progress_bar) => progress_bar.set_message(line.to_string()),
+        Err(_) => println!("{line}"),
+    }
+}
+
+/// 'println!' for transfer steps, see 'print_status'
+macro_rules! status {
+    ($($arg:tt)*) => {
+        $crate::ui::print_status(format_args!($($arg)*))
+    };
+}
+pub(crate) use status;
@@ -0,0 +1,118 @@
+//! Hashing throughput: the streaming reader against the blocking pool path
+//!
+//! Run with `cargo bench -p common --bench hashing`. 'hash_reader_with' over a tokio file
+//! through a 4 KiB buffer is how files were hashed before, 'hash_file_with' reads through a
+//! large buffer or a memory map on the blocking pool, 'hash_files' hashes several files at once.
+
+use common::hashing::{self, HashAlgorithm};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::path::{Path, PathBuf};
+use tokio::runtime::Runtime;
+
+// Size of the single file, mapped as it is above the mapping threshold
+const FILE_SIZE: usize = 64 * 1024 * 1024;
+// Files hashed at once and the size of each
+const FILE_COUNT: usize = 16;
+const SMALL_FILE_SIZE: usize = 4 * 1024 * 1024;
+// Buffer of the streaming baseline
+const STREAMING_BUFFER_SIZE: usize = 4096;
+
+// Writes `size` bytes of noise to `path`
+fn write_file(path: &Path, size: usize) {
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let data: Vec<u8> = (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    std::fs::write(path, data).unwrap();
+}
+
+fn bench_file(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let dir = std::env::temp_dir().join(format!("veriflow-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("large.bin");
+    write_file(&path, FILE_SIZE);
+
+    let mut group = c.benchmark_group("hash_file");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.sample_size(10);
+    for algorithm in HashAlgorithm::ALL {
+        group.bench_with_input(
+            BenchmarkId::new("streaming", algorithm),
+            &algorithm,
+            |b, &algorithm| {
+                b.to_async(&runtime).iter(|| async {
+                    let mut file = tokio::fs::File::open(&path).await.unwrap();
+                    hashing::hash_reader_with(&mut file, algorithm, STREAMING_BUFFER_SIZE, |_| {})
+                        .await
+                        .unwrap()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("blocking", algorithm),
+            &algorithm,
+            |b, &algorithm| {
+                b.to_async(&runtime).iter(|| async {
+                    hashing::hash_file_with(&path, algorithm, hashing::DEFAULT_BUFFER_SIZE, |_| {})
+                        .await
+                        .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn bench_files(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let dir = std::env::temp_dir().join(format!("veriflow-bench-files-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let paths: Vec<PathBuf> = (0..FILE_COUNT)
+        .map(|index| dir.join(format!("{index}.bin")))
+        .collect();
+    for path in &paths {
+        write_file(path, SMALL_FILE_SIZE);
+    }
+
+    let mut group = c.benchmark_group("hash_files");
+    group.throughput(Throughput::Bytes((FILE_COUNT * SMALL_FILE_SIZE) as u64));
+    group.sample_size(10);
+    group.bench_function("one_by_one", |b| {
+        b.to_async(&runtime).iter(|| async {
+            for path in &paths {
+                let mut file = tokio::fs::File::open(path).await.unwrap();
+                hashing::hash_reader_with(
+                    &mut file,
+                    HashAlgorithm::Sha256,
+                    STREAMING_BUFFER_SIZE,
+                    |_| {},
+                )
+                .await
+                .unwrap();
+            }
+        })
+    });
+    group.bench_function("parallel", |b| {
+        b.to_async(&runtime).iter(|| async {
+            hashing::hash_files(
+                paths.clone(),
+                HashAlgorithm::Sha256,
+                hashing::DEFAULT_BUFFER_SIZE,
+            )
+            .await
+            .unwrap()
+        })
+    });
+    group.finish();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+criterion_group!(benches, bench_file, bench_files);
+criterion_main!(benches);
@@ -0,0 +1,68 @@
+//! Download throughput: the buffered loop against sendfile
+//!
+//! Run with `cargo bench -p common --bench zero_copy`. A file is sent over a loopback
+//! connection to a reader draining it, through 'write_file_to_stream' (every byte copied
+//! through the user-space buffer) and through 'write_file_range' (sendfile on Linux).
+
+use common::protocol::ProtocolConnection;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+// Size of the file sent every iteration
+const FILE_SIZE: usize = 256 * 1024 * 1024;
+
+fn bench_send(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let dir = std::env::temp_dir().join(format!("veriflow-bench-send-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("download.bin");
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&path, data).unwrap();
+
+    // the receiving side drains everything for as long as the benchmark runs
+    let mut connection = runtime.block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            tokio::io::copy(&mut socket, &mut tokio::io::sink())
+                .await
+                .unwrap();
+        });
+        ProtocolConnection::new(TcpStream::connect(address).await.unwrap())
+            .await
+            .unwrap()
+    });
+
+    let mut group = c.benchmark_group("send_file");
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    group.sample_size(10);
+    group.bench_function("buffered", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let mut file = tokio::fs::File::open(&path).await.unwrap();
+                connection
+                    .write_file_to_stream(&mut file, FILE_SIZE as u64)
+                    .await
+                    .unwrap();
+            })
+        })
+    });
+    group.bench_function("zero_copy", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let file = tokio::fs::File::open(&path).await.unwrap();
+                connection
+                    .write_file_range(file, 0, FILE_SIZE as u64)
+                    .await
+                    .unwrap();
+            })
+        })
+    });
+    group.finish();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+criterion_group!(benches, bench_send);
+criterion_main!(benches);
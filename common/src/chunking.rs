@@ -0,0 +1,110 @@
+//! Content-defined chunking (FastCDC) for chunked transfers
+//!
+//! Chunk boundaries depend on the bytes around them rather than on their offset, so an
+//! insert or edit only changes the chunks it touches and the rest dedupe against
+//! what the server already stores.
+
+use crate::hashing;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+/// Chunk size bounds, chunks average 'AVG_CHUNK_SIZE' bytes
+pub const MIN_CHUNK_SIZE: u32 = 64 * 1024;
+pub const AVG_CHUNK_SIZE: u32 = 256 * 1024;
+pub const MAX_CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// A chunk of a file identified by the SHA256 of its bytes
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct Chunk {
+    pub hash: String,
+    pub size: u64,
+}
+
+/// A file split into chunks
+#[derive(Debug, Clone)]
+pub struct Chunked {
+    pub chunks: Vec<Chunk>,
+    pub size: u64,
+    pub hash: String, // SHA256 of the whole file
+}
+
+/// Splits a file into content-defined chunks, hashing every chunk and the whole file in one pass
+pub async fn chunk_file<F>(path: &Path, mut on_progress: F) -> crate::Result<Chunked>
+where
+    F: FnMut(usize) + Send + 'static,
+{
+    let path = path.to_path_buf();
+    // chunking is CPU bound, keep it off the async workers
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(path)?;
+        let chunker =
+            fastcdc::v2020::StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE);
+
+        let mut hasher = Sha256::new();
+        let mut chunks = Vec::new();
+        let mut size = 0;
+        for chunk in chunker {
+            let chunk = chunk.map_err(|e| std::io::Error::other(e.to_string()))?;
+            hasher.update(&chunk.data);
+            chunks.push(Chunk {
+                hash: hashing::hash_bytes(&chunk.data),
+                size: chunk.length as u64,
+            });
+            size += chunk.length as u64;
+            on_progress(chunk.length);
+        }
+
+        Ok(Chunked {
+            chunks,
+            size,
+            hash: format!("{:x}", hasher.finalize()),
+        })
+    })
+    .await
+    .map_err(|e| std::io::Error::other(e.to_string()))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_chunks_survive_an_insert() -> crate::Result<()> {
+        let dir = std::env::temp_dir().join(format!("veriflow-cdc-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+
+        // pseudo random content so boundaries depend on the data
+        let mut state: u64 = 42;
+        let original: Vec<u8> = (0..4 * 1024 * 1024)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 33) as u8
+            })
+            .collect();
+        let mut edited = b"inserted at the start".to_vec();
+        edited.extend_from_slice(&original);
+
+        tokio::fs::write(dir.join("original"), &original).await?;
+        tokio::fs::write(dir.join("edited"), &edited).await?;
+        let before = chunk_file(&dir.join("original"), |_| {}).await?;
+        let after = chunk_file(&dir.join("edited"), |_| {}).await?;
+
+        assert_eq!(before.size, original.len() as u64);
+        assert_eq!(before.hash, hashing::hash_bytes(&original));
+        assert!(before
+            .chunks
+            .iter()
+            .all(|chunk| chunk.size <= MAX_CHUNK_SIZE as u64));
+        // only the chunk around the insert differs
+        let shared = after
+            .chunks
+            .iter()
+            .filter(|chunk| before.chunks.contains(chunk))
+            .count();
+        assert!(shared >= before.chunks.len() - 1);
+
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
@@ -1,33 +1,196 @@
-//! File hashing via SHA256
+//! File hashing via SHA256, SHA512 or BLAKE3
+//!
+//! Digests other than SHA256 are tagged with their algorithm (see 'Checksum').
+//! Besides the hash of a whole file a Merkle tree over its fixed-size blocks can be built,
+//! its root lets any block (or byte range of whole blocks) be verified on its own.
+//!
+//! Files are hashed on the blocking pool through a large buffer, or memory mapped once they
+//! are large, and BLAKE3 spreads big inputs over all cores. Many files can be hashed at once
+//! with 'hash_files'.
 
-use sha2::{Digest, Sha256};
-use std::path::Path;
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use crate::VeriflowError;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::cmp;
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-// convention: 4096B or 8192B
-// Buffer size of 8kb for hashing
-const BUFFER_SIZE: usize = 4096;
+/// Bytes read at once when hashing, unless the caller asks for another buffer size
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+// Files at least this large are memory mapped instead of read
+const MMAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+// Mapped bytes hashed between two progress reports
+const MAPPED_STEP: usize = 8 * 1024 * 1024;
+// Inputs BLAKE3 spreads over several threads
+const PARALLEL_THRESHOLD: usize = 128 * 1024;
+
+/// Bytes covered by one leaf of a 'MerkleTree'
+pub const MERKLE_BLOCK_SIZE: u64 = 1024 * 1024;
+// First byte hashed for leaves and inner nodes, so a leaf never passes for a node
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
 
 // Hashes a file using SHA256
 // Function now accepts a callback
-pub async fn hash_file<F>(path: &Path, mut on_progress: F) -> crate::Result<String>
+pub async fn hash_file<F>(path: &Path, on_progress: F) -> crate::Result<String>
 where
     F: FnMut(usize),
 {
-    // Buffer
-    let mut buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+    hash_file_with(
+        path,
+        HashAlgorithm::Sha256,
+        DEFAULT_BUFFER_SIZE,
+        on_progress,
+    )
+    .await
+}
 
-    // get file with tokio
-    let mut file = File::open(path).await?;
+/// Hashes a file with `algorithm`, read `buffer_size` bytes at once, the digest is tagged with it
+pub async fn hash_file_with<F>(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+    on_progress: F,
+) -> crate::Result<String>
+where
+    F: FnMut(usize),
+{
+    let path = path.to_path_buf();
+    run_blocking(
+        move |on_progress| {
+            let mut hasher = Hasher::with_algorithm(algorithm);
+            read_file(&path, buffer_size, |data| hasher.update(data), on_progress)?;
+            Ok(hasher.finalize())
+        },
+        on_progress,
+    )
+    .await
+}
 
-    // create hasher for SHA256
-    let mut hasher: Sha256 = Sha256::new();
+/// Hashes several files at once with `algorithm`, the results are in the order of `paths`
+pub async fn hash_files(
+    paths: Vec<PathBuf>,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> crate::Result<Vec<crate::Result<String>>> {
+    run_blocking(
+        move |_| {
+            Ok(paths
+                .par_iter()
+                .map(|path| {
+                    let mut hasher = Hasher::with_algorithm(algorithm);
+                    read_file(path, buffer_size, |data| hasher.update(data), &mut |_| {})?;
+                    Ok(hasher.finalize())
+                })
+                .collect())
+        },
+        |_| {},
+    )
+    .await
+}
+
+// Runs `work` on the blocking pool, the progress it reports is handed to `on_progress` here
+async fn run_blocking<T, W, F>(work: W, mut on_progress: F) -> crate::Result<T>
+where
+    T: Send + 'static,
+    W: FnOnce(&mut dyn FnMut(usize)) -> crate::Result<T> + Send + 'static,
+    F: FnMut(usize),
+{
+    let (progress, mut reports) = tokio::sync::mpsc::unbounded_channel();
+    let task = tokio::task::spawn_blocking(move || {
+        work(&mut |bytes_read| {
+            let _ = progress.send(bytes_read);
+        })
+    });
+    // ends once the work is done and drops its sender
+    while let Some(bytes_read) = reports.recv().await {
+        on_progress(bytes_read);
+    }
+    task.await.map_err(std::io::Error::other)?
+}
+
+// Feeds the whole file to `feed`, blocking
+fn read_file<U>(
+    path: &Path,
+    buffer_size: usize,
+    mut feed: U,
+    on_progress: &mut dyn FnMut(usize),
+) -> crate::Result<()>
+where
+    U: FnMut(&[u8]),
+{
+    let mut file = std::fs::File::open(path)?;
+    if file.metadata()?.len() >= MMAP_THRESHOLD {
+        // SAFETY: the map is only read. A file truncated by someone else meanwhile may fault,
+        // the same holds for every tool hashing mapped files
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        #[cfg(unix)]
+        let _ = map.advise(memmap2::Advice::Sequential);
+        for piece in map.chunks(MAPPED_STEP) {
+            feed(piece);
+            on_progress(piece.len());
+        }
+        return Ok(());
+    }
+
+    let mut buffer = buffer(buffer_size);
+    loop {
+        let bytes_read = match file.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(bytes_read) => bytes_read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        feed(&buffer[..bytes_read]);
+        on_progress(bytes_read);
+    }
+}
+
+// Read buffer of `size` bytes, an empty one would end every read right away
+fn buffer(size: usize) -> Vec<u8> {
+    vec![0u8; size.max(1)]
+}
+
+/// Hashes everything a reader yields using SHA256 (files kept outside the local disk)
+pub async fn hash_reader<R, F>(reader: &mut R, on_progress: F) -> crate::Result<String>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    F: FnMut(usize),
+{
+    hash_reader_with(
+        reader,
+        HashAlgorithm::Sha256,
+        DEFAULT_BUFFER_SIZE,
+        on_progress,
+    )
+    .await
+}
+
+/// Hashes everything a reader yields with `algorithm`, read `buffer_size` bytes at once
+pub async fn hash_reader_with<R, F>(
+    reader: &mut R,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+    mut on_progress: F,
+) -> crate::Result<String>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    F: FnMut(usize),
+{
+    // Buffer
+    let mut buffer = buffer(buffer_size);
+
+    // create hasher for the algorithm
+    let mut hasher = Hasher::with_algorithm(algorithm);
 
     // read file using buffer
     loop {
         // Read chunk from file (number of bytes successfully read)
-        let bytes_read: usize = file.read(&mut buffer).await?;
+        let bytes_read: usize = reader.read(&mut buffer).await?;
 
         // finish reading file
         if bytes_read == 0 {
@@ -38,19 +201,528 @@ where
         // trigger callback for progressbar
         on_progress(bytes_read);
 
-        // load the chunk from file
-        let current_chunk: &[u8] = &buffer[..bytes_read];
-
         // update hasher with current chunk reference
-        hasher.update(current_chunk);
+        hasher.update(&buffer[..bytes_read]);
     }
 
-    // finalise hasher, get its output (byte array)
-    let file_hash = hasher.finalize();
+    // finalise hasher, get its tagged hex digest
+    Ok(hasher.finalize())
+}
 
+/// Hashes an in-memory buffer using SHA256
+pub fn hash_bytes(data: &[u8]) -> String {
     // Convert hash (byte array) to hex
-    let file_hash_hex: String = format!("{:x}", file_hash);
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Hashes an in-memory buffer with `algorithm`
+pub fn hash_bytes_with(data: &[u8], algorithm: HashAlgorithm) -> String {
+    let mut hasher = Hasher::with_algorithm(algorithm);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// Hash functions files can be hashed with
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// Understood by every client and server, the default
+    #[default]
+    Sha256,
+    Sha512,
+    /// Several times faster than SHA256 on large files
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 3] = [
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Sha512,
+        HashAlgorithm::Blake3,
+    ];
+
+    /// Name used in tagged digests and configs
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    // Hex characters of a digest
+    fn hex_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Blake3 => 64,
+            HashAlgorithm::Sha512 => 128,
+        }
+    }
+
+    /// The algorithm a transfer is hashed with: this one if `accepted`, otherwise SHA256
+    pub fn negotiate(self, accepted: &[HashAlgorithm]) -> HashAlgorithm {
+        if accepted.contains(&self) {
+            self
+        } else {
+            HashAlgorithm::Sha256
+        }
+    }
+
+    /// Whether this is SHA256, which is left out of headers for older peers
+    pub fn is_default(&self) -> bool {
+        *self == HashAlgorithm::Sha256
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = VeriflowError;
+
+    fn from_str(name: &str) -> crate::Result<HashAlgorithm> {
+        HashAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| VeriflowError::UnsupportedHash(name.to_string()))
+    }
+}
+
+/// A hex digest tagged with the algorithm it was made with, written `<algorithm>:<hex>`
+///
+/// SHA256 digests are written as plain hex, as they always were, so hashes recorded and
+/// signed before other algorithms existed keep their meaning
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    pub hex: String,
+}
+
+impl Checksum {
+    /// Parses a tagged (or plain SHA256) digest, rejecting anything else
+    pub fn parse(digest: &str) -> crate::Result<Checksum> {
+        let (algorithm, hex) = match digest.split_once(':') {
+            Some((name, hex)) => (name.parse()?, hex),
+            None => (HashAlgorithm::Sha256, digest),
+        };
+        if hex.len() != algorithm.hex_len() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(VeriflowError::UnsupportedHash(digest.to_string()));
+        }
+        Ok(Checksum {
+            algorithm,
+            hex: hex.to_ascii_lowercase(),
+        })
+    }
+
+    /// Algorithm of a digest, SHA256 for plain hex and anything unreadable
+    pub fn algorithm_of(digest: &str) -> HashAlgorithm {
+        Checksum::parse(digest).map_or(HashAlgorithm::Sha256, |checksum| checksum.algorithm)
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.algorithm {
+            HashAlgorithm::Sha256 => f.write_str(&self.hex),
+            algorithm => write!(f, "{algorithm}:{}", self.hex),
+        }
+    }
+}
+
+/// Incremental hash over data fed in pieces, SHA256 unless another algorithm is picked
+pub struct Hasher(Inner);
+
+enum Inner {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Default for Hasher {
+    fn default() -> Self {
+        Hasher::new()
+    }
+}
+
+impl Hasher {
+    pub fn new() -> Hasher {
+        Hasher::with_algorithm(HashAlgorithm::Sha256)
+    }
+
+    pub fn with_algorithm(algorithm: HashAlgorithm) -> Hasher {
+        Hasher(match algorithm {
+            HashAlgorithm::Sha256 => Inner::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Inner::Sha512(Sha512::new()),
+            HashAlgorithm::Blake3 => Inner::Blake3(Box::default()),
+        })
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        match self.0 {
+            Inner::Sha256(_) => HashAlgorithm::Sha256,
+            Inner::Sha512(_) => HashAlgorithm::Sha512,
+            Inner::Blake3(_) => HashAlgorithm::Blake3,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.0 {
+            Inner::Sha256(hasher) => hasher.update(data),
+            Inner::Sha512(hasher) => hasher.update(data),
+            Inner::Blake3(hasher) if data.len() >= PARALLEL_THRESHOLD => {
+                hasher.update_rayon(data);
+            }
+            Inner::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Tagged hex digest of everything fed so far
+    pub fn finalize(self) -> String {
+        let algorithm = self.algorithm();
+        let hex = match self.0 {
+            Inner::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Inner::Sha512(hasher) => format!("{:x}", hasher.finalize()),
+            Inner::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        };
+        Checksum { algorithm, hex }.to_string()
+    }
+}
+
+/// SHA256 of every block of a file, the root of the tree over them stands for the whole file
+///
+/// Leaves hash a block, inner nodes the hex digests of their two children. An odd node out is
+/// carried up a level as it is
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct MerkleTree {
+    /// Size of the file
+    pub size: u64,
+    pub block_size: u64,
+    pub leaves: Vec<String>,
+}
+
+impl MerkleTree {
+    /// Hex digest of the root node
+    pub fn root(&self) -> String {
+        let mut level = self.leaves.clone();
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => {
+                        let mut hasher = Sha256::new_with_prefix([NODE_PREFIX]);
+                        hasher.update(left.as_bytes());
+                        hasher.update(right.as_bytes());
+                        format!("{:x}", hasher.finalize())
+                    }
+                    _ => pair[0].clone(),
+                })
+                .collect();
+        }
+        level
+            .pop()
+            .unwrap_or_else(|| format!("{:x}", Sha256::new_with_prefix([LEAF_PREFIX]).finalize()))
+    }
+
+    /// Whether the tree covers a file of `size` bytes with `root`
+    pub fn matches(&self, size: u64, root: &str) -> bool {
+        self.size == size
+            && self.block_size > 0
+            && self.leaves.len() as u64 == size.div_ceil(self.block_size).max(1)
+            && self.root() == root
+    }
+
+    /// Offset and length of block `index`
+    pub fn block(&self, index: usize) -> (u64, u64) {
+        let offset = index as u64 * self.block_size;
+        (
+            offset,
+            self.block_size.min(self.size.saturating_sub(offset)),
+        )
+    }
+
+    /// Checks the bytes of the file from `offset` on, which has to be where a block starts
+    pub fn verifier(&self, offset: u64) -> crate::Result<BlockVerifier> {
+        if self.block_size == 0 || !offset.is_multiple_of(self.block_size) || offset > self.size {
+            return Err(VeriflowError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Offset {offset} doesn't start a block"),
+            )));
+        }
+        Ok(BlockVerifier {
+            leaves: self.leaves.clone(),
+            first: (offset / self.block_size) as usize,
+            blocks: MerkleBuilder::with_block_size(self.block_size),
+        })
+    }
+
+    /// Indices of the blocks of `reader` (the file so far) that don't match their leaf
+    pub async fn mismatched_blocks<R>(&self, reader: &mut R) -> crate::Result<Vec<usize>>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut blocks = MerkleBuilder::with_block_size(self.block_size);
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let bytes_read = reader.read(&mut buffer).await?;
+            if bytes_read == 0 {
+                break;
+            }
+            blocks.update(&buffer[..bytes_read]);
+        }
+        let read = blocks.finish();
+        Ok((0..self.leaves.len())
+            .filter(|&index| read.leaves.get(index) != Some(&self.leaves[index]))
+            .collect())
+    }
+}
+
+/// Builds a 'MerkleTree' from data fed in pieces
+pub struct MerkleBuilder {
+    block_size: u64,
+    size: u64,
+    // bytes of the current block hashed so far
+    filled: u64,
+    current: Sha256,
+    leaves: Vec<String>,
+}
+
+impl Default for MerkleBuilder {
+    fn default() -> Self {
+        MerkleBuilder::new()
+    }
+}
+
+impl MerkleBuilder {
+    pub fn new() -> MerkleBuilder {
+        MerkleBuilder::with_block_size(MERKLE_BLOCK_SIZE)
+    }
+
+    pub fn with_block_size(block_size: u64) -> MerkleBuilder {
+        MerkleBuilder {
+            block_size: block_size.max(1),
+            size: 0,
+            filled: 0,
+            current: Sha256::new_with_prefix([LEAF_PREFIX]),
+            leaves: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = cmp::min(self.block_size - self.filled, data.len() as u64) as usize;
+            self.current.update(&data[..take]);
+            self.filled += take as u64;
+            self.size += take as u64;
+            data = &data[take..];
+            if self.filled == self.block_size {
+                self.end_block();
+            }
+        }
+    }
+
+    // Turns the current block into a leaf
+    fn end_block(&mut self) {
+        let current = std::mem::replace(&mut self.current, Sha256::new_with_prefix([LEAF_PREFIX]));
+        self.leaves.push(format!("{:x}", current.finalize()));
+        self.filled = 0;
+    }
+
+    /// Tree of everything fed so far, an empty file has a single empty block
+    pub fn finish(mut self) -> MerkleTree {
+        if self.filled > 0 || self.leaves.is_empty() {
+            self.end_block();
+        }
+        MerkleTree {
+            size: self.size,
+            block_size: self.block_size,
+            leaves: self.leaves,
+        }
+    }
+}
+
+/// Checks a byte range of a file block by block against the leaves of its 'MerkleTree'
+pub struct BlockVerifier {
+    leaves: Vec<String>,
+    // block the range starts at
+    first: usize,
+    blocks: MerkleBuilder,
+}
 
-    // Send back if successful
-    Ok(file_hash_hex)
+impl BlockVerifier {
+    /// Feeds the next bytes of the range, failing as soon as a whole block doesn't match
+    pub fn update(&mut self, data: &[u8]) -> crate::Result<()> {
+        let checked = self.blocks.leaves.len();
+        self.blocks.update(data);
+        self.check(checked)
+    }
+
+    /// Checks the last block, which only ends early at the end of the file
+    pub fn finish(mut self) -> crate::Result<()> {
+        let checked = self.blocks.leaves.len();
+        if self.blocks.filled > 0 {
+            self.blocks.end_block();
+        }
+        self.check(checked)
+    }
+
+    // Compares the leaves completed since `checked`
+    fn check(&self, checked: usize) -> crate::Result<()> {
+        for (index, leaf) in self.blocks.leaves.iter().enumerate().skip(checked) {
+            if self.leaves.get(self.first + index) != Some(leaf) {
+                return Err(VeriflowError::HashMismatch);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hashes a file with `algorithm` and builds its 'MerkleTree', reading it once
+pub async fn hash_file_tree(
+    path: &Path,
+    algorithm: HashAlgorithm,
+    buffer_size: usize,
+) -> crate::Result<(String, MerkleTree)> {
+    let path = path.to_path_buf();
+    run_blocking(
+        move |on_progress| {
+            let mut hasher = Hasher::with_algorithm(algorithm);
+            let mut blocks = MerkleBuilder::new();
+            read_file(
+                &path,
+                buffer_size,
+                |data| {
+                    hasher.update(data);
+                    blocks.update(data);
+                },
+                on_progress,
+            )?;
+            Ok((hasher.finalize(), blocks.finish()))
+        },
+        |_| {},
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_algorithms() -> crate::Result<()> {
+        let sha256 = hash_bytes(b"abc");
+        let sha512 = hash_bytes_with(b"abc", HashAlgorithm::Sha512);
+        let blake3 = hash_bytes_with(b"abc", HashAlgorithm::Blake3);
+
+        // SHA256 stays plain hex, the others are tagged
+        assert_eq!(hash_bytes_with(b"abc", HashAlgorithm::Sha256), sha256);
+        assert_eq!(
+            sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(sha512.starts_with("sha512:ddaf35a193617aba"));
+        assert_eq!(
+            blake3,
+            "blake3:6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+
+        for digest in [&sha256, &sha512, &blake3] {
+            assert_eq!(Checksum::parse(digest)?.to_string(), *digest);
+        }
+        assert_eq!(Checksum::algorithm_of(&blake3), HashAlgorithm::Blake3);
+        assert!(Checksum::parse("md5:900150983cd24fb0d6963f7d28e17f72").is_err());
+        assert!(Checksum::parse("blake3:abc").is_err());
+        assert!(Checksum::parse("../../etc").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_merkle_blocks() -> crate::Result<()> {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let mut builder = MerkleBuilder::with_block_size(1024);
+        for piece in data.chunks(700) {
+            builder.update(piece);
+        }
+        let tree = builder.finish();
+        assert_eq!(tree.leaves.len(), 10);
+        assert!(tree.matches(10_000, &tree.root()));
+        assert!(!tree.matches(9_999, &tree.root()));
+
+        // a range of whole blocks is verified on its own, up to the short last block
+        let mut verifier = tree.verifier(7 * 1024)?;
+        verifier.update(&data[7 * 1024..])?;
+        verifier.finish()?;
+        assert!(tree.verifier(100).is_err());
+
+        // the first corrupted block fails before the range is complete
+        let mut corrupted = data.clone();
+        corrupted[3 * 1024 + 5] ^= 1;
+        let mut verifier = tree.verifier(2 * 1024)?;
+        verifier.update(&corrupted[2 * 1024..3 * 1024])?;
+        assert!(matches!(
+            verifier.update(&corrupted[3 * 1024..4 * 1024]),
+            Err(VeriflowError::HashMismatch)
+        ));
+
+        // a partial file is missing whatever doesn't match yet
+        let mut partial = corrupted[..6 * 1024 + 10].to_vec();
+        assert_eq!(
+            tree.mismatched_blocks(&mut &partial[..]).await?,
+            [3, 6, 7, 8, 9]
+        );
+        partial.truncate(0);
+        assert_eq!(tree.mismatched_blocks(&mut &partial[..]).await?.len(), 10);
+
+        // a single block is its own root, the empty file has one too
+        let single = MerkleBuilder::new().finish();
+        assert_eq!(single.leaves.len(), 1);
+        assert_eq!(single.root(), single.leaves[0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_hashing() -> crate::Result<()> {
+        let dir = std::env::temp_dir().join(format!("veriflow-hashing-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        // one file read through the buffer, one large enough to be mapped
+        let small: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let large: Vec<u8> = (0..MMAP_THRESHOLD as u32 + 5)
+            .map(|i| (i % 241) as u8)
+            .collect();
+        let paths = vec![dir.join("small.bin"), dir.join("large.bin")];
+        tokio::fs::write(&paths[0], &small).await?;
+        tokio::fs::write(&paths[1], &large).await?;
+
+        for algorithm in HashAlgorithm::ALL {
+            let mut reported = 0;
+            let hash =
+                hash_file_with(&paths[1], algorithm, 4096, |bytes| reported += bytes).await?;
+            assert_eq!(hash, hash_bytes_with(&large, algorithm));
+            assert_eq!(reported, large.len());
+
+            let hashes = hash_files(paths.clone(), algorithm, DEFAULT_BUFFER_SIZE).await?;
+            assert_eq!(
+                hashes[0].as_ref().ok(),
+                Some(&hash_bytes_with(&small, algorithm))
+            );
+            assert_eq!(hashes[1].as_ref().ok(), Some(&hash));
+        }
+        let (hash, tree) = hash_file_tree(&paths[0], HashAlgorithm::Blake3, 4096).await?;
+        assert_eq!(hash, hash_bytes_with(&small, HashAlgorithm::Blake3));
+        assert_eq!(tree.size, small.len() as u64);
+
+        let missing = hash_files(
+            vec![dir.join("missing.bin")],
+            HashAlgorithm::Sha256,
+            DEFAULT_BUFFER_SIZE,
+        )
+        .await?;
+        assert!(missing[0].is_err());
+
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
 }
@@ -1,4 +1,6 @@
+use hashing::HashAlgorithm;
 use serde::{Deserialize, Serialize};
+pub mod chunking;
 pub mod hashing;
 pub mod protocol;
 use thiserror::Error;
@@ -9,15 +11,79 @@ use thiserror::Error;
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 #[serde(tag = "command", content = "data")]
 pub enum FileHeader {
-    /// Upload file
+    /// Upload file, an empty hash is sent after the body in a 'Trailer'.
+    /// Hashes other than SHA256 are tagged with their algorithm ('hashing::Checksum')
     Upload {
         name: String,
         size: u64,    // u64 is standard for files
         hash: String, // hex string
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<Signature>, // signed manifest, stored by the server and returned with downloads
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        root: Option<String>, // Merkle root recorded with a downloaded file, its blocks are checked against it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<i32>, // zstd level asked for by the client, or the one a download is sent with
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        integrity: bool, // body in checksummed frames, asked for by the client, or how a download is sent
+        #[serde(default, skip_serializing_if = "HashAlgorithm::is_default")]
+        algorithm: HashAlgorithm, // hash algorithm asked for by the client, or the one 'hash' was made with
     },
 
-    /// Download file
-    Download { name: String },
+    /// Upload a file split into chunks, only the chunks the server is missing are sent.
+    /// The chunk list ('manifest_len' bytes of JSON) follows once the server is 'Ready'
+    ChunkedUpload {
+        name: String,
+        size: u64,
+        hash: String, // hex string of the whole file
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<Signature>,
+        manifest_len: u64,
+    },
+
+    /// Upload a file as byte ranges sent at once over other connections ('UploadRange').
+    /// The server answers 'Accepted' and, once every range arrived and the whole file
+    /// matches 'hash', 'Success' on this connection
+    ParallelUpload {
+        name: String,
+        size: u64,
+        hash: String, // hex string of the whole file
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<Signature>,
+    },
+
+    /// One byte range of a parallel upload, the server answers 'Ready' and then the
+    /// 'length' bytes follow (compressed if agreed)
+    UploadRange {
+        id: String,
+        offset: u64,
+        length: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<i32>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        integrity: bool,
+    },
+
+    /// Download 'length' bytes of a file from 'offset' on. The server answers like a
+    /// 'Download' (size and hash are of the whole file) but only the range follows
+    DownloadRange {
+        name: String,
+        offset: u64,
+        length: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<i32>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        integrity: bool,
+    },
+
+    /// Download file, compressed on the wire if a zstd level is given and the server agrees,
+    /// in checksummed frames with 'integrity'
+    Download {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<i32>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        integrity: bool,
+    },
 
     /// Delete file
     Delete { name: String },
@@ -25,6 +91,65 @@ pub enum FileHeader {
     /// Lists the directories from server's resource folder
     List, // No data required
 
+    /// Storage used and available for the requesting user
+    Usage, // No data required
+
+    /// Lists the deleted files/directories waiting in the server's trash
+    TrashList, // No data required
+
+    /// Move a trashed file/directory back to where it was deleted from
+    Restore { id: u64 },
+
+    /// Permanently remove a trash entry, or the whole trash if no id is given
+    Purge { id: Option<u64> },
+
+    /// Lists the previous versions kept of a file
+    Versions { name: String },
+
+    /// Download a previous version of a file, `hash` may be a unique prefix
+    DownloadVersion {
+        name: String,
+        hash: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<i32>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        integrity: bool,
+    },
+
+    /// Make a previous version the current contents of a file, `hash` may be a unique prefix
+    RestoreVersion { name: String, hash: String },
+
+    /// Block hashes of a file, answered with its 'MerkleTree' as payload. The tree is only
+    /// trusted if it matches the root sent with the download
+    Tree { name: String },
+
+    /// Server accepts an upload, the client sends the first 'preview' bytes
+    /// and waits for a verdict on their content before sending the rest.
+    /// A 'Success' in its place means the server already had the file and nothing is sent.
+    /// The rest of the body is zstd compressed at 'compression' when the server agreed to it,
+    /// and sent in checksummed frames with 'integrity'. The server hashes the upload with
+    /// 'algorithm', if the client's hash was made with another one it follows in a 'Trailer'
+    Ready {
+        preview: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compression: Option<i32>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        integrity: bool,
+        #[serde(default, skip_serializing_if = "HashAlgorithm::is_default")]
+        algorithm: HashAlgorithm,
+    },
+
+    /// Hash (and signed manifest) of an upload, sent after its body when the client
+    /// hashed it while sending
+    Trailer {
+        hash: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<Signature>,
+    },
+
+    /// Server accepts a parallel upload, its ranges are sent with this id
+    Accepted { id: String },
+
     /// Server response to given request
     /// Success
     Success(String),
@@ -33,8 +158,73 @@ pub enum FileHeader {
     Error(String),
 }
 
+/// Ed25519 signature over the name, size and hash of a file (hex encoded)
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Signature {
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl Signature {
+    /// Bytes that get signed for a file, shared by signing and verification
+    pub fn manifest(name: &str, size: u64, hash: &str) -> Vec<u8> {
+        format!("veriflow-manifest-v1\n{name}\n{size}\n{hash}").into_bytes()
+    }
+}
+
+/// Storage usage of a user, sent as the payload of a 'Usage' response
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Usage {
+    pub user: String,
+    pub used: u64,
+    pub quota: Option<u64>, // None if unlimited
+    pub available: u64,     // bytes the user can still upload
+}
+
+/// A file on the server, sent in the payload of a 'List' response
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ListEntry {
+    pub path: String, // relative to the listed directory
+    pub size: u64,    // size of the original content
+    pub stored: u64,  // bytes it takes up on the server, less when compressed at rest
+}
+
+/// A deleted file/directory in the server's trash, sent as the payload of a 'TrashList' response
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TrashEntry {
+    pub id: u64,
+    pub path: String, // original path, relative to the resource directory
+    pub is_dir: bool,
+    pub size: u64,
+    pub deleted_at: u64,         // unix seconds
+    pub expires_at: Option<u64>, // None if kept until purged
+}
+
+/// A previous version of a file, sent in the payload of a 'Versions' response
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct FileVersion {
+    pub hash: String,
+    pub size: u64,
+    pub saved_at: u64, // unix seconds, when it was replaced
+    pub signature: Option<Signature>,
+}
+
 // FileHeader Server Response Logic
 impl FileHeader {
+    /// Header of the `size` bytes of JSON the server sends after it (listings, usage, ...)
+    pub fn payload(name: &str, size: u64) -> FileHeader {
+        FileHeader::Upload {
+            name: name.to_string(),
+            size,
+            hash: String::new(),
+            signature: None,
+            root: None,
+            compression: None,
+            integrity: false,
+            algorithm: HashAlgorithm::default(),
+        }
+    }
+
     /// Check if the server to client header is a Success or an Error then handle it
     pub fn unpack_response(self) -> Result<()> {
         match self {
@@ -47,12 +237,46 @@ impl FileHeader {
         }
     }
 
+    /// Helper to get the variant name (the command)
+    pub fn command(&self) -> &'static str {
+        match self {
+            FileHeader::Upload { .. } => "Upload",
+            FileHeader::ChunkedUpload { .. } => "ChunkedUpload",
+            FileHeader::ParallelUpload { .. } => "ParallelUpload",
+            FileHeader::UploadRange { .. } => "UploadRange",
+            FileHeader::DownloadRange { .. } => "DownloadRange",
+            FileHeader::Download { .. } => "Download",
+            FileHeader::Delete { .. } => "Delete",
+            FileHeader::List => "List",
+            FileHeader::Usage => "Usage",
+            FileHeader::TrashList => "TrashList",
+            FileHeader::Restore { .. } => "Restore",
+            FileHeader::Purge { .. } => "Purge",
+            FileHeader::Versions { .. } => "Versions",
+            FileHeader::DownloadVersion { .. } => "DownloadVersion",
+            FileHeader::RestoreVersion { .. } => "RestoreVersion",
+            FileHeader::Tree { .. } => "Tree",
+            FileHeader::Ready { .. } => "Ready",
+            FileHeader::Trailer { .. } => "Trailer",
+            FileHeader::Accepted { .. } => "Accepted",
+            FileHeader::Success(_) => "Success",
+            FileHeader::Error(_) => "Error",
+        }
+    }
+
     /// Helper to get the variant filename
     pub fn path(&self) -> &str {
         match self {
             FileHeader::Upload { name, .. } => name,
-            FileHeader::Download { name } => name,
+            FileHeader::ChunkedUpload { name, .. } => name,
+            FileHeader::ParallelUpload { name, .. } => name,
+            FileHeader::DownloadRange { name, .. } => name,
+            FileHeader::Download { name, .. } => name,
             FileHeader::Delete { name } => name,
+            FileHeader::Versions { name } => name,
+            FileHeader::DownloadVersion { name, .. } => name,
+            FileHeader::RestoreVersion { name, .. } => name,
+            FileHeader::Tree { name } => name,
             _ => "", // Other enums return empty string
         }
     }
@@ -77,6 +301,10 @@ pub enum VeriflowError {
     #[error("Hash Mismatch: The downloaded file was corrupted")]
     HashMismatch,
 
+    /// Hash of an unknown algorithm, or not a hash at all
+    #[error("Unsupported Hash: \"{0}\"")]
+    UnsupportedHash(String),
+
     /// Giant Header Error
     #[error("Security Alert: Requested header size {0} bytes exceeds the limit.")]
     HeaderSizeExceeded(usize),
@@ -93,6 +321,38 @@ pub enum VeriflowError {
     #[error("Server Error: {0}")]
     ServerError(String),
 
+    /// Client-side encryption/decryption Error
+    #[error("Encryption Error: {0}")]
+    Crypto(String),
+
+    /// Upload rejected by a quota or the free disk space reserve
+    #[error("Quota Exceeded: {0}")]
+    QuotaExceeded(String),
+
+    /// Audit log hash chain doesn't verify
+    #[error("Audit Log Tampered: {0}")]
+    AuditTampered(String),
+
+    /// Signed manifest missing, invalid or from an untrusted key
+    #[error("Signature Error: {0}")]
+    Signature(String),
+
+    /// Upload rejected by the server's upload policy
+    #[error("Upload Rejected: {0}")]
+    PolicyViolation(String),
+
+    /// Peer too slow or unresponsive
+    #[error("Timeout: {0}")]
+    Timeout(String),
+
+    /// Invalid configuration value
+    #[error("Config Error: {0}")]
+    Config(String),
+
+    /// Files of a multi-file transfer that failed for good
+    #[error("Transfer Failed: {0}")]
+    TransferFailed(String),
+
     /// TOML Error
     #[error("Serialisation Error: {0}")]
     TOMLser(#[from] toml::ser::Error),
@@ -118,6 +378,11 @@ mod tests {
             name: String::from(file_name),
             size: 4001,
             hash: String::from("abc123def"),
+            signature: None,
+            root: None,
+            compression: None,
+            integrity: false,
+            algorithm: HashAlgorithm::Sha256,
         };
         // serialise to JSON (Struct -> String)
         let json_string_wrapped = serde_json::to_string(&original_file_header);
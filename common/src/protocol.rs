@@ -1,22 +1,237 @@
+use crate::hashing::{self, BlockVerifier, HashAlgorithm, Hasher, MerkleBuilder, MerkleTree};
 use crate::{Result, VeriflowError};
+use serde::{Deserialize, Serialize};
 use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tracing::warn;
 
 // convention: 4096B or 8192B
-// Buffer size of 8kb for TCP
+// Buffer size of 8kb for TCP, the default chunk size of file bodies
 pub const BUFFER_SIZE: usize = 4096;
 
+// Largest chunk adaptive sizing grows file bodies to by default
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+/// Largest buffer 'Buffers' may configure
+pub const MAX_BUFFER_SIZE: usize = 64 * 1024 * 1024;
+// Adaptive chunks take at least this long at the observed throughput (longer on slow round trips)
+const MIN_CHUNK_TIME: Duration = Duration::from_millis(5);
+
 // Header size (max 4kb)
 pub const MAX_HEADER_SIZE: usize = 4096;
 
 // Max one-shot payload (max 10mb)
 pub const MAX_PAYLOAD_SIZE: usize = 10485760;
 
+/// zstd level used when compression is asked for without one
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// Highest zstd level
+pub const MAX_COMPRESSION_LEVEL: i32 = 22;
+
+// Bytes of a file body compressed into one frame
+const COMPRESSION_BLOCK_SIZE: usize = 128 * 1024;
+
+// Checksummed frames sent before waiting for a reply
+const INTEGRITY_WINDOW: usize = 16;
+// Times a corrupted frame is sent again before the transfer fails
+const MAX_FRAME_RESENDS: u32 = 3;
+// Replies of the receiver to a checksummed frame
+const FRAME_OK: u8 = 0;
+const FRAME_RESEND: u8 = 1;
+
+// Bytes handed to one sendfile call
+#[cfg(target_os = "linux")]
+const ZERO_COPY_CHUNK: usize = 4 * 1024 * 1024;
+
+/// Read/write timeouts of a connection, 'None' disables the check
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)] // to only fill missing blanks
+pub struct Timeouts {
+    /// Max wait (seconds) for a peer to start sending a header
+    pub idle_secs: Option<u64>,
+    /// Max time (seconds) to receive the rest of a header once it started
+    pub header_secs: Option<u64>,
+    /// Max wait (seconds) for a single chunk of a file body to be read or written
+    pub chunk_secs: Option<u64>,
+    /// Minimum average throughput (bytes/s) while receiving a file body
+    pub min_bytes_per_sec: Option<u64>,
+    /// Seconds a transfer may run before the minimum throughput is enforced
+    pub throughput_grace_secs: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            idle_secs: Some(30),
+            header_secs: Some(10),
+            chunk_secs: Some(30),
+            min_bytes_per_sec: Some(1024),
+            throughput_grace_secs: 10,
+        }
+    }
+}
+
+/// Sizes of the buffers file bodies and hashed files are read through
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(default)] // to only fill missing blanks
+pub struct Buffers {
+    /// Bytes read and written at once for raw file bodies, where adaptive sizing starts
+    pub chunk_size: usize,
+    /// Grow chunks while the link keeps up with them (and shrink them again when it doesn't)
+    pub adaptive: bool,
+    /// Largest chunk adaptive sizing grows to
+    pub max_chunk_size: usize,
+    /// Bytes read at once when hashing files
+    pub hash_buffer_size: usize,
+}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Self {
+            chunk_size: BUFFER_SIZE,
+            adaptive: true,
+            max_chunk_size: MAX_CHUNK_SIZE,
+            hash_buffer_size: hashing::DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+impl Buffers {
+    /// Checks every size lies between 1 byte and 'MAX_BUFFER_SIZE', chunks can't outgrow their maximum
+    pub fn validate(&self) -> Result<()> {
+        for (name, size) in [
+            ("chunk_size", self.chunk_size),
+            ("max_chunk_size", self.max_chunk_size),
+            ("hash_buffer_size", self.hash_buffer_size),
+        ] {
+            if !(1..=MAX_BUFFER_SIZE).contains(&size) {
+                return Err(VeriflowError::Config(format!(
+                    "buffers.{name} must be between 1 and {MAX_BUFFER_SIZE} bytes, not {size}"
+                )));
+            }
+        }
+        if self.max_chunk_size < self.chunk_size {
+            return Err(VeriflowError::Config(
+                "buffers.max_chunk_size can't be smaller than buffers.chunk_size".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Size of the next chunk of a file body
+//
+// Adaptive chunks are what the link carried in a round trip (at least 'MIN_CHUNK_TIME')
+// so far: fast links get large chunks and few syscalls, small transfers and slow links stay
+// at small ones. The size at most doubles or halves from one chunk to the next
+struct ChunkSizer {
+    size: usize,
+    min: usize,
+    max: usize,
+    adaptive: bool,
+    window: Duration,
+    // bytes per second, smoothed
+    rate: Option<f64>,
+}
+
+impl ChunkSizer {
+    // `buffers` passed 'Buffers::validate'
+    fn new(buffers: &Buffers, round_trip: Option<Duration>) -> ChunkSizer {
+        ChunkSizer {
+            size: buffers.chunk_size,
+            min: buffers.chunk_size,
+            max: buffers.max_chunk_size,
+            adaptive: buffers.adaptive,
+            window: round_trip.map_or(MIN_CHUNK_TIME, |rtt| rtt.max(MIN_CHUNK_TIME)),
+            rate: None,
+        }
+    }
+
+    // Accounts for a chunk of `bytes` that took `elapsed` to pass
+    fn record(&mut self, bytes: usize, elapsed: Duration) {
+        if !self.adaptive {
+            return;
+        }
+        let rate = bytes as f64 / elapsed.as_secs_f64().max(1e-6);
+        let rate = self.rate.map_or(rate, |old| old * 0.75 + rate * 0.25);
+        self.rate = Some(rate);
+        let target = (rate * self.window.as_secs_f64()) as usize;
+        self.size = target
+            .clamp(self.size / 2, self.size.saturating_mul(2))
+            .clamp(self.min, self.max);
+    }
+}
+
+impl Timeouts {
+    /// No timeouts at all, wait forever
+    pub fn disabled() -> Self {
+        Self {
+            idle_secs: None,
+            header_secs: None,
+            chunk_secs: None,
+            min_bytes_per_sec: None,
+            throughput_grace_secs: 0,
+        }
+    }
+}
+
+// Runs an IO future, failing with a 'Timeout' error when 'limit' seconds pass first
+async fn timed<T>(
+    limit: Option<u64>,
+    operation: &str,
+    future: impl Future<Output = io::Result<T>>,
+) -> Result<T> {
+    match limit {
+        Some(secs) => match tokio::time::timeout(Duration::from_secs(secs), future).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(VeriflowError::Timeout(format!(
+                "{operation} took longer than {secs}s"
+            ))),
+        },
+        None => Ok(future.await?),
+    }
+}
+
+// CRC32 of a checksummed frame, covering its lengths and sequence number (`header`) too
+fn frame_crc(header: &[u8], frame: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(frame);
+    hasher.finalize()
+}
+
+// Error for a frame reply that doesn't fit the frames in flight
+fn unexpected_reply(reply: u8) -> VeriflowError {
+    VeriflowError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unexpected frame reply {reply}"),
+    ))
+}
+
 ///Represents the custom Protocol read and send methods built on top of Tcp
 pub struct ProtocolConnection {
     stream: TcpStream,
+    timeouts: Timeouts,
+    // zstd level file bodies are compressed with, 'None' sends them raw
+    compression: Option<i32>,
+    // hashes the original bytes of file bodies while they pass
+    hasher: Option<Hasher>,
+    // builds the Merkle tree of file bodies while they pass
+    tree: Option<MerkleBuilder>,
+    // checks received file bodies block by block
+    blocks: Option<BlockVerifier>,
+    // file bodies are sent in checksummed frames, each acknowledged by the receiver
+    integrity: bool,
+    // sequence number of the next checksummed frame
+    frames: u32,
+    buffers: Buffers,
 }
 
 impl ProtocolConnection {
@@ -39,8 +254,136 @@ impl ProtocolConnection {
     /// }
     /// ```
     pub async fn new(stream: TcpStream) -> Result<ProtocolConnection> {
-        //returns a new connection object
-        Ok(ProtocolConnection { stream })
+        //returns a new connection object, without timeouts
+        Ok(ProtocolConnection {
+            stream,
+            timeouts: Timeouts::disabled(),
+            compression: None,
+            hasher: None,
+            tree: None,
+            blocks: None,
+            integrity: false,
+            frames: 0,
+            buffers: Buffers::default(),
+        })
+    }
+
+    /// Address of the peer on the other end of the connection
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.stream.peer_addr()?)
+    }
+
+    /// Sets the read/write timeouts used by every following operation
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Sets the chunk sizes raw file bodies are sent and received in, checked by 'Buffers::validate'
+    pub fn set_buffers(&mut self, buffers: Buffers) {
+        self.buffers = buffers;
+    }
+
+    /// Buffer sizes of the connection
+    pub fn buffers(&self) -> &Buffers {
+        &self.buffers
+    }
+
+    // Smoothed round trip time the kernel measured for the connection
+    #[cfg(target_os = "linux")]
+    fn round_trip(&self) -> Option<Duration> {
+        use std::os::fd::AsRawFd;
+
+        let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        // SAFETY: the kernel writes at most `len` bytes into `info`
+        let result = unsafe {
+            libc::getsockopt(
+                self.stream.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut libc::tcp_info as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        (result == 0 && info.tcpi_rtt > 0).then(|| Duration::from_micros(info.tcpi_rtt as u64))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn round_trip(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Compresses the following file bodies with zstd at 'level', 'None' sends them raw
+    ///
+    /// Both ends have to agree on it for every transfer, the receiving end only
+    /// needs to know that the body is compressed, not the level
+    pub fn set_compression(&mut self, level: Option<i32>) {
+        self.compression = level;
+    }
+
+    /// Sends the following file bodies in checksummed frames when 'enabled'
+    ///
+    /// The receiver checks every frame as it arrives and asks for a corrupted one again,
+    /// both ends have to agree on it for every transfer
+    pub fn set_integrity(&mut self, enabled: bool) {
+        self.integrity = enabled;
+        self.frames = 0;
+    }
+
+    /// Hashes the file bodies sent or received from now on with `algorithm`, read with 'take_hash'
+    ///
+    /// Spares reading a file a second time just to hash it
+    pub fn start_hashing(&mut self, algorithm: HashAlgorithm) {
+        self.hasher = Some(Hasher::with_algorithm(algorithm));
+    }
+
+    /// Builds the Merkle tree of the file bodies sent or received from now on, read with 'take_tree'
+    pub fn start_tree(&mut self) {
+        self.tree = Some(MerkleBuilder::new());
+    }
+
+    /// Adds bytes that reached the peer another way (like a preview) to the running hash
+    pub fn update_hash(&mut self, data: &[u8]) {
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(data);
+        }
+        if let Some(tree) = &mut self.tree {
+            tree.update(data);
+        }
+    }
+
+    /// Tagged hash of the bodies since 'start_hashing', which stops hashing
+    pub fn take_hash(&mut self) -> Option<String> {
+        self.hasher.take().map(Hasher::finalize)
+    }
+
+    /// Merkle tree of the bodies since 'start_tree', which stops building it
+    pub fn take_tree(&mut self) -> Option<MerkleTree> {
+        self.tree.take().map(MerkleBuilder::finish)
+    }
+
+    /// Checks the next file body received block by block with 'verifier'
+    ///
+    /// Receiving fails with 'HashMismatch' at the first block that doesn't match,
+    /// before the rest of the body arrives
+    pub fn verify_blocks(&mut self, verifier: BlockVerifier) {
+        self.blocks = Some(verifier);
+    }
+
+    // Feeds received bytes to the block verifier
+    fn check_blocks(&mut self, data: &[u8]) -> Result<()> {
+        match &mut self.blocks {
+            Some(verifier) => verifier.update(data),
+            None => Ok(()),
+        }
+    }
+
+    // Checks the last block once a body is complete
+    fn finish_blocks(&mut self) -> Result<()> {
+        match self.blocks.take() {
+            Some(verifier) => verifier.finish(),
+            None => Ok(()),
+        }
     }
 
     /// Sends the custom json header
@@ -63,7 +406,12 @@ impl ProtocolConnection {
         self.send_data(data_as_bytes).await?;
 
         // Flush to ensure the bytes actually leave the network buffer
-        self.stream.flush().await?;
+        timed(
+            self.timeouts.chunk_secs,
+            "Sending header",
+            self.stream.flush(),
+        )
+        .await?;
 
         Ok(())
     }
@@ -74,7 +422,21 @@ impl ProtocolConnection {
     /// # Returns
     /// A generic Result indicating success or failure
     pub async fn send_data(&mut self, buffer: &[u8]) -> Result<()> {
-        self.stream.write_all(buffer).await?;
+        timed(
+            self.timeouts.chunk_secs,
+            "Sending data",
+            self.stream.write_all(buffer),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Waits until the peer hangs up, however long that takes
+    ///
+    /// For connections idle while the peer is busy on others, anything it sends is discarded
+    pub async fn closed(&mut self) -> Result<()> {
+        let mut buf = [0u8; 64];
+        while self.stream.read(&mut buf).await? > 0 {}
         Ok(())
     }
 
@@ -85,7 +447,12 @@ impl ProtocolConnection {
     pub async fn read_prefix(&mut self) -> Result<usize> {
         //creates the prefix buffer
         let mut buf: [u8; 4] = [0u8; 4];
-        self.stream.read_exact(&mut buf).await?;
+        timed(
+            self.timeouts.idle_secs,
+            "Waiting for a header",
+            self.stream.read_exact(&mut buf),
+        )
+        .await?;
 
         Ok(u32::from_be_bytes(buf) as usize)
     }
@@ -105,7 +472,12 @@ impl ProtocolConnection {
         //creates a buffer for a custom size
         let mut buf = vec![0u8; buffer_len];
 
-        self.stream.read_exact(&mut buf).await?;
+        timed(
+            self.timeouts.header_secs,
+            "Receiving header",
+            self.stream.read_exact(&mut buf),
+        )
+        .await?;
 
         Ok(buf)
     }
@@ -118,13 +490,26 @@ impl ProtocolConnection {
 
         // Read payload (one-shot)
         let mut buf = vec![0u8; payload_len];
-        self.stream.read_exact(&mut buf).await?;
+        timed(
+            self.timeouts.header_secs,
+            "Receiving payload",
+            self.stream.read_exact(&mut buf),
+        )
+        .await?;
 
         Ok(buf)
     }
 
-    pub async fn write_file_to_stream(&mut self, input: &mut File, file_size: u64) -> Result<()> {
-        let mut buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+    /// Streams a file (or any reader) to the network
+    pub async fn write_file_to_stream<R>(&mut self, input: &mut R, file_size: u64) -> Result<()>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        if self.compression.is_some() || self.integrity {
+            return self.write_framed(input, file_size).await;
+        }
+        let mut sizer = ChunkSizer::new(&self.buffers, self.round_trip());
+        let mut buffer = Vec::new();
         let mut total_bytes_read: u64 = 0;
 
         loop {
@@ -132,20 +517,117 @@ impl ProtocolConnection {
                 break;
             }
             let remaining_bytes = file_size - total_bytes_read;
-            let bytes_to_read: usize = cmp::min(buffer.len() as u64, remaining_bytes) as usize;
+            let bytes_to_read: usize = cmp::min(sizer.size as u64, remaining_bytes) as usize;
+            if buffer.len() < bytes_to_read {
+                buffer.resize(bytes_to_read, 0);
+            }
             input.read_exact(&mut buffer[..bytes_to_read]).await?;
-            self.stream.write_all(&buffer[..bytes_to_read]).await?;
+            self.update_hash(&buffer[..bytes_to_read]);
+            let sending = Instant::now();
+            timed(
+                self.timeouts.chunk_secs,
+                "Sending file chunk",
+                self.stream.write_all(&buffer[..bytes_to_read]),
+            )
+            .await?;
+            sizer.record(bytes_to_read, sending.elapsed());
             total_bytes_read += bytes_to_read as u64;
         }
-        self.stream.flush().await?;
+        timed(
+            self.timeouts.chunk_secs,
+            "Sending file",
+            self.stream.flush(),
+        )
+        .await?;
         Ok(())
     }
 
-    /// Streams a file to disk from the network
-    pub async fn read_file_to_disk(&mut self, output: &mut File, file_size: u64) -> Result<()> {
-        // Buffer
-        let mut buffer: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+    /// Sends `length` bytes of `file` from `offset` on, like 'write_file_to_stream'
+    ///
+    /// On Linux raw bodies go straight from the file to the socket with sendfile, never
+    /// copied through user space. Compressed, checksummed or hashed bodies need to see their
+    /// bytes and are sent through the buffer, as is everything where sendfile isn't supported
+    pub async fn write_file_range(
+        &mut self,
+        mut file: File,
+        offset: u64,
+        length: u64,
+    ) -> Result<()> {
+        let mut sent = 0;
+        #[cfg(target_os = "linux")]
+        if self.compression.is_none() && !self.integrity && self.hasher.is_none() {
+            sent = self.send_zero_copy(&file, offset, length).await?;
+            if sent == length {
+                return Ok(());
+            }
+        }
+        file.seek(io::SeekFrom::Start(offset + sent)).await?;
+        self.write_file_to_stream(&mut file, length - sent).await
+    }
+
+    // Sends with sendfile until done, or until it turns out not to work for the file (0 sent)
+    #[cfg(target_os = "linux")]
+    async fn send_zero_copy(&mut self, file: &File, offset: u64, length: u64) -> Result<u64> {
+        use std::os::fd::AsRawFd;
+        use tokio::io::Interest;
+
+        let file_fd = file.as_raw_fd();
+        let socket_fd = self.stream.as_raw_fd();
+        let mut sent = 0;
+        while sent < length {
+            timed(
+                self.timeouts.chunk_secs,
+                "Sending file chunk",
+                self.stream.writable(),
+            )
+            .await?;
+            let count = cmp::min(length - sent, ZERO_COPY_CHUNK as u64) as usize;
+            let mut file_offset = (offset + sent) as libc::off_t;
+            let result = self.stream.try_io(Interest::WRITABLE, || {
+                // SAFETY: both descriptors are borrowed for the call, the offset is a local
+                let written =
+                    unsafe { libc::sendfile(socket_fd, file_fd, &mut file_offset, count) };
+                if written < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(written as u64)
+            });
+            match result {
+                Ok(0) => {
+                    return Err(VeriflowError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "File ended before the body was sent",
+                    )))
+                }
+                Ok(written) => sent += written,
+                // the socket is full, wait until it is writable again
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                // files sendfile can't read from are sent the buffered way
+                Err(e)
+                    if sent == 0
+                        && matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) =>
+                {
+                    return Ok(0)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Streams a file to disk (or any writer) from the network
+    pub async fn read_file_to_disk<W>(&mut self, output: &mut W, file_size: u64) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        if self.compression.is_some() || self.integrity {
+            return self.read_framed(output, file_size).await;
+        }
+        // Buffer, grown with the chunks
+        let mut sizer = ChunkSizer::new(&self.buffers, self.round_trip());
+        let mut buffer = Vec::new();
         let mut total_bytes_read: u64 = 0;
+        let started = Instant::now();
 
         // read file using buffer
         loop {
@@ -158,18 +640,494 @@ impl ProtocolConnection {
             let remaining_bytes: u64 = file_size - total_bytes_read;
 
             // determine how much is left to read
-            let bytes_to_read: usize = cmp::min(buffer.len() as u64, remaining_bytes) as usize;
+            let bytes_to_read: usize = cmp::min(sizer.size as u64, remaining_bytes) as usize;
+            if buffer.len() < bytes_to_read {
+                buffer.resize(bytes_to_read, 0);
+            }
 
             // read the chunk from buffer
-            self.stream.read_exact(&mut buffer[..bytes_to_read]).await?;
+            let receiving = Instant::now();
+            timed(
+                self.timeouts.chunk_secs,
+                "Receiving file chunk",
+                self.stream.read_exact(&mut buffer[..bytes_to_read]),
+            )
+            .await?;
+            sizer.record(bytes_to_read, receiving.elapsed());
+            self.update_hash(&buffer[..bytes_to_read]);
+            self.check_blocks(&buffer[..bytes_to_read])?;
             output.write_all(&buffer[..bytes_to_read]).await?;
 
             total_bytes_read += bytes_to_read as u64;
+
+            // slow clients (trickling a few bytes at a time) are cut off after the grace period
+            self.check_throughput(started, total_bytes_read)?;
         }
 
         // flush to make sure that the data is physically written to disk
         output.flush().await?;
+        self.finish_blocks()?;
+
+        Ok(())
+    }
+
+    // Framed bodies are sent as frames of two u32 (the block and frame length) followed by
+    // the frame: the zstd compressed block, or the block itself when it isn't compressed (or
+    // didn't shrink). Frames carry their block length, so a body may be sent in parts.
+    //
+    // With integrity the prefix also holds the frame's sequence number and a CRC32 over both
+    // lengths, the sequence number and the frame, and the receiver answers every frame it
+    // reads with a reply byte. The sender keeps up to 'INTEGRITY_WINDOW' frames unacknowledged,
+    // on a 'FRAME_RESEND' it sends just that frame again ("selective repeat"); the receiver
+    // holds the frames after it until it arrives.
+    async fn write_framed<R>(&mut self, input: &mut R, file_size: u64) -> Result<()>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let level = self.compression;
+        // frames not acknowledged yet by sequence number (prefix and frame, resends)
+        let mut window: BTreeMap<u32, (Vec<u8>, u32)> = BTreeMap::new();
+        // sequence numbers of the frames sent, in the order their replies come back
+        let mut in_flight: VecDeque<u32> = VecDeque::new();
+        let mut total_bytes_read: u64 = 0;
+
+        while total_bytes_read < file_size || !window.is_empty() {
+            if total_bytes_read < file_size && window.len() < INTEGRITY_WINDOW {
+                let block_len =
+                    cmp::min(COMPRESSION_BLOCK_SIZE as u64, file_size - total_bytes_read) as usize;
+                let mut block = vec![0u8; block_len];
+                input.read_exact(&mut block).await?;
+                self.update_hash(&block);
+                total_bytes_read += block_len as u64;
+
+                // compressing is CPU bound, keep it off the async workers
+                let frame =
+                    match level {
+                        Some(level) => tokio::task::spawn_blocking(move || {
+                            match zstd::bulk::compress(&block, level) {
+                                Ok(compressed) if compressed.len() < block.len() => compressed,
+                                _ => block,
+                            }
+                        })
+                        .await
+                        .map_err(io::Error::other)?,
+                        None => block,
+                    };
+
+                let mut data = Vec::with_capacity(frame.len() + 16);
+                data.extend_from_slice(&(block_len as u32).to_be_bytes());
+                data.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+                if !self.integrity {
+                    data.extend_from_slice(&frame);
+                    self.send_data(&data).await?;
+                    continue;
+                }
+                let seq = self.frames;
+                self.frames = self.frames.wrapping_add(1);
+                data.extend_from_slice(&seq.to_be_bytes());
+                let crc = frame_crc(&data, &frame);
+                data.extend_from_slice(&crc.to_be_bytes());
+                data.extend_from_slice(&frame);
+                self.send_data(&data).await?;
+                window.insert(seq, (data, 0));
+                in_flight.push_back(seq);
+
+                // handle the replies that already arrived without waiting for more
+                let mut reply = [0u8; 1];
+                while let Ok(1) = self.stream.try_read(&mut reply) {
+                    self.handle_reply(reply[0], &mut window, &mut in_flight)
+                        .await?;
+                }
+                continue;
+            }
+
+            // the window is full or everything was sent, wait for the next reply
+            let mut reply = [0u8; 1];
+            timed(
+                self.timeouts.chunk_secs,
+                "Waiting for frame acknowledgement",
+                self.stream.read_exact(&mut reply),
+            )
+            .await?;
+            self.handle_reply(reply[0], &mut window, &mut in_flight)
+                .await?;
+        }
+        timed(
+            self.timeouts.chunk_secs,
+            "Sending file",
+            self.stream.flush(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    // Acts on the receiver's reply to the oldest frame sent and not answered yet
+    async fn handle_reply(
+        &mut self,
+        reply: u8,
+        window: &mut BTreeMap<u32, (Vec<u8>, u32)>,
+        in_flight: &mut VecDeque<u32>,
+    ) -> Result<()> {
+        let seq = in_flight
+            .pop_front()
+            .ok_or_else(|| unexpected_reply(reply))?;
+        match reply {
+            FRAME_OK => {
+                window.remove(&seq);
+                Ok(())
+            }
+            FRAME_RESEND => {
+                let (data, resends) = window
+                    .get_mut(&seq)
+                    .ok_or_else(|| unexpected_reply(reply))?;
+                *resends += 1;
+                if *resends > MAX_FRAME_RESENDS {
+                    return Err(VeriflowError::Io(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Frame {seq} arrived corrupted {MAX_FRAME_RESENDS} times"),
+                    )));
+                }
+                warn!("Frame {} arrived corrupted, sending it again", seq);
+                let data = data.clone();
+                self.send_data(&data).await?;
+                in_flight.push_back(seq);
+                Ok(())
+            }
+            other => Err(unexpected_reply(other)),
+        }
+    }
+
+    // Reads the frames written by 'write_framed', writing out the original bytes
+    async fn read_framed<W>(&mut self, output: &mut W, file_size: u64) -> Result<()>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let mut total_bytes_read: u64 = 0;
+        let started = Instant::now();
+        // frames that arrived while an earlier one is sent again, by sequence number
+        let mut held: BTreeMap<u32, (usize, Vec<u8>)> = BTreeMap::new();
+        while total_bytes_read < file_size {
+            let mut prefix = [0u8; 16];
+            let prefix_len = if self.integrity { 16 } else { 8 };
+            timed(
+                self.timeouts.chunk_secs,
+                "Receiving file chunk",
+                self.stream.read_exact(&mut prefix[..prefix_len]),
+            )
+            .await?;
+            let field = |at: usize| {
+                u32::from_be_bytes([prefix[at], prefix[at + 1], prefix[at + 2], prefix[at + 3]])
+            };
+            let block_len = field(0) as usize;
+            let frame_len = field(4) as usize;
+            // a frame never grows past its block, don't let a peer make us allocate more.
+            // the frame length is needed to find the checksum, a corrupted one ends the transfer
+            if block_len > COMPRESSION_BLOCK_SIZE || block_len as u64 > file_size - total_bytes_read
+            {
+                return Err(VeriflowError::PayloadSizeExceeded(block_len));
+            }
+            if block_len == 0 || frame_len > block_len {
+                return Err(VeriflowError::PayloadSizeExceeded(frame_len));
+            }
+            let mut frame = vec![0u8; frame_len];
+            timed(
+                self.timeouts.chunk_secs,
+                "Receiving file chunk",
+                self.stream.read_exact(&mut frame),
+            )
+            .await?;
+
+            if !self.integrity {
+                total_bytes_read += self.write_frame(output, block_len, frame).await? as u64;
+                self.check_throughput(started, total_bytes_read)?;
+                continue;
+            }
+
+            // nothing in a corrupted frame can be trusted, the sender knows which one it was
+            if frame_crc(&prefix[..12], &frame) != field(12) {
+                warn!("Frame arrived corrupted, asking for it again");
+                self.send_data(&[FRAME_RESEND]).await?;
+                continue;
+            }
+            let seq = field(8);
+            if seq.wrapping_sub(self.frames) as usize >= INTEGRITY_WINDOW || held.contains_key(&seq)
+            {
+                return Err(VeriflowError::Io(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Frame {seq} is outside the window"),
+                )));
+            }
+            self.send_data(&[FRAME_OK]).await?;
+            held.insert(seq, (block_len, frame));
+
+            // write out the frames that are complete up to here
+            while let Some((block_len, frame)) = held.remove(&self.frames) {
+                if block_len as u64 > file_size - total_bytes_read {
+                    return Err(VeriflowError::PayloadSizeExceeded(block_len));
+                }
+                total_bytes_read += self.write_frame(output, block_len, frame).await? as u64;
+                self.frames = self.frames.wrapping_add(1);
+            }
+
+            // slow clients (trickling a few bytes at a time) are cut off after the grace period
+            self.check_throughput(started, total_bytes_read)?;
+        }
+        output.flush().await?;
+        self.finish_blocks()
+    }
+
+    // Writes out the block of a received frame, returns its length
+    async fn write_frame<W>(
+        &mut self,
+        output: &mut W,
+        block_len: usize,
+        frame: Vec<u8>,
+    ) -> Result<usize>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let frame_len = frame.len();
+        let block = if frame_len == block_len {
+            frame
+        } else if self.compression.is_some() {
+            zstd::bulk::decompress(&frame, block_len)?
+        } else {
+            return Err(VeriflowError::PayloadSizeExceeded(frame_len));
+        };
+        if block.len() != block_len {
+            return Err(VeriflowError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Compressed block has the wrong size",
+            )));
+        }
+        self.update_hash(&block);
+        self.check_blocks(&block)?;
+        output.write_all(&block).await?;
+        Ok(block_len)
+    }
+
+    // Fails once the average receive rate drops below the configured minimum
+    fn check_throughput(&self, started: Instant, total_bytes: u64) -> Result<()> {
+        let Some(min_rate) = self.timeouts.min_bytes_per_sec else {
+            return Ok(());
+        };
+        let elapsed = started.elapsed().as_secs_f64();
+        if elapsed < self.timeouts.throughput_grace_secs as f64 {
+            return Ok(());
+        }
+        let rate = total_bytes as f64 / elapsed;
+        if rate < min_rate as f64 {
+            return Err(VeriflowError::Timeout(format!(
+                "Transfer rate of {rate:.0} B/s is below the minimum of {min_rate} B/s"
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_compressed_file_body() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        // text compresses, the random looking tail is sent as is
+        let mut body = b"timestamp,level,message\n".repeat(20_000);
+        body.extend((0..300_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8));
+        let size = body.len() as u64;
+
+        let sent = body.clone();
+        let sender = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await?;
+            let mut connection = ProtocolConnection::new(stream).await?;
+            connection.set_compression(Some(DEFAULT_COMPRESSION_LEVEL));
+            connection.start_hashing(HashAlgorithm::Sha256);
+            // bodies may be sent in parts, like the chunks of a chunked file
+            let (head, tail) = sent.split_at(100_000);
+            connection
+                .write_file_to_stream(&mut &head[..], head.len() as u64)
+                .await?;
+            connection
+                .write_file_to_stream(&mut &tail[..], tail.len() as u64)
+                .await?;
+            Ok::<_, VeriflowError>(connection.take_hash())
+        });
+
+        let (stream, _) = listener.accept().await?;
+        let mut connection = ProtocolConnection::new(stream).await?;
+        connection.set_compression(Some(DEFAULT_COMPRESSION_LEVEL));
+        connection.start_hashing(HashAlgorithm::Sha256);
+        let mut received = Vec::new();
+        connection.read_file_to_disk(&mut received, size).await?;
+        let sent_hash = sender.await.map_err(io::Error::other)??;
+
+        assert_eq!(received, body);
+        // both ends hash the original bytes as they pass
+        let hash = crate::hashing::hash_bytes(&body);
+        assert_eq!(sent_hash, Some(hash.clone()));
+        assert_eq!(connection.take_hash(), Some(hash));
+        assert_eq!(connection.take_hash(), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_range_body() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let path = std::env::temp_dir().join(format!("veriflow-range-{}", std::process::id()));
+        let body: Vec<u8> = (0..9_000_000u32).map(|i| (i % 253) as u8).collect();
+        tokio::fs::write(&path, &body).await?;
+
+        // sent zero-copy where possible, then through the buffer while hashing
+        let file_path = path.clone();
+        let sender = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await?;
+            let mut connection = ProtocolConnection::new(stream).await?;
+            let file = File::open(&file_path).await?;
+            connection.write_file_range(file, 1000, 5_000_000).await?;
+            connection.start_hashing(HashAlgorithm::Sha256);
+            let file = File::open(&file_path).await?;
+            connection
+                .write_file_range(file, 8_000_000, 1_000_000)
+                .await?;
+            Ok::<_, VeriflowError>(connection.take_hash())
+        });
+
+        let (stream, _) = listener.accept().await?;
+        let mut connection = ProtocolConnection::new(stream).await?;
+        let mut received = Vec::new();
+        connection
+            .read_file_to_disk(&mut received, 6_000_000)
+            .await?;
+        let sent_hash = sender.await.map_err(io::Error::other)??;
+
+        assert_eq!(received[..5_000_000], body[1000..5_001_000]);
+        assert_eq!(received[5_000_000..], body[8_000_000..]);
+        assert_eq!(
+            sent_hash,
+            Some(crate::hashing::hash_bytes(&body[8_000_000..]))
+        );
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_sizes() {
+        let buffers = Buffers::default();
+        let mut sizer = ChunkSizer::new(&buffers, Some(Duration::from_millis(20)));
+        assert_eq!(sizer.size, BUFFER_SIZE);
+
+        // a fast link (1 GB/s) doubles the chunks up to the largest size
+        let mut sizes = vec![];
+        for _ in 0..12 {
+            let size = sizer.size;
+            sizer.record(size, Duration::from_secs_f64(size as f64 / 1e9));
+            sizes.push(sizer.size);
+        }
+        assert_eq!(
+            sizes[..3],
+            [2 * BUFFER_SIZE, 4 * BUFFER_SIZE, 8 * BUFFER_SIZE]
+        );
+        assert_eq!(sizer.size, MAX_CHUNK_SIZE);
+
+        // a slow one halves them again, never below the configured size
+        for _ in 0..40 {
+            sizer.record(sizer.size, Duration::from_secs(2));
+        }
+        assert_eq!(sizer.size, BUFFER_SIZE);
+
+        // fixed chunks stay as configured
+        let fixed = Buffers {
+            chunk_size: 64 * 1024,
+            adaptive: false,
+            ..Buffers::default()
+        };
+        let mut sizer = ChunkSizer::new(&fixed, None);
+        sizer.record(64 * 1024, Duration::from_micros(10));
+        assert_eq!(sizer.size, 64 * 1024);
+
+        // sizes out of bounds are refused instead of adjusted
+        assert!(buffers.validate().is_ok());
+        for invalid in [
+            Buffers {
+                chunk_size: 0,
+                ..Buffers::default()
+            },
+            Buffers {
+                hash_buffer_size: MAX_BUFFER_SIZE + 1,
+                ..Buffers::default()
+            },
+            Buffers {
+                max_chunk_size: BUFFER_SIZE / 2,
+                ..Buffers::default()
+            },
+        ] {
+            assert!(matches!(invalid.validate(), Err(VeriflowError::Config(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_integrity_frames_resend() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = proxy.local_addr()?;
+
+        let body: Vec<u8> = (0..600_000u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        let size = body.len() as u64;
+
+        // a proxy that corrupts a byte of the first frame and the sequence number of the
+        // frame after it on their way
+        tokio::spawn(async move {
+            let (sender, _) = proxy.accept().await?;
+            let receiver = TcpStream::connect(addr).await?;
+            let (mut from_sender, mut to_sender) = sender.into_split();
+            let (mut from_receiver, mut to_receiver) = receiver.into_split();
+            tokio::spawn(async move { tokio::io::copy(&mut from_receiver, &mut to_sender).await });
+            let mut buffer = [0u8; 8192];
+            let mut position = 0;
+            loop {
+                let bytes_read = from_sender.read(&mut buffer).await?;
+                if bytes_read == 0 {
+                    break;
+                }
+                for corrupted in [1000, 16 + COMPRESSION_BLOCK_SIZE + 8] {
+                    if (position..position + bytes_read).contains(&corrupted) {
+                        buffer[corrupted - position] ^= 0xff;
+                    }
+                }
+                position += bytes_read;
+                to_receiver.write_all(&buffer[..bytes_read]).await?;
+            }
+            Ok::<_, io::Error>(())
+        });
+
+        let sent = body.clone();
+        let sender = tokio::spawn(async move {
+            let stream = TcpStream::connect(proxy_addr).await?;
+            let mut connection = ProtocolConnection::new(stream).await?;
+            connection.set_integrity(true);
+            connection.write_file_to_stream(&mut &sent[..], size).await
+        });
+
+        let (stream, _) = listener.accept().await?;
+        let mut connection = ProtocolConnection::new(stream).await?;
+        connection.set_integrity(true);
+        connection.start_hashing(HashAlgorithm::Sha256);
+        let mut received = Vec::new();
+        connection.read_file_to_disk(&mut received, size).await?;
+        sender.await.map_err(io::Error::other)??;
 
+        // the corrupted frames were sent again, the whole file still checks out
+        assert_eq!(received, body);
+        assert_eq!(
+            connection.take_hash(),
+            Some(crate::hashing::hash_bytes(&body))
+        );
         Ok(())
     }
 }
@@ -0,0 +1,167 @@
+//! CIDR based IP allow-list and deny-list
+
+use crate::Config;
+use common::VeriflowError;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+// How often the config file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Access section of the server config
+///
+/// Rules are CIDR ranges ("10.0.0.0/8") or single addresses ("192.168.1.20").
+/// Deny rules win over allow rules, an empty allow-list allows every address that is not denied.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(default)] // to only fill missing blanks
+pub struct Access {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+/// Parsed access rules
+#[derive(Debug, Default)]
+pub struct AccessList {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    /// Parses the rules of the access config section
+    pub fn from_config(access: &Access) -> common::Result<AccessList> {
+        Ok(AccessList {
+            allow: parse_rules(&access.allow)?,
+            deny: parse_rules(&access.deny)?,
+        })
+    }
+
+    /// Check if a peer address passes the access rules
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on a dual-stack socket show up as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+// parse CIDR ranges, bare addresses become single host ranges
+fn parse_rules(rules: &[String]) -> common::Result<Vec<IpNet>> {
+    rules
+        .iter()
+        .map(|rule| {
+            let rule = rule.trim();
+            rule.parse::<IpNet>()
+                .or_else(|_| rule.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| VeriflowError::Config(format!("Invalid IP or CIDR rule \"{rule}\"")))
+        })
+        .collect()
+}
+
+/// Access rules shared with the listener, swappable at runtime
+#[derive(Default)]
+pub struct AccessControl {
+    rules: RwLock<Arc<AccessList>>,
+}
+
+impl AccessControl {
+    pub fn new(rules: AccessList) -> AccessControl {
+        AccessControl {
+            rules: RwLock::new(Arc::new(rules)),
+        }
+    }
+
+    /// Check a peer address against the current rules
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let rules = self.rules.read().unwrap_or_else(|e| e.into_inner());
+        rules.is_allowed(ip)
+    }
+
+    /// Replace the rules, new connections are checked against them straight away
+    pub fn reload(&self, rules: AccessList) {
+        let mut current = self.rules.write().unwrap_or_else(|e| e.into_inner());
+        *current = Arc::new(rules);
+    }
+}
+
+/// Watches the config file and reloads the access rules whenever it changes
+///
+/// Invalid rules are logged and the previous rules stay active
+pub async fn watch_config(config_path: PathBuf, control: Arc<AccessControl>) {
+    let mut last_modified = modified(&config_path).await;
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        let current = modified(&config_path).await;
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+
+        match load_access(&config_path).await {
+            Ok(rules) => {
+                control.reload(rules);
+                info!("Reloaded access rules from {:?}", config_path);
+            }
+            Err(e) => error!("Keeping previous access rules: {}", e),
+        }
+    }
+}
+
+// modification time of the config file, None if it can't be read
+async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+// read the config file and parse its access section
+async fn load_access(path: &Path) -> common::Result<AccessList> {
+    let content = tokio::fs::read_to_string(path).await?;
+    let config: Config = toml::from_str(&content)?;
+    AccessList::from_config(&config.access)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow_and_deny_rules() -> common::Result<()> {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        // empty lists allow everyone
+        let open = AccessList::from_config(&Access::default())?;
+        assert!(open.is_allowed(ip("203.0.113.9")));
+
+        let access = Access {
+            allow: vec!["10.0.0.0/8".to_string(), "192.168.1.20".to_string()],
+            deny: vec!["10.0.5.0/24".to_string()],
+        };
+        let rules = AccessList::from_config(&access)?;
+        assert!(rules.is_allowed(ip("10.1.2.3")));
+        assert!(rules.is_allowed(ip("192.168.1.20")));
+        assert!(rules.is_allowed(ip("::ffff:10.1.2.3")));
+        assert!(!rules.is_allowed(ip("10.0.5.7")));
+        assert!(!rules.is_allowed(ip("192.168.1.21")));
+
+        // reload swaps the rules in place
+        let control = AccessControl::new(rules);
+        assert!(!control.is_allowed(ip("203.0.113.9")));
+        control.reload(open);
+        assert!(control.is_allowed(ip("203.0.113.9")));
+
+        let invalid = Access {
+            allow: vec!["10.0.0.0/33".to_string()],
+            deny: vec![],
+        };
+        assert!(AccessList::from_config(&invalid).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+//! Append-only, hash-chained audit log of every handled request
+//!
+//! Each line is a JSON entry holding the hash of the previous entry, its own hash covers
+//! all of its fields. Editing, removing or reordering entries breaks the chain, which
+//! `verify` detects. Keep a copy of the head hash elsewhere to also detect a truncated tail.
+
+use common::{hashing, FileHeader, VeriflowError};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+
+// Previous hash of the first entry
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Audit section of the server config
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)] // to only fill missing blanks
+pub struct Audit {
+    pub enabled: bool,
+    /// Log file, `.veriflow/audit.log` inside the resource directory when not set
+    pub path: Option<PathBuf>,
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: None,
+        }
+    }
+}
+
+impl Audit {
+    /// Location of the log for the resource directory `root`
+    pub fn log_path(&self, root: &Path) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| root.join(crate::META_DIR).join("audit.log"))
+    }
+}
+
+/// A single audited request
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub timestamp: u64, // unix seconds
+    pub peer: String,
+    pub identity: String,
+    pub operation: String,
+    pub path: String,
+    pub size: Option<u64>,
+    pub hash: Option<String>,
+    pub outcome: String,
+    pub prev_hash: String,
+}
+
+impl AuditEntry {
+    /// Starts an entry for a received header, the chain fields are filled in when it is appended
+    pub fn new(header: &FileHeader, peer: String, identity: String) -> AuditEntry {
+        let (size, hash) = match header {
+            FileHeader::Upload { size, hash, .. }
+            | FileHeader::ChunkedUpload { size, hash, .. }
+            | FileHeader::ParallelUpload { size, hash, .. } => (Some(*size), Some(hash.clone())),
+            _ => (None, None),
+        };
+
+        AuditEntry {
+            seq: 0,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            peer,
+            identity,
+            operation: header.command().to_string(),
+            path: header.path().to_string(),
+            size,
+            hash,
+            outcome: String::new(),
+            prev_hash: String::new(),
+        }
+    }
+
+    // hash over every field, including the link to the previous entry
+    fn digest(&self) -> common::Result<String> {
+        Ok(hashing::hash_bytes(&serde_json::to_vec(self)?))
+    }
+}
+
+// A line of the log file
+#[derive(Serialize, Deserialize)]
+struct AuditLine {
+    #[serde(flatten)]
+    entry: AuditEntry,
+    entry_hash: String,
+}
+
+struct ChainHead {
+    file: File,
+    next_seq: u64,
+    last_hash: String,
+}
+
+/// Writer appending to the audit log
+pub struct AuditLog {
+    head: Mutex<ChainHead>,
+}
+
+impl AuditLog {
+    /// Opens (or creates) the log and continues its chain
+    pub async fn open(path: &Path) -> common::Result<AuditLog> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // continue from the last entry
+        let mut next_seq = 0;
+        let mut last_hash = GENESIS_HASH.to_string();
+        if tokio::fs::try_exists(path).await? {
+            let mut lines = BufReader::new(File::open(path).await?).lines();
+            let mut last_line = None;
+            while let Some(line) = lines.next_line().await? {
+                if !line.trim().is_empty() {
+                    last_line = Some(line);
+                }
+            }
+            if let Some(line) = last_line {
+                let last: AuditLine = serde_json::from_str(&line)?;
+                next_seq = last.entry.seq + 1;
+                last_hash = last.entry_hash;
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        Ok(AuditLog {
+            head: Mutex::new(ChainHead {
+                file,
+                next_seq,
+                last_hash,
+            }),
+        })
+    }
+
+    /// Links the entry to the chain and appends it
+    pub async fn record(&self, mut entry: AuditEntry) -> common::Result<()> {
+        let mut head = self.head.lock().await;
+        entry.seq = head.next_seq;
+        entry.prev_hash = head.last_hash.clone();
+
+        let entry_hash = entry.digest()?;
+        let mut line = serde_json::to_vec(&AuditLine {
+            entry,
+            entry_hash: entry_hash.clone(),
+        })?;
+        line.push(b'\n');
+
+        head.file.write_all(&line).await?;
+        // entries must survive a crash
+        head.file.sync_data().await?;
+
+        head.next_seq += 1;
+        head.last_hash = entry_hash;
+        Ok(())
+    }
+}
+
+/// Result of a successful chain verification
+pub struct Verified {
+    pub entries: u64,
+    pub head_hash: String,
+}
+
+/// Checks every link of the audit log at `path`
+///
+/// # Returns
+/// The number of entries and the hash of the last one, or 'AuditTampered' naming the first bad line
+pub async fn verify(path: &Path) -> common::Result<Verified> {
+    let mut lines = BufReader::new(File::open(path).await?).lines();
+    let mut expected_seq = 0;
+    let mut last_hash = GENESIS_HASH.to_string();
+    let mut line_no = 0;
+
+    while let Some(line) = lines.next_line().await? {
+        line_no += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let tampered =
+            |reason: &str| VeriflowError::AuditTampered(format!("line {line_no}: {reason}"));
+
+        let parsed: AuditLine =
+            serde_json::from_str(&line).map_err(|_| tampered("not a valid entry"))?;
+        if parsed.entry.seq != expected_seq {
+            return Err(tampered(&format!(
+                "expected entry {expected_seq}, found {}",
+                parsed.entry.seq
+            )));
+        }
+        if parsed.entry.prev_hash != last_hash {
+            return Err(tampered("link to the previous entry is broken"));
+        }
+        if parsed.entry.digest()? != parsed.entry_hash {
+            return Err(tampered("entry was modified"));
+        }
+
+        expected_seq += 1;
+        last_hash = parsed.entry_hash;
+    }
+
+    Ok(Verified {
+        entries: expected_seq,
+        head_hash: last_hash,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::hashing::HashAlgorithm;
+
+    #[tokio::test]
+    async fn test_audit_chain_detects_tampering() -> common::Result<()> {
+        let dir = std::env::temp_dir().join(format!("veriflow-audit-{}", std::process::id()));
+        let path = dir.join("audit.log");
+        let header = FileHeader::Upload {
+            name: "report.pdf".to_string(),
+            size: 42,
+            hash: "abc".to_string(),
+            signature: None,
+            root: None,
+            compression: None,
+            integrity: false,
+            algorithm: HashAlgorithm::Sha256,
+        };
+
+        // the chain continues across restarts
+        for _ in 0..2 {
+            let log = AuditLog::open(&path).await?;
+            let mut entry = AuditEntry::new(&header, "10.0.0.1:5000".into(), "10.0.0.1".into());
+            entry.outcome = "success".to_string();
+            log.record(entry).await?;
+        }
+        let verified = verify(&path).await?;
+        assert_eq!(verified.entries, 2);
+
+        // change the recorded size of the first entry
+        let content = tokio::fs::read_to_string(&path).await?;
+        tokio::fs::write(&path, content.replacen("\"size\":42", "\"size\":43", 1)).await?;
+        assert!(matches!(
+            verify(&path).await,
+            Err(VeriflowError::AuditTampered(_))
+        ));
+
+        // drop the first entry
+        let second_only: String = content.lines().skip(1).collect();
+        tokio::fs::write(&path, second_only).await?;
+        assert!(verify(&path).await.is_err());
+
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,684 @@
+//! Storage backends holding the files served to clients
+//!
+//! Handlers address files by key (their path relative to the resource directory, with '/'
+//! separators) and go through a 'FileStore' instead of the filesystem. The local backend maps
+//! keys onto the resource directory, the memory backend keeps files in RAM (tests) and the S3
+//! backend stores every key as an object of a bucket on any S3-compatible store (AWS, MinIO).
+//! Server metadata (indexes, signatures, the audit log) always stays in the local `.veriflow`.
+
+use async_trait::async_trait;
+use common::{hashing, VeriflowError};
+use futures::TryStreamExt;
+use object_store::aws::AmazonS3Builder;
+use object_store::buffered::{BufReader, BufWriter};
+use object_store::path::Path as ObjectPath;
+use object_store::ObjectStore;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+/// Reads a stored file
+pub type Reader = Box<dyn AsyncRead + Send + Unpin>;
+/// Writes a stored file, complete once shut down
+pub type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Backend section of the server config
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Backend {
+    /// Files in the resource directory
+    #[default]
+    Local,
+    /// Files in memory, lost on restart
+    Memory,
+    /// Objects in a bucket of an S3-compatible store
+    S3(S3),
+}
+
+/// Connection to an S3-compatible store
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct S3 {
+    pub bucket: String,
+    /// Endpoint of a self-hosted store (MinIO...), AWS when not set
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    /// Credentials, read from the AWS_* environment variables when not set
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// Allow plain http endpoints, for stores on the local network
+    #[serde(default)]
+    pub allow_http: bool,
+}
+
+impl Backend {
+    /// Opens the configured backend, the local one serves the resource directory `root`
+    pub fn open(&self, root: &Path) -> common::Result<Arc<dyn FileStore>> {
+        Ok(match self {
+            Backend::Local => Arc::new(LocalStore::new(root)),
+            Backend::Memory => Arc::new(MemoryStore::default()),
+            Backend::S3(config) => Arc::new(S3Store::connect(config)?),
+        })
+    }
+
+    /// Whether files are plain files in the resource directory (needed for hard link dedupe)
+    pub fn is_local(&self) -> bool {
+        *self == Backend::Local
+    }
+}
+
+/// A stored file or directory
+#[derive(Debug, PartialEq, Clone)]
+pub struct FileInfo {
+    pub key: String,
+    pub size: u64, // 0 for directories
+    pub is_dir: bool,
+    pub modified: Option<SystemTime>,
+}
+
+/// Where the files served to clients are kept
+#[async_trait]
+pub trait FileStore: Send + Sync {
+    /// Reads the file stored under `key`
+    async fn open(&self, key: &str) -> common::Result<Reader>;
+
+    /// Reads the file stored under `key` from byte `offset` on
+    async fn open_at(&self, key: &str, offset: u64) -> common::Result<Reader> {
+        // stores that can't seek skip the bytes before the offset
+        let mut reader = self.open(key).await?;
+        let skipped =
+            tokio::io::copy(&mut (&mut reader).take(offset), &mut tokio::io::sink()).await?;
+        if skipped != offset {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Offset past the end of the file",
+            )
+            .into());
+        }
+        Ok(reader)
+    }
+
+    /// Writes a new file under `key`, replacing the one there
+    async fn create(&self, key: &str) -> common::Result<Writer>;
+
+    /// The file or directory under `key`, a 'NotFound' error if there is none
+    async fn stat(&self, key: &str) -> common::Result<FileInfo>;
+
+    /// Every file below the directory `prefix`, the whole store when empty
+    async fn list(&self, prefix: &str) -> common::Result<Vec<FileInfo>>;
+
+    /// Removes the file or directory under `key`
+    async fn delete(&self, key: &str) -> common::Result<()>;
+
+    /// Moves the file or directory under `from` to `to`, replacing a file there
+    async fn rename(&self, from: &str, to: &str) -> common::Result<()>;
+
+    /// Where the file under `key` lies on the local disk, for stores keeping plain files
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// Moves the local file at `path` into the store under `key`, replacing the file there
+    async fn put_file(&self, key: &str, path: &Path) -> common::Result<()> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut writer = self.create(key).await?;
+        tokio::io::copy(&mut file, &mut writer).await?;
+        writer.shutdown().await?;
+        tokio::fs::remove_file(path).await?;
+        Ok(())
+    }
+}
+
+/// Whether anything is stored under `key`
+pub async fn exists(files: &dyn FileStore, key: &str) -> bool {
+    files.stat(key).await.is_ok()
+}
+
+/// Copies the file under `from` to `to`
+pub async fn copy(files: &dyn FileStore, from: &str, to: &str) -> common::Result<()> {
+    let mut reader = files.open(from).await?;
+    let mut writer = files.create(to).await?;
+    tokio::io::copy(&mut reader, &mut writer).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// SHA256 of the file under `key`
+pub async fn hash(files: &dyn FileStore, key: &str) -> common::Result<String> {
+    let mut reader = files.open(key).await?;
+    hashing::hash_reader(&mut reader, |_| {}).await
+}
+
+fn not_found(key: &str) -> VeriflowError {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Nothing stored under {key}"),
+    )
+    .into()
+}
+
+// keys below the directory `prefix`
+fn is_below(key: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || key
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Files in the resource directory
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: &Path) -> LocalStore {
+        LocalStore {
+            root: root.to_path_buf(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    fn info(key: String, metadata: &std::fs::Metadata) -> FileInfo {
+        FileInfo {
+            key,
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            is_dir: metadata.is_dir(),
+            modified: metadata.modified().ok(),
+        }
+    }
+}
+
+#[async_trait]
+impl FileStore for LocalStore {
+    async fn open(&self, key: &str) -> common::Result<Reader> {
+        Ok(Box::new(tokio::fs::File::open(self.path(key)).await?))
+    }
+
+    async fn open_at(&self, key: &str, offset: u64) -> common::Result<Reader> {
+        let mut file = tokio::fs::File::open(self.path(key)).await?;
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        Ok(Box::new(file))
+    }
+
+    async fn create(&self, key: &str) -> common::Result<Writer> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // never write into an existing file, its body may be shared with other paths
+        let _ = tokio::fs::remove_file(&path).await;
+        Ok(Box::new(tokio::fs::File::create(&path).await?))
+    }
+
+    async fn stat(&self, key: &str) -> common::Result<FileInfo> {
+        let metadata = tokio::fs::metadata(self.path(key)).await?;
+        Ok(Self::info(key.to_string(), &metadata))
+    }
+
+    async fn list(&self, prefix: &str) -> common::Result<Vec<FileInfo>> {
+        let mut files = Vec::new();
+        let mut stack = vec![self.path(prefix)];
+        while let Some(dir) = stack.pop() {
+            let mut dir_content = tokio::fs::read_dir(dir).await?;
+            while let Some(entry) = dir_content.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    stack.push(entry.path());
+                } else if file_type.is_file() {
+                    let path = entry.path();
+                    let relative = path.strip_prefix(&self.root).unwrap_or(&path);
+                    let key = relative.to_string_lossy().replace("\\", "/");
+                    files.push(Self::info(key, &entry.metadata().await?));
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    async fn delete(&self, key: &str) -> common::Result<()> {
+        let path = self.path(key);
+        if tokio::fs::metadata(&path).await?.is_dir() {
+            tokio::fs::remove_dir_all(&path).await?;
+        } else {
+            tokio::fs::remove_file(&path).await?;
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> common::Result<()> {
+        let destination = self.path(to);
+        if let Some(parent) = destination.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(self.path(from), destination).await?;
+        Ok(())
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+
+    async fn put_file(&self, key: &str, path: &Path) -> common::Result<()> {
+        let destination = self.path(key);
+        if let Some(parent) = destination.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // other filesystems can't be renamed from, copy then
+        if tokio::fs::rename(path, &destination).await.is_err() {
+            let _ = tokio::fs::remove_file(&destination).await;
+            tokio::fs::copy(path, &destination).await?;
+            tokio::fs::remove_file(path).await?;
+        }
+        Ok(())
+    }
+}
+
+type MemoryEntries = BTreeMap<String, (Arc<[u8]>, SystemTime)>;
+type MemoryFiles = Arc<Mutex<MemoryEntries>>;
+
+/// Files kept in memory, for tests and throwaway servers
+#[derive(Default)]
+pub struct MemoryStore {
+    files: MemoryFiles,
+}
+
+impl MemoryStore {
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryEntries> {
+        // a panic while holding the lock can't leave the map half updated
+        self.files
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // bytes of the file `key`
+    fn content(&self, key: &str) -> common::Result<Arc<[u8]>> {
+        self.lock()
+            .get(key)
+            .map(|(content, _)| Arc::clone(content))
+            .ok_or_else(|| not_found(key))
+    }
+
+    // keys of the file `key` or of the files below it
+    fn matching(&self, key: &str) -> Vec<String> {
+        let files = self.lock();
+        if files.contains_key(key) {
+            return vec![key.to_string()];
+        }
+        files
+            .keys()
+            .filter(|stored| is_below(stored, key))
+            .cloned()
+            .collect()
+    }
+}
+
+// collects the written bytes, stored once shut down
+struct MemoryWriter {
+    key: String,
+    buffer: Vec<u8>,
+    files: MemoryFiles,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.buffer.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let content: Arc<[u8]> = std::mem::take(&mut self.buffer).into();
+        let mut files = self
+            .files
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        files.insert(self.key.clone(), (content, SystemTime::now()));
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl FileStore for MemoryStore {
+    async fn open(&self, key: &str) -> common::Result<Reader> {
+        Ok(Box::new(io::Cursor::new(self.content(key)?)))
+    }
+
+    async fn open_at(&self, key: &str, offset: u64) -> common::Result<Reader> {
+        let mut reader = io::Cursor::new(self.content(key)?);
+        reader.set_position(offset);
+        Ok(Box::new(reader))
+    }
+
+    async fn create(&self, key: &str) -> common::Result<Writer> {
+        Ok(Box::new(MemoryWriter {
+            key: key.to_string(),
+            buffer: Vec::new(),
+            files: Arc::clone(&self.files),
+        }))
+    }
+
+    async fn stat(&self, key: &str) -> common::Result<FileInfo> {
+        let files = self.lock();
+        if let Some((content, modified)) = files.get(key) {
+            return Ok(FileInfo {
+                key: key.to_string(),
+                size: content.len() as u64,
+                is_dir: false,
+                modified: Some(*modified),
+            });
+        }
+        // directories only exist through the files below them
+        if key.is_empty() || files.keys().any(|stored| is_below(stored, key)) {
+            return Ok(FileInfo {
+                key: key.to_string(),
+                size: 0,
+                is_dir: true,
+                modified: None,
+            });
+        }
+        Err(not_found(key))
+    }
+
+    async fn list(&self, prefix: &str) -> common::Result<Vec<FileInfo>> {
+        let files = self.lock();
+        Ok(files
+            .iter()
+            .filter(|(key, _)| is_below(key, prefix))
+            .map(|(key, (content, modified))| FileInfo {
+                key: key.clone(),
+                size: content.len() as u64,
+                is_dir: false,
+                modified: Some(*modified),
+            })
+            .collect())
+    }
+
+    async fn delete(&self, key: &str) -> common::Result<()> {
+        let keys = self.matching(key);
+        if keys.is_empty() {
+            return Err(not_found(key));
+        }
+        let mut files = self.lock();
+        for key in keys {
+            files.remove(&key);
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> common::Result<()> {
+        let keys = self.matching(from);
+        if keys.is_empty() {
+            return Err(not_found(from));
+        }
+        let mut files = self.lock();
+        for key in keys {
+            if let Some(file) = files.remove(&key) {
+                files.insert(format!("{to}{}", &key[from.len()..]), file);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Objects in a bucket of an S3-compatible store
+pub struct S3Store {
+    store: Arc<dyn ObjectStore>,
+}
+
+impl S3Store {
+    /// Wraps any object store, tests use an in-memory stand-in
+    pub fn new(store: Arc<dyn ObjectStore>) -> S3Store {
+        S3Store { store }
+    }
+
+    /// Connects to the bucket of the config, missing settings are read from the environment
+    pub fn connect(config: &S3) -> common::Result<S3Store> {
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&config.bucket)
+            .with_allow_http(config.allow_http);
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        if let Some(region) = &config.region {
+            builder = builder.with_region(region);
+        }
+        if let Some(access_key_id) = &config.access_key_id {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = &config.secret_access_key {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+        let store = builder
+            .build()
+            .map_err(|e| VeriflowError::Config(format!("S3 backend: {e}")))?;
+        Ok(S3Store::new(Arc::new(store)))
+    }
+
+    fn info(meta: object_store::ObjectMeta) -> FileInfo {
+        FileInfo {
+            key: meta.location.to_string(),
+            size: meta.size,
+            is_dir: false,
+            modified: Some(meta.last_modified.into()),
+        }
+    }
+
+    // keys of the object `key` or of the objects below it
+    async fn matching(&self, key: &str) -> common::Result<Vec<String>> {
+        match self.store.head(&ObjectPath::from(key)).await {
+            Ok(_) => Ok(vec![key.to_string()]),
+            Err(object_store::Error::NotFound { .. }) => Ok(self
+                .list(key)
+                .await?
+                .into_iter()
+                .map(|info| info.key)
+                .collect()),
+            Err(e) => Err(io::Error::from(e).into()),
+        }
+    }
+}
+
+#[async_trait]
+impl FileStore for S3Store {
+    async fn open(&self, key: &str) -> common::Result<Reader> {
+        let meta = self
+            .store
+            .head(&ObjectPath::from(key))
+            .await
+            .map_err(io::Error::from)?;
+        Ok(Box::new(BufReader::new(Arc::clone(&self.store), &meta)))
+    }
+
+    async fn open_at(&self, key: &str, offset: u64) -> common::Result<Reader> {
+        let meta = self
+            .store
+            .head(&ObjectPath::from(key))
+            .await
+            .map_err(io::Error::from)?;
+        let mut reader = BufReader::new(Arc::clone(&self.store), &meta);
+        reader.seek(io::SeekFrom::Start(offset)).await?;
+        Ok(Box::new(reader))
+    }
+
+    async fn create(&self, key: &str) -> common::Result<Writer> {
+        Ok(Box::new(BufWriter::new(
+            Arc::clone(&self.store),
+            ObjectPath::from(key),
+        )))
+    }
+
+    async fn stat(&self, key: &str) -> common::Result<FileInfo> {
+        match self.store.head(&ObjectPath::from(key)).await {
+            Ok(meta) => Ok(Self::info(meta)),
+            // prefixes stand in for directories
+            Err(object_store::Error::NotFound { .. }) => {
+                let prefix = ObjectPath::from(key);
+                let mut below = self.store.list(Some(&prefix));
+                match below.try_next().await.map_err(io::Error::from)? {
+                    Some(_) => Ok(FileInfo {
+                        key: key.to_string(),
+                        size: 0,
+                        is_dir: true,
+                        modified: None,
+                    }),
+                    None => Err(not_found(key)),
+                }
+            }
+            Err(e) => Err(io::Error::from(e).into()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> common::Result<Vec<FileInfo>> {
+        let prefix = ObjectPath::from(prefix);
+        let objects: Vec<object_store::ObjectMeta> = self
+            .store
+            .list(Some(&prefix))
+            .try_collect()
+            .await
+            .map_err(io::Error::from)?;
+        Ok(objects.into_iter().map(Self::info).collect())
+    }
+
+    async fn delete(&self, key: &str) -> common::Result<()> {
+        let keys = self.matching(key).await?;
+        if keys.is_empty() {
+            return Err(not_found(key));
+        }
+        for key in keys {
+            self.store
+                .delete(&ObjectPath::from(key.as_str()))
+                .await
+                .map_err(io::Error::from)?;
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> common::Result<()> {
+        let keys = self.matching(from).await?;
+        if keys.is_empty() {
+            return Err(not_found(from));
+        }
+        for key in keys {
+            let destination = format!("{to}{}", &key[from.len()..]);
+            self.store
+                .rename(
+                    &ObjectPath::from(key.as_str()),
+                    &ObjectPath::from(destination.as_str()),
+                )
+                .await
+                .map_err(io::Error::from)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    // the same operations must behave alike on every backend
+    async fn exercise(files: &dyn FileStore) -> common::Result<()> {
+        let mut writer = files.create("docs/a.txt").await?;
+        writer.write_all(b"hello").await?;
+        writer.shutdown().await?;
+        let mut writer = files.create("b.txt").await?;
+        writer.write_all(b"bye").await?;
+        writer.shutdown().await?;
+
+        let mut content = Vec::new();
+        files
+            .open("docs/a.txt")
+            .await?
+            .read_to_end(&mut content)
+            .await?;
+        assert_eq!(content, b"hello");
+        let mut content = Vec::new();
+        files
+            .open_at("docs/a.txt", 2)
+            .await?
+            .read_to_end(&mut content)
+            .await?;
+        assert_eq!(content, b"llo");
+        assert_eq!(files.stat("docs/a.txt").await?.size, 5);
+        assert!(files.stat("docs").await?.is_dir);
+        assert!(files.stat("doc").await.is_err());
+
+        let mut keys: Vec<String> = files
+            .list("")
+            .await?
+            .into_iter()
+            .map(|info| info.key)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, vec!["b.txt", "docs/a.txt"]);
+
+        // directories move and go as a whole
+        files.rename("docs", "archive/docs").await?;
+        assert_eq!(files.list("archive").await?[0].key, "archive/docs/a.txt");
+        copy(files, "b.txt", "c.txt").await?;
+        assert_eq!(hash(files, "c.txt").await?, hashing::hash_bytes(b"bye"));
+
+        // local files are moved in
+        let local = std::env::temp_dir().join(format!("veriflow-put-{}", std::process::id()));
+        tokio::fs::write(&local, b"put").await?;
+        files.put_file("c.txt", &local).await?;
+        assert_eq!(hash(files, "c.txt").await?, hashing::hash_bytes(b"put"));
+        assert!(!tokio::fs::try_exists(&local).await?);
+        files.delete("archive").await?;
+        files.delete("b.txt").await?;
+        assert!(!exists(files, "archive/docs/a.txt").await);
+        assert_eq!(files.list("").await?.len(), 1);
+        files.delete("c.txt").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backends_behave_alike() -> common::Result<()> {
+        let root = std::env::temp_dir().join(format!("veriflow-backend-{}", std::process::id()));
+        tokio::fs::create_dir_all(&root).await?;
+        exercise(&LocalStore::new(&root)).await?;
+        tokio::fs::remove_dir_all(&root).await?;
+
+        exercise(&MemoryStore::default()).await?;
+
+        // S3 code paths against an in-process object store standing in for MinIO
+        exercise(&S3Store::new(Arc::new(
+            object_store::memory::InMemory::new(),
+        )))
+        .await?;
+
+        // and against a real S3-compatible store when one is configured, e.g.
+        // VERIFLOW_S3_ENDPOINT=http://localhost:9000 VERIFLOW_S3_BUCKET=veriflow-test for MinIO
+        if let (Ok(endpoint), Ok(bucket)) = (
+            std::env::var("VERIFLOW_S3_ENDPOINT"),
+            std::env::var("VERIFLOW_S3_BUCKET"),
+        ) {
+            let config = S3 {
+                bucket,
+                endpoint: Some(endpoint),
+                region: None,
+                access_key_id: None,
+                secret_access_key: None,
+                allow_http: true,
+            };
+            exercise(&S3Store::connect(&config)?).await?;
+        }
+        Ok(())
+    }
+}
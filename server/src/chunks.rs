@@ -0,0 +1,306 @@
+//! Chunk store for chunked uploads
+//!
+//! Chunks are stored once in `.veriflow/chunks/<sha256>` of the storage backend. A file uploaded in chunks is kept
+//! at its path as a small recipe listing its chunks, downloads reassemble it on the fly.
+//! Which files are recipes is recorded in the layout index, see 'layouts'.
+//! Chunks no recipe (file, trash entry or version) refers to are removed by the periodic
+//! sweep once they are older than 'GC_GRACE', so uploads in progress keep theirs.
+
+use crate::backend::{self, FileStore, Reader, Writer};
+use crate::layouts::{Layout, LayoutIndex};
+use crate::packed;
+use crate::META_DIR;
+use common::chunking::{Chunk, MAX_CHUNK_SIZE};
+use common::hashing::{self, Checksum, MerkleBuilder, MerkleTree};
+use common::VeriflowError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Chunk directory inside the metadata directory
+const CHUNKS_DIR: &str = "chunks";
+// Chunks being received, inside the chunk directory
+const INCOMING_DIR: &str = "incoming";
+// Unreferenced chunks younger than this may belong to an upload in progress
+const GC_GRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Chunks a file is made of, stored at its path instead of the bytes
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Recipe {
+    pub size: u64,
+    pub hash: String,
+    pub chunks: Vec<Chunk>,
+}
+
+/// Checks a client's chunk list before anything is looked up or stored by it
+pub fn check_manifest(chunks: &[Chunk], size: u64) -> common::Result<()> {
+    let invalid =
+        |reason: &str| VeriflowError::UnexpectedFileHeader(format!("Chunk list: {reason}"));
+
+    // hashes name files in the chunk store
+    let valid_hash = |hash: &str| hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit());
+    if !chunks.iter().all(|chunk| valid_hash(&chunk.hash)) {
+        return Err(invalid("invalid chunk hash"));
+    }
+    if chunks
+        .iter()
+        .any(|chunk| chunk.size == 0 || chunk.size > MAX_CHUNK_SIZE as u64)
+    {
+        return Err(invalid("invalid chunk size"));
+    }
+    if chunks.iter().map(|chunk| chunk.size).sum::<u64>() != size {
+        return Err(invalid("chunks don't add up to the file size"));
+    }
+    Ok(())
+}
+
+/// Reads the recipe stored under `key`, which the layout index lists as one
+pub async fn read_recipe(files: &dyn FileStore, key: &str) -> common::Result<Recipe> {
+    let mut json = Vec::new();
+    files.open(key).await?.read_to_end(&mut json).await?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Size and SHA256 of the file stored under `key` with `layout`, reading recipes and packed headers instead of hashing them
+pub async fn describe(
+    files: &dyn FileStore,
+    key: &str,
+    layout: Layout,
+) -> common::Result<(u64, String)> {
+    match layout {
+        Layout::Packed => {
+            let header = packed::read_header(files, key).await?;
+            Ok((header.size, header.hash))
+        }
+        Layout::Recipe => {
+            let recipe = read_recipe(files, key).await?;
+            Ok((recipe.size, recipe.hash))
+        }
+        Layout::Plain => {
+            let size = files.stat(key).await?.size;
+            Ok((size, backend::hash(files, key).await?))
+        }
+    }
+}
+
+/// Size of the original file stored under `key` with `layout`, which takes up `stored` bytes
+pub async fn logical_size(
+    files: &dyn FileStore,
+    key: &str,
+    stored: u64,
+    layout: Layout,
+) -> common::Result<u64> {
+    match layout {
+        Layout::Packed => Ok(packed::read_header(files, key).await?.size),
+        Layout::Recipe => Ok(read_recipe(files, key).await?.size),
+        Layout::Plain => Ok(stored),
+    }
+}
+
+/// Chunks of a storage backend keyed by their SHA256
+pub struct ChunkStore {
+    files: Arc<dyn FileStore>,
+    layouts: Arc<LayoutIndex>,
+    // names chunks being received
+    next_incoming: AtomicU64,
+}
+
+impl ChunkStore {
+    pub fn new(files: Arc<dyn FileStore>, layouts: Arc<LayoutIndex>) -> ChunkStore {
+        ChunkStore {
+            files,
+            layouts,
+            next_incoming: AtomicU64::new(0),
+        }
+    }
+
+    /// Key of the chunk with `hash`
+    pub fn chunk(&self, hash: &str) -> String {
+        format!("{META_DIR}/{CHUNKS_DIR}/{hash}")
+    }
+
+    /// Indexes of the chunks in `chunks` that aren't stored yet, each hash listed once
+    pub async fn missing(&self, chunks: &[Chunk]) -> common::Result<Vec<u64>> {
+        let mut seen = HashSet::new();
+        let mut missing = Vec::new();
+        for (index, chunk) in chunks.iter().enumerate() {
+            if !seen.insert(chunk.hash.as_str()) {
+                continue;
+            }
+            let stored = self
+                .files
+                .stat(&self.chunk(&chunk.hash))
+                .await
+                .is_ok_and(|stored| stored.size == chunk.size);
+            if !stored {
+                missing.push(index as u64);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Creates a file to receive a chunk into, returns its key
+    pub async fn incoming(&self) -> common::Result<(String, Writer)> {
+        let id = self.next_incoming.fetch_add(1, Ordering::Relaxed);
+        let key = format!(
+            "{META_DIR}/{CHUNKS_DIR}/{INCOMING_DIR}/{}-{id}",
+            std::process::id()
+        );
+        let writer = self.files.create(&key).await?;
+        Ok((key, writer))
+    }
+
+    /// Moves a received chunk into the store if it matches `chunk`
+    ///
+    /// # Returns
+    /// false (and the received file removed) if the bytes don't match the chunk's hash
+    pub async fn store(&self, incoming: &str, chunk: &Chunk) -> common::Result<bool> {
+        let hash = backend::hash(&*self.files, incoming).await?;
+        if hash != chunk.hash {
+            self.files.delete(incoming).await?;
+            return Ok(false);
+        }
+        self.files.rename(incoming, &self.chunk(&hash)).await?;
+        Ok(true)
+    }
+
+    /// Opens the chunk with `hash`
+    pub async fn open(&self, hash: &str) -> common::Result<Reader> {
+        self.files.open(&self.chunk(hash)).await
+    }
+
+    /// Hashes the chunks of a recipe in order, which must give the whole file's hash
+    ///
+    /// # Returns
+    /// The Merkle tree of the file, None if the chunks don't add up to it
+    pub async fn verify(&self, recipe: &Recipe) -> common::Result<Option<MerkleTree>> {
+        let algorithm = Checksum::algorithm_of(&recipe.hash);
+        let mut hasher = hashing::Hasher::with_algorithm(algorithm);
+        let mut blocks = MerkleBuilder::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut size = 0;
+        for chunk in &recipe.chunks {
+            let mut file = self.open(&chunk.hash).await?;
+            loop {
+                let bytes_read = file.read(&mut buffer).await?;
+                if bytes_read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..bytes_read]);
+                blocks.update(&buffer[..bytes_read]);
+                size += bytes_read as u64;
+            }
+        }
+        let verified = size == recipe.size && hasher.finalize() == recipe.hash;
+        Ok(verified.then(|| blocks.finish()))
+    }
+
+    /// Stores a recipe under `key`, replacing the file there
+    pub async fn write_recipe(&self, recipe: &Recipe, key: &str) -> common::Result<()> {
+        let mut file = self.files.create(key).await?;
+        file.write_all(&serde_json::to_vec(recipe)?).await?;
+        file.shutdown().await?;
+        self.layouts.set(key, Layout::Recipe).await
+    }
+
+    /// Removes the chunks no recipe refers to anymore
+    ///
+    /// # Returns
+    /// The number of bytes freed
+    pub async fn collect_garbage(&self) -> common::Result<u64> {
+        let chunks_dir = format!("{META_DIR}/{CHUNKS_DIR}");
+        if !backend::exists(&*self.files, &chunks_dir).await {
+            return Ok(0);
+        }
+
+        // recipes may sit anywhere: files, trash entries and versions
+        let mut referenced = HashSet::new();
+        for key in self.layouts.keys(Layout::Recipe).await {
+            if let Ok(recipe) = read_recipe(&*self.files, &key).await {
+                referenced.extend(recipe.chunks.into_iter().map(|chunk| chunk.hash));
+            }
+        }
+
+        // leftovers of aborted uploads go the same way
+        let mut freed = 0;
+        for file in self.files.list(&chunks_dir).await? {
+            let name = file.key.rsplit('/').next().unwrap_or(&file.key);
+            let settled = file
+                .modified
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age >= GC_GRACE);
+            if settled && !referenced.contains(name) {
+                self.files.delete(&file.key).await?;
+                freed += file.size;
+            }
+        }
+        Ok(freed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryStore;
+
+    #[tokio::test]
+    async fn test_chunk_recipes() -> common::Result<()> {
+        let root = std::env::temp_dir().join(format!("veriflow-chunks-{}", std::process::id()));
+        let files: Arc<dyn FileStore> = Arc::new(MemoryStore::default());
+        let layouts = Arc::new(LayoutIndex::load(&root).await?);
+        let store = ChunkStore::new(Arc::clone(&files), Arc::clone(&layouts));
+
+        let parts: [&[u8]; 3] = [b"first ", b"second ", b"first "];
+        let chunks: Vec<Chunk> = parts
+            .iter()
+            .map(|part| Chunk {
+                hash: hashing::hash_bytes(part),
+                size: part.len() as u64,
+            })
+            .collect();
+
+        // repeated chunks are only asked for once
+        assert_eq!(store.missing(&chunks).await?, vec![0, 1]);
+        for index in [0, 1] {
+            let (incoming, mut file) = store.incoming().await?;
+            file.write_all(parts[index]).await?;
+            file.shutdown().await?;
+            assert!(store.store(&incoming, &chunks[index]).await?);
+        }
+        assert!(store.missing(&chunks).await?.is_empty());
+
+        // corrupt chunks are refused
+        let (incoming, mut file) = store.incoming().await?;
+        file.write_all(b"garbage").await?;
+        file.shutdown().await?;
+        assert!(!store.store(&incoming, &chunks[0]).await?);
+
+        let recipe = Recipe {
+            size: 19,
+            hash: hashing::hash_bytes(b"first second first "),
+            chunks,
+        };
+        let tree = store.verify(&recipe).await?.expect("chunks add up");
+        assert_eq!(tree.size, 19);
+        store.write_recipe(&recipe, "file.bin").await?;
+        assert_eq!(layouts.get("file.bin").await, Layout::Recipe);
+        assert_eq!(read_recipe(&*files, "file.bin").await?, recipe);
+        assert_eq!(
+            describe(&*files, "file.bin", Layout::Recipe).await?.1,
+            hashing::hash_bytes(b"first second first ")
+        );
+
+        // an uploaded copy of the recipe is just a file
+        let mut copy = files.create("copy.bin").await?;
+        copy.write_all(&serde_json::to_vec(&recipe)?).await?;
+        copy.shutdown().await?;
+        let (size, _) = describe(&*files, "copy.bin", layouts.get("copy.bin").await).await?;
+        assert_ne!(size, 19);
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,21 @@
+//! CLI Arg Parsing Struct
+
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// Runs the server when no command is given
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Check the audit log chain offline and exit
+    VerifyAudit {
+        /// Log file, the one from config.toml by default
+        path: Option<PathBuf>,
+    },
+}
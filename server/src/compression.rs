@@ -0,0 +1,92 @@
+//! Wire compression of file bodies
+//!
+//! Clients ask for zstd at a level of their choosing per transfer, the server agrees unless
+//! compression is off or the file is of a type that is already compressed (archives, images,
+//! audio, video), where zstd would only cost CPU. Hashes are always of the original bytes.
+
+use common::protocol::MAX_COMPRESSION_LEVEL;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Compression section of the server config
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)] // to only fill missing blanks
+pub struct Compression {
+    /// Compress transfers when the client asks for it
+    pub enabled: bool,
+    /// Highest zstd level the server spends CPU on, higher requests are lowered to it
+    pub max_level: i32,
+    /// Extensions of already compressed content, always sent raw
+    pub skip_extensions: Vec<String>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_level: 9,
+            skip_extensions: [
+                "7z", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg",
+                "jpg", "lz4", "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "png", "pptx", "rar",
+                "tgz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+            ]
+            .iter()
+            .map(|ext| ext.to_string())
+            .collect(),
+        }
+    }
+}
+
+impl Compression {
+    /// The zstd level a transfer of `name` is compressed with, 'None' if it is sent raw
+    ///
+    /// # Arguments
+    /// * 'requested' - level the client asked for, 'None' if it didn't ask
+    pub fn negotiate(&self, name: &str, requested: Option<i32>) -> Option<i32> {
+        let requested = requested?;
+        if !self.enabled || requested < 1 || self.is_compressed(name) {
+            return None;
+        }
+        Some(
+            requested
+                .min(self.max_level)
+                .clamp(1, MAX_COMPRESSION_LEVEL),
+        )
+    }
+
+    /// Whether `name` is of a type that is compressed already
+    pub fn is_compressed(&self, name: &str) -> bool {
+        let Some(extension) = Path::new(name).extension().and_then(|ext| ext.to_str()) else {
+            return false;
+        };
+        self.skip_extensions
+            .iter()
+            .any(|skip| skip.trim_start_matches('.').eq_ignore_ascii_case(extension))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_compression() {
+        let compression = Compression::default();
+
+        // only compressed when asked for, at most at the configured level
+        assert_eq!(compression.negotiate("logs/app.log", None), None);
+        assert_eq!(compression.negotiate("logs/app.log", Some(3)), Some(3));
+        assert_eq!(compression.negotiate("data.CSV", Some(19)), Some(9));
+        assert_eq!(compression.negotiate("data.csv", Some(0)), None);
+
+        // already compressed content is sent as is
+        assert_eq!(compression.negotiate("photo.JPG", Some(3)), None);
+        assert_eq!(compression.negotiate("backup.tar.gz", Some(3)), None);
+
+        let disabled = Compression {
+            enabled: false,
+            ..Compression::default()
+        };
+        assert_eq!(disabled.negotiate("data.csv", Some(3)), None);
+    }
+}
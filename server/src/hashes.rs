@@ -0,0 +1,187 @@
+//! Hash algorithms accepted for uploads, and the hashes recorded with stored files
+//!
+//! Clients pick SHA256, SHA512 or BLAKE3 per upload, the server agrees if the algorithm is in
+//! `hashing.algorithms` and falls back to SHA256 otherwise. The verified hash of every upload
+//! is kept in `.veriflow/hashes/` (mirroring the resource directory), so downloads neither
+//! read a file twice nor hand back another hash than the one it was uploaded and signed with.
+//! The root of the upload's Merkle tree is kept on a second line, when one was built.
+
+use crate::META_DIR;
+use common::hashing::HashAlgorithm;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+// Hash directory inside the metadata directory
+const HASHES_DIR: &str = "hashes";
+
+/// Hashing section of the server config
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)] // to only fill missing blanks
+pub struct Hashing {
+    /// Algorithms uploads may be hashed with, SHA256 is always understood
+    pub algorithms: Vec<HashAlgorithm>,
+}
+
+impl Default for Hashing {
+    fn default() -> Self {
+        Self {
+            algorithms: HashAlgorithm::ALL.to_vec(),
+        }
+    }
+}
+
+impl Hashing {
+    /// The algorithm an upload is hashed with, SHA256 unless `requested` is accepted
+    pub fn negotiate(&self, requested: HashAlgorithm) -> HashAlgorithm {
+        requested.negotiate(&self.algorithms)
+    }
+}
+
+/// Hashes recorded with the files in a resource directory
+pub struct HashStore {
+    dir: PathBuf,
+}
+
+impl HashStore {
+    pub fn new(root: &Path) -> HashStore {
+        HashStore {
+            dir: root.join(META_DIR).join(HASHES_DIR),
+        }
+    }
+
+    // hash file of the file stored under `key`
+    fn sidecar(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.hash"))
+    }
+
+    /// Records the verified hash of an uploaded file, with the root of its Merkle tree if one was built
+    pub async fn save(&self, key: &str, hash: &str, root: Option<&str>) -> common::Result<()> {
+        let sidecar = self.sidecar(key);
+        if let Some(parent) = sidecar.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let record = match root {
+            Some(root) => format!("{hash}\n{root}"),
+            None => hash.to_string(),
+        };
+        tokio::fs::write(&sidecar, record).await?;
+        Ok(())
+    }
+
+    /// Hash recorded for the file stored under `key`, None if it was stored before hashes were recorded
+    pub async fn load(&self, key: &str) -> common::Result<Option<String>> {
+        Ok(self.record(key).await?.map(|(hash, _)| hash))
+    }
+
+    /// Merkle root recorded with the hash of the file stored under `key`
+    pub async fn root(&self, key: &str) -> common::Result<Option<String>> {
+        Ok(self.record(key).await?.and_then(|(_, root)| root))
+    }
+
+    // hash and root recorded for `key`
+    async fn record(&self, key: &str) -> common::Result<Option<(String, Option<String>)>> {
+        let sidecar = self.sidecar(key);
+        if !tokio::fs::try_exists(&sidecar).await? {
+            return Ok(None);
+        }
+        let record = tokio::fs::read_to_string(&sidecar).await?;
+        Ok(Some(match record.split_once('\n') {
+            Some((hash, root)) => (hash.to_string(), Some(root.to_string())),
+            None => (record, None),
+        }))
+    }
+
+    /// Moves the hashes of `from` and everything below it to `to` (trashed or restored files)
+    pub async fn rename(&self, from: &str, to: &str) -> common::Result<()> {
+        for (source, destination) in [
+            (self.sidecar(from), self.sidecar(to)),
+            (self.dir.join(from), self.dir.join(to)),
+        ] {
+            if tokio::fs::try_exists(&source).await? {
+                if let Some(parent) = destination.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::rename(&source, &destination).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the hashes of `key` and everything below it (deleted files)
+    pub async fn remove(&self, key: &str) -> common::Result<()> {
+        if key.is_empty() {
+            // the whole resource directory
+            if tokio::fs::try_exists(&self.dir).await? {
+                tokio::fs::remove_dir_all(&self.dir).await?;
+            }
+            return Ok(());
+        }
+        let sidecar = self.sidecar(key);
+        if tokio::fs::try_exists(&sidecar).await? {
+            tokio::fs::remove_file(&sidecar).await?;
+        }
+        let dir = self.dir.join(key);
+        if tokio::fs::try_exists(&dir).await? {
+            tokio::fs::remove_dir_all(&dir).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::hashing;
+
+    #[tokio::test]
+    async fn test_recorded_hashes() -> common::Result<()> {
+        let root = std::env::temp_dir().join(format!("veriflow-hashes-{}", std::process::id()));
+        let store = HashStore::new(&root);
+        let blake3 = hashing::hash_bytes_with(b"movie", HashAlgorithm::Blake3);
+
+        store.save("media/movie.mkv", &blake3, None).await?;
+        assert_eq!(store.load("media/movie.mkv").await?, Some(blake3.clone()));
+        assert_eq!(store.root("media/movie.mkv").await?, None);
+
+        // an upload with another algorithm replaces the old hash, its tree root is kept too
+        let sha256 = hashing::hash_bytes(b"movie");
+        let tree_root = hashing::hash_bytes(b"root");
+        store
+            .save("media/movie.mkv", &sha256, Some(&tree_root))
+            .await?;
+        assert_eq!(store.load("media/movie.mkv").await?, Some(sha256));
+        assert_eq!(
+            store.root("media/movie.mkv").await?,
+            Some(tree_root.clone())
+        );
+
+        // hashes move with trashed files
+        store
+            .save("media/movie.mkv", &blake3, Some(&tree_root))
+            .await?;
+        store.rename("media", ".trash/1/media").await?;
+        assert_eq!(store.load(".trash/1/media/movie.mkv").await?, Some(blake3));
+        assert_eq!(
+            store.root(".trash/1/media/movie.mkv").await?,
+            Some(tree_root)
+        );
+        store.remove(".trash/1").await?;
+        assert_eq!(store.load(".trash/1/media/movie.mkv").await?, None);
+
+        // only accepted algorithms are used
+        let hashing = Hashing {
+            algorithms: vec![HashAlgorithm::Sha256, HashAlgorithm::Blake3],
+        };
+        assert_eq!(
+            hashing.negotiate(HashAlgorithm::Blake3),
+            HashAlgorithm::Blake3
+        );
+        assert_eq!(
+            hashing.negotiate(HashAlgorithm::Sha512),
+            HashAlgorithm::Sha256
+        );
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+//! How stored files are laid out
+//!
+//! A file is stored as it was uploaded, as the recipe of its chunks or packed (zstd compressed
+//! behind a header). Which one is recorded by the server in `.veriflow/layouts.json`, never
+//! inferred from the stored bytes, so an uploaded file that happens to look like a recipe or a
+//! packed file is served as it is.
+//! Files missing from the index are stored as they are.
+
+use crate::META_DIR;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// Index inside the metadata directory
+const INDEX_FILE: &str = "layouts.json";
+
+/// How the bytes under a key make up the file
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// The file as it was uploaded
+    #[default]
+    Plain,
+    /// A 'Recipe' listing the file's chunks
+    Recipe,
+    /// A 'PackedHeader' followed by the compressed file
+    Packed,
+}
+
+/// Layouts of the files in a resource directory that aren't stored as they are
+pub struct LayoutIndex {
+    root: PathBuf,
+    // by key, plain files are left out
+    index: tokio::sync::Mutex<BTreeMap<String, Layout>>,
+}
+
+// whether `key` is `parent` or below it
+fn is_below(key: &str, parent: &str) -> bool {
+    parent.is_empty()
+        || key == parent
+        || key
+            .strip_prefix(parent)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+impl LayoutIndex {
+    /// Loads the layout index of the resource directory `root`
+    pub async fn load(root: &Path) -> common::Result<LayoutIndex> {
+        let index_path = root.join(META_DIR).join(INDEX_FILE);
+        let index = if tokio::fs::try_exists(&index_path).await? {
+            serde_json::from_slice(&tokio::fs::read(&index_path).await?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(LayoutIndex {
+            root: root.to_path_buf(),
+            index: tokio::sync::Mutex::new(index),
+        })
+    }
+
+    /// Layout of the file stored under `key`
+    pub async fn get(&self, key: &str) -> Layout {
+        let index = self.index.lock().await;
+        index.get(key).copied().unwrap_or_default()
+    }
+
+    /// Records the layout of what was just stored under `key`
+    pub async fn set(&self, key: &str, layout: Layout) -> common::Result<()> {
+        let mut index = self.index.lock().await;
+        let changed = if layout == Layout::Plain {
+            index.remove(key).is_some()
+        } else {
+            index.insert(key.to_string(), layout) != Some(layout)
+        };
+        if changed {
+            self.persist(&index).await?;
+        }
+        Ok(())
+    }
+
+    /// Keys of the files stored with `layout`, plain files aren't listed
+    pub async fn keys(&self, layout: Layout) -> Vec<String> {
+        let index = self.index.lock().await;
+        index
+            .iter()
+            .filter(|(_, kept)| **kept == layout)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Moves the layouts of `from` and everything below it to `to` (trashed, restored or archived files)
+    pub async fn rename(&self, from: &str, to: &str) -> common::Result<()> {
+        let mut index = self.index.lock().await;
+        let moved: Vec<String> = index
+            .keys()
+            .filter(|key| is_below(key, from))
+            .cloned()
+            .collect();
+        if moved.is_empty() {
+            return Ok(());
+        }
+        for key in moved {
+            if let Some(layout) = index.remove(&key) {
+                index.insert(format!("{to}{}", &key[from.len()..]), layout);
+            }
+        }
+        self.persist(&index).await
+    }
+
+    /// Forgets the layouts of `key` and everything below it (deleted files)
+    pub async fn remove(&self, key: &str) -> common::Result<()> {
+        let mut index = self.index.lock().await;
+        let before = index.len();
+        index.retain(|kept, _| !is_below(kept, key));
+        if index.len() == before {
+            return Ok(());
+        }
+        self.persist(&index).await
+    }
+
+    // write the layout index to disk
+    async fn persist(&self, index: &BTreeMap<String, Layout>) -> common::Result<()> {
+        let snapshot = serde_json::to_vec_pretty(index)?;
+
+        let meta_dir = self.root.join(META_DIR);
+        tokio::fs::create_dir_all(&meta_dir).await?;
+        // write then rename so a crash never leaves a half written index
+        let tmp = meta_dir.join(format!("{INDEX_FILE}.tmp"));
+        tokio::fs::write(&tmp, snapshot).await?;
+        tokio::fs::rename(&tmp, meta_dir.join(INDEX_FILE)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_layout_index() -> common::Result<()> {
+        let root = std::env::temp_dir().join(format!("veriflow-layouts-{}", std::process::id()));
+        let layouts = LayoutIndex::load(&root).await?;
+        assert_eq!(layouts.get("media/movie.mkv").await, Layout::Plain);
+
+        layouts.set("media/movie.mkv", Layout::Recipe).await?;
+        layouts.set("media/movies.txt", Layout::Recipe).await?;
+        assert_eq!(layouts.get("media/movie.mkv").await, Layout::Recipe);
+
+        // layouts move with trashed directories, similar names stay where they are
+        layouts
+            .rename("media/movie.mkv", ".trash/1/movie.mkv")
+            .await?;
+        assert_eq!(layouts.get("media/movie.mkv").await, Layout::Plain);
+        assert_eq!(layouts.get(".trash/1/movie.mkv").await, Layout::Recipe);
+        assert_eq!(layouts.get("media/movies.txt").await, Layout::Recipe);
+
+        // and are kept across restarts
+        let layouts = LayoutIndex::load(&root).await?;
+        assert_eq!(
+            layouts.keys(Layout::Recipe).await,
+            vec![".trash/1/movie.mkv", "media/movies.txt"]
+        );
+
+        // a plain upload over a recipe, packed afterwards, then everything deleted
+        layouts.set("media/movies.txt", Layout::Plain).await?;
+        assert_eq!(layouts.get("media/movies.txt").await, Layout::Plain);
+        layouts.set("media/movies.txt", Layout::Packed).await?;
+        assert_eq!(layouts.keys(Layout::Packed).await, vec!["media/movies.txt"]);
+        layouts.remove("media").await?;
+        layouts.remove(".trash").await?;
+        assert!(layouts.keys(Layout::Recipe).await.is_empty());
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+}
@@ -1,12 +1,70 @@
 use std::path::PathBuf;
 
+use access::Access;
+use audit::Audit;
+use backend::Backend;
+use common::protocol::{Buffers, Timeouts};
+use compression::Compression;
+use hashes::Hashing;
+use limits::Limits;
+use policy::Policy;
+use quota::Quota;
 use serde::{Deserialize, Serialize};
+use storage::Storage;
+use trash::Trash;
+use versions::Versions;
+pub mod access;
+pub mod audit;
+pub mod backend;
+pub mod chunks;
+pub mod compression;
+pub mod hashes;
+pub mod layouts;
+pub mod limits;
+pub mod packed;
+pub mod parallel;
+pub mod policy;
+pub mod quota;
 pub mod server;
+pub mod signatures;
+pub mod storage;
+pub mod trash;
+pub mod trees;
+pub mod versions;
+
+/// Hidden directory inside the resource folder for server metadata, never served to clients
+pub const META_DIR: &str = ".veriflow";
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Config {
     pub network: Network,
     pub directory: Directory,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub access: Access,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub buffers: Buffers,
+    #[serde(default)]
+    pub quota: Quota,
+    #[serde(default)]
+    pub policy: Policy,
+    #[serde(default)]
+    pub audit: Audit,
+    #[serde(default)]
+    pub trash: Trash,
+    #[serde(default)]
+    pub versions: Versions,
+    #[serde(default)]
+    pub storage: Storage,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default)]
+    pub hashing: Hashing,
 }
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub struct Network {
@@ -20,6 +78,7 @@ pub struct Directory {
 #[cfg(test)]
 mod test {
     use crate::server::Listener;
+    use common::hashing::HashAlgorithm;
     pub use common::protocol::ProtocolConnection;
     pub use common::FileHeader;
     use tokio::net::TcpStream;
@@ -52,11 +111,16 @@ mod test {
             name: String::from(file_name),
             size: 4001,
             hash: String::from("abc123def"),
+            signature: None,
+            root: None,
+            compression: None,
+            integrity: false,
+            algorithm: HashAlgorithm::Sha256,
         };
         // serialise to JSON (Struct -> String)
         let json_string_wrapped = serde_json::to_string(&original_file_header);
         let json_string = json_string_wrapped.unwrap();
-        let _result = connection.send_header(&json_string).await?;
+        connection.send_header(&json_string).await?;
         let header_length = connection.read_prefix().await?;
         let byte_header = connection.read_body(header_length).await?;
         let header = String::from_utf8_lossy(&byte_header);
@@ -68,5 +132,193 @@ mod test {
         Ok(())
     }
 
-    //async fn test_server_upload() {}
+    #[tokio::test]
+    async fn test_silent_client_times_out(
+    ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use common::protocol::Timeouts;
+        use common::VeriflowError;
+
+        let mut server = Listener::new("127.0.0.1", "0").await?;
+        let addr = server.local_addr()?;
+
+        // client connects but never sends anything
+        let _stream = TcpStream::connect(addr).await?;
+        let mut conn = ProtocolConnection::new(server.accept_once().await?).await?;
+        conn.set_timeouts(Timeouts {
+            idle_secs: Some(1),
+            ..Timeouts::default()
+        });
+
+        let started = std::time::Instant::now();
+        let result = conn.read_prefix().await;
+        assert!(matches!(result, Err(VeriflowError::Timeout(_))));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        Ok(())
+    }
+
+    type AnyResult<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+    // Serves a fresh resource directory named after the test
+    async fn serve(name: &str) -> AnyResult<(std::net::SocketAddr, std::path::PathBuf)> {
+        let root = std::env::temp_dir().join(format!("veriflow-{name}-{}", std::process::id()));
+        let _ = tokio::fs::remove_dir_all(&root).await;
+        tokio::fs::create_dir_all(&root).await?;
+        let mut server = Listener::new("127.0.0.1", "0").await?;
+        let addr = server.local_addr()?;
+        let served = root.clone();
+        tokio::spawn(async move { server.listen(served).await });
+        Ok((addr, root))
+    }
+
+    // Reads the next header the server sends
+    async fn read_header(connection: &mut ProtocolConnection) -> AnyResult<FileHeader> {
+        let len = connection.read_prefix().await?;
+        let body = connection.read_body(len).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    // Uploads `body` as `name` claiming `hash`, an empty one is followed by 'trailer'
+    async fn upload(
+        addr: std::net::SocketAddr,
+        name: &str,
+        body: &[u8],
+        hash: &str,
+        trailer: Option<&str>,
+    ) -> AnyResult<FileHeader> {
+        let mut connection = ProtocolConnection::new(TcpStream::connect(addr).await?).await?;
+        let header = FileHeader::Upload {
+            name: name.to_string(),
+            size: body.len() as u64,
+            hash: hash.to_string(),
+            signature: None,
+            root: None,
+            compression: None,
+            integrity: false,
+            algorithm: HashAlgorithm::Sha256,
+        };
+        connection
+            .send_header(&serde_json::to_string(&header)?)
+            .await?;
+        match read_header(&mut connection).await? {
+            FileHeader::Ready { .. } => {}
+            other => return Ok(other),
+        }
+        connection.send_data(body).await?;
+        if let Some(hash) = trailer {
+            let trailer = FileHeader::Trailer {
+                hash: hash.to_string(),
+                signature: None,
+            };
+            connection
+                .send_header(&serde_json::to_string(&trailer)?)
+                .await?;
+        }
+        read_header(&mut connection).await
+    }
+
+    #[tokio::test]
+    async fn test_failed_overwrite_keeps_file() -> AnyResult<()> {
+        use crate::backend::Backend;
+        use crate::layouts::LayoutIndex;
+        use crate::versions::{VersionStore, Versions};
+        use common::hashing::hash_bytes;
+
+        let (addr, root) = serve("overwrite").await?;
+        let first = upload(addr, "notes.txt", b"first", &hash_bytes(b"first"), None).await?;
+        assert!(matches!(first, FileHeader::Success(_)));
+
+        // the body doesn't match its hash, the stored file stays as it was
+        let failed = upload(addr, "notes.txt", b"second", &hash_bytes(b"other"), None).await?;
+        assert!(matches!(failed, FileHeader::Error(_)));
+        assert_eq!(tokio::fs::read(root.join("notes.txt")).await?, b"first");
+        let files = Backend::default().open(&root)?;
+        let layouts = std::sync::Arc::new(LayoutIndex::load(&root).await?);
+        let versions = VersionStore::load(Versions::default(), &root, files, layouts).await?;
+        assert!(versions.list("notes.txt").await.is_empty());
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upload_with_trailer() -> AnyResult<()> {
+        use crate::hashes::HashStore;
+        use common::hashing::hash_bytes;
+
+        let (addr, root) = serve("trailer").await?;
+        let body = b"hashed while it was sent";
+        let hash = hash_bytes(body);
+        let matching = upload(addr, "sent.txt", body, "", Some(&hash)).await?;
+        assert!(matches!(matching, FileHeader::Success(_)));
+        assert_eq!(tokio::fs::read(root.join("sent.txt")).await?, body);
+        // recorded, downloads don't hash the file again
+        let hashes = HashStore::new(&root);
+        assert_eq!(hashes.load("sent.txt").await?, Some(hash.clone()));
+        let mut connection = ProtocolConnection::new(TcpStream::connect(addr).await?).await?;
+        let download = FileHeader::Download {
+            name: "sent.txt".to_string(),
+            compression: None,
+            integrity: false,
+        };
+        connection
+            .send_header(&serde_json::to_string(&download)?)
+            .await?;
+        let served = read_header(&mut connection).await?;
+        // with the root of the tree built while the upload was received
+        let mut blocks = common::hashing::MerkleBuilder::new();
+        blocks.update(body);
+        let tree_root = blocks.finish().root();
+        assert!(matches!(
+            &served,
+            FileHeader::Upload { hash: served, root: Some(served_root), .. }
+                if *served == hash && *served_root == tree_root
+        ));
+
+        let wrong = hash_bytes(b"something else");
+        let mismatching = upload(addr, "wrong.txt", body, "", Some(&wrong)).await?;
+        assert!(matches!(mismatching, FileHeader::Error(_)));
+        assert!(!tokio::fs::try_exists(root.join("wrong.txt")).await?);
+        assert_eq!(hashes.load("wrong.txt").await?, None);
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ranges_hash_once() -> AnyResult<()> {
+        use crate::hashes::HashStore;
+        use common::hashing::hash_bytes;
+
+        // stored before hashes were recorded
+        let (addr, root) = serve("ranges").await?;
+        let body = b"0123456789".repeat(10);
+        tokio::fs::write(root.join("digits.txt"), &body).await?;
+        let hashes = HashStore::new(&root);
+        assert_eq!(hashes.load("digits.txt").await?, None);
+
+        for offset in [0, 50] {
+            let mut connection = ProtocolConnection::new(TcpStream::connect(addr).await?).await?;
+            let request = FileHeader::DownloadRange {
+                name: "digits.txt".to_string(),
+                offset,
+                length: 50,
+                compression: None,
+                integrity: false,
+            };
+            connection
+                .send_header(&serde_json::to_string(&request)?)
+                .await?;
+            let served = read_header(&mut connection).await?;
+            assert!(
+                matches!(served, FileHeader::Upload { size: 100, hash, .. } if hash == hash_bytes(&body))
+            );
+            let range = connection.read_payload(50).await?;
+            assert_eq!(range, &body[offset as usize..offset as usize + 50]);
+            // hashed for the first range, the second one is served with the recorded hash
+            assert_eq!(hashes.load("digits.txt").await?, Some(hash_bytes(&body)));
+        }
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,284 @@
+//! Connection caps, per-IP rate limiting and temporary bans
+
+use common::VeriflowError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Prune idle peers once the table grows past this
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Limits section of the server config
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)] // to only fill missing blanks
+pub struct Limits {
+    /// Max concurrent connections across all clients
+    pub max_connections: usize,
+    /// Max concurrent connections from a single IP
+    pub max_connections_per_ip: usize,
+    /// Token bucket refill rate (requests per second per IP)
+    pub requests_per_second: f64,
+    /// Token bucket capacity (burst of requests per IP)
+    pub burst: u32,
+    /// Protocol errors from one IP before it gets banned
+    pub ban_threshold: u32,
+    /// Ban duration in seconds
+    pub ban_seconds: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_connections: 256,
+            max_connections_per_ip: 16,
+            requests_per_second: 10.0,
+            burst: 20,
+            ban_threshold: 5,
+            ban_seconds: 300,
+        }
+    }
+}
+
+/// Reason a connection was turned away
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    Banned,
+    TooManyConnections,
+    TooManyConnectionsFromIp,
+    RateLimited,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Rejection::Banned => "temporarily banned",
+            Rejection::TooManyConnections => "server connection limit reached",
+            Rejection::TooManyConnectionsFromIp => "too many connections from this address",
+            Rejection::RateLimited => "rate limit exceeded",
+        };
+        write!(f, "{reason}")
+    }
+}
+
+// Book-keeping for a single source IP
+struct Peer {
+    active: usize,
+    tokens: f64,
+    last_refill: Instant,
+    failures: u32,
+    last_failure: Instant,
+    banned_until: Option<Instant>,
+}
+
+impl Peer {
+    fn new(limits: &Limits, now: Instant) -> Peer {
+        Peer {
+            active: 0,
+            tokens: limits.burst as f64,
+            last_refill: now,
+            failures: 0,
+            last_failure: now,
+            banned_until: None,
+        }
+    }
+
+    // top up the token bucket for the time elapsed since the last refill
+    fn refill(&mut self, limits: &Limits, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limits.requests_per_second).min(limits.burst as f64);
+        self.last_refill = now;
+    }
+
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+
+    // entry holds no state worth keeping
+    fn is_idle(&self, limits: &Limits, now: Instant) -> bool {
+        self.active == 0
+            && !self.is_banned(now)
+            && self.failures == 0
+            && self.tokens
+                + now.duration_since(self.last_refill).as_secs_f64() * limits.requests_per_second
+                >= limits.burst as f64
+    }
+}
+
+/// Shared limiter consulted for every accepted connection
+pub struct ConnectionLimiter {
+    limits: Limits,
+    active: AtomicUsize,
+    peers: Mutex<HashMap<IpAddr, Peer>>,
+}
+
+/// Held for the lifetime of a connection, frees its slot on drop
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: Limits) -> ConnectionLimiter {
+        ConnectionLimiter {
+            limits,
+            active: AtomicUsize::new(0),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks bans, connection caps and the token bucket for `ip`
+    ///
+    /// # Returns
+    /// A 'ConnectionPermit' to keep alive while the client is served, or the 'Rejection' reason
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Result<ConnectionPermit, Rejection> {
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+
+        if peers.len() > PRUNE_THRESHOLD {
+            peers.retain(|_, peer| !peer.is_idle(&self.limits, now));
+        }
+
+        let peer = peers
+            .entry(ip)
+            .or_insert_with(|| Peer::new(&self.limits, now));
+
+        if peer.is_banned(now) {
+            return Err(Rejection::Banned);
+        }
+        if self.active.load(Ordering::SeqCst) >= self.limits.max_connections {
+            return Err(Rejection::TooManyConnections);
+        }
+        if peer.active >= self.limits.max_connections_per_ip {
+            return Err(Rejection::TooManyConnectionsFromIp);
+        }
+
+        peer.refill(&self.limits, now);
+        if peer.tokens < 1.0 {
+            return Err(Rejection::RateLimited);
+        }
+        peer.tokens -= 1.0;
+
+        // the peers lock serialises acquisitions so the global counter can't overshoot
+        peer.active += 1;
+        self.active.fetch_add(1, Ordering::SeqCst);
+
+        Ok(ConnectionPermit {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+
+    /// Records a failed request from `ip`, banning it once `ban_threshold` is reached
+    ///
+    /// # Returns
+    /// 'true' if this failure triggered a ban
+    pub fn record_failure(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let ban = Duration::from_secs(self.limits.ban_seconds);
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        let peer = peers
+            .entry(ip)
+            .or_insert_with(|| Peer::new(&self.limits, now));
+
+        // failures older than a ban period are forgotten
+        if now.duration_since(peer.last_failure) > ban {
+            peer.failures = 0;
+        }
+        peer.failures += 1;
+        peer.last_failure = now;
+
+        if peer.failures >= self.limits.ban_threshold {
+            peer.failures = 0;
+            peer.banned_until = Some(now + ban);
+            return true;
+        }
+        false
+    }
+
+    // frees the slot held by a permit
+    fn release(&self, ip: IpAddr) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        let mut peers = self.peers.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(peer) = peers.get_mut(&ip) {
+            peer.active = peer.active.saturating_sub(1);
+        }
+    }
+}
+
+/// Whether an error was caused by a misbehaving client (counts towards a ban)
+///
+/// Timeouts don't, a slow network is no reason to lock a client out
+pub fn is_protocol_error(error: &VeriflowError) -> bool {
+    match error {
+        VeriflowError::JSON(_)
+        | VeriflowError::HeaderSizeExceeded(_)
+        | VeriflowError::PayloadSizeExceeded(_)
+        | VeriflowError::UnexpectedFileHeader(_) => true,
+        // path traversal / absolute path attempts
+        VeriflowError::Io(e) => e.kind() == io::ErrorKind::PermissionDenied,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_caps_rate_limit_and_ban() {
+        let limits = Limits {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            requests_per_second: 0.0, // no refill during the test
+            burst: 3,
+            ban_threshold: 2,
+            ban_seconds: 60,
+        };
+        let limiter = Arc::new(ConnectionLimiter::new(limits));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let c: IpAddr = "10.0.0.3".parse().unwrap();
+
+        // per ip cap
+        let first = limiter.try_acquire(a).unwrap();
+        let _second = limiter.try_acquire(a).unwrap();
+        assert_eq!(
+            limiter.try_acquire(a).err(),
+            Some(Rejection::TooManyConnectionsFromIp)
+        );
+
+        // global cap
+        let _third = limiter.try_acquire(b).unwrap();
+        assert_eq!(
+            limiter.try_acquire(c).err(),
+            Some(Rejection::TooManyConnections)
+        );
+
+        // slot is released on drop but the bucket of 'a' is now empty
+        drop(first);
+        assert!(limiter.try_acquire(a).is_ok());
+        assert_eq!(limiter.try_acquire(a).err(), Some(Rejection::RateLimited));
+
+        // ban after repeated failures
+        assert!(!limiter.record_failure(c));
+        assert!(limiter.record_failure(c));
+        assert_eq!(limiter.try_acquire(c).err(), Some(Rejection::Banned));
+
+        // only a misbehaving client counts towards a ban, a slow one doesn't
+        assert!(is_protocol_error(&VeriflowError::HeaderSizeExceeded(0)));
+        assert!(!is_protocol_error(&VeriflowError::Timeout(
+            "Reading header".to_string()
+        )));
+    }
+}
@@ -1,9 +1,23 @@
 use std::path::PathBuf;
 
+use clap::Parser;
+use cli::{Args, Commands};
+use common::protocol::{Buffers, Timeouts};
+use server::access::{self, Access, AccessList};
+use server::audit::{self, Audit};
+use server::backend::Backend;
+use server::compression::Compression;
+use server::hashes::Hashing;
+use server::{limits::Limits, policy::Policy, quota::Quota, storage::Storage};
 use server::{server::Listener, Config, Directory, Network};
+use server::{trash::Trash, versions::Versions};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+mod cli;
+
 #[tokio::main]
 async fn main() -> common::Result<()> {
+    let args = Args::parse();
     const FILE_PATH: &str = "../Veriflow/resources/";
     const CONFIG_PATH: &str = "./config.toml";
     let config_exists = tokio::fs::try_exists(CONFIG_PATH).await?;
@@ -20,6 +34,19 @@ async fn main() -> common::Result<()> {
             directory: (Directory {
                 path: PathBuf::from(FILE_PATH),
             }),
+            limits: Limits::default(),
+            access: Access::default(),
+            timeouts: Timeouts::default(),
+            buffers: Buffers::default(),
+            quota: Quota::default(),
+            policy: Policy::default(),
+            audit: Audit::default(),
+            trash: Trash::default(),
+            versions: Versions::default(),
+            storage: Storage::default(),
+            backend: Backend::default(),
+            compression: Compression::default(),
+            hashing: Hashing::default(),
         };
         let _ = tokio::fs::File::create(CONFIG_PATH).await?;
         let mut config_file = tokio::fs::OpenOptions::new()
@@ -39,9 +66,41 @@ async fn main() -> common::Result<()> {
     if !path_exists {
         tokio::fs::create_dir_all(&config_struct.directory.path).await?;
     }
+
+    if let Some(Commands::VerifyAudit { path }) = args.command {
+        let log_path =
+            path.unwrap_or_else(|| config_struct.audit.log_path(&config_struct.directory.path));
+        let verified = audit::verify(&log_path).await?;
+        println!(
+            "Audit log {:?} is intact: {} entries, head hash {}",
+            log_path, verified.entries, verified.head_hash
+        );
+        return Ok(());
+    }
+
     tracing_subscriber::fmt::init();
     let mut listener =
         Listener::new(&config_struct.network.ip, &config_struct.network.port).await?;
+    listener.set_limits(config_struct.limits);
+    listener.set_timeouts(config_struct.timeouts);
+    listener.set_buffers(config_struct.buffers);
+    listener.set_quota(config_struct.quota);
+    listener.set_policy(config_struct.policy);
+    listener.set_audit(config_struct.audit);
+    listener.set_trash(config_struct.trash);
+    listener.set_versions(config_struct.versions);
+    listener.set_storage(config_struct.storage);
+    listener.set_backend(config_struct.backend);
+    listener.set_compression(config_struct.compression);
+    listener.set_hashing(config_struct.hashing);
+
+    // IP allow/deny rules, reloaded whenever the config file changes
+    let access_control = listener.access_control();
+    access_control.reload(AccessList::from_config(&config_struct.access)?);
+    tokio::spawn(access::watch_config(
+        PathBuf::from(CONFIG_PATH),
+        access_control,
+    ));
     listener.listen(config_struct.directory.path).await?;
     Ok(())
 }
@@ -0,0 +1,235 @@
+//! Files stored zstd compressed at rest
+//!
+//! With `storage.compress` a verified upload is rewritten as a packed file: a small header with
+//! the original size and SHA256 followed by the zstd compressed bytes. Downloads decompress on
+//! the fly, so clients only ever see (and verify) the original content. Files that don't get
+//! smaller, or are of an already compressed type, are kept as they are. Packed files are
+//! listed in the layout index, see 'layouts'.
+
+use crate::backend::{FileStore, Reader};
+use crate::META_DIR;
+use common::protocol::ProtocolConnection;
+use common::VeriflowError;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// First bytes of a packed file, followed by the header length (u32) and the header
+const PACKED_MAGIC: &[u8] = b"VFPACKED1\n";
+// Packed files being written, inside the metadata directory
+const PACKING_DIR: &str = "packing";
+// Longest header accepted, it only holds a size and a hash
+const MAX_HEADER_LEN: usize = 1024;
+// Bytes read at a time while (de)compressing
+const BLOCK_SIZE: usize = 64 * 1024;
+
+// names packed files being written
+static NEXT_PACKING: AtomicU64 = AtomicU64::new(0);
+
+/// Original size and SHA256 of a packed file
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PackedHeader {
+    pub size: u64,
+    pub hash: String,
+}
+
+/// Opens the packed file under `key`, which the layout index lists as one
+///
+/// # Returns
+/// The header and a reader positioned at the compressed bytes
+pub async fn open(files: &dyn FileStore, key: &str) -> common::Result<(PackedHeader, Reader)> {
+    let mut file = files.open(key).await?;
+    let mut magic = vec![0u8; PACKED_MAGIC.len() + 4];
+    file.read_exact(&mut magic).await?;
+    if &magic[..PACKED_MAGIC.len()] != PACKED_MAGIC {
+        return Err(corrupted());
+    }
+
+    let mut len_bytes = [0u8; 4];
+    len_bytes.copy_from_slice(&magic[PACKED_MAGIC.len()..]);
+    let header_len = u32::from_be_bytes(len_bytes) as usize;
+    if header_len > MAX_HEADER_LEN {
+        return Err(VeriflowError::HeaderSizeExceeded(header_len));
+    }
+    let mut header = vec![0u8; header_len];
+    file.read_exact(&mut header).await?;
+    Ok((serde_json::from_slice(&header)?, file))
+}
+
+/// Header of the packed file under `key`
+pub async fn read_header(files: &dyn FileStore, key: &str) -> common::Result<PackedHeader> {
+    Ok(open(files, key).await?.0)
+}
+
+/// Compresses the file under `key` in place, keeping it as is if it doesn't get smaller
+///
+/// # Arguments
+/// * 'header' - size and hash of the file, already verified
+///
+/// # Returns
+/// Whether the file was packed
+pub async fn pack(
+    files: &dyn FileStore,
+    key: &str,
+    header: &PackedHeader,
+    level: i32,
+) -> common::Result<bool> {
+    let id = NEXT_PACKING.fetch_add(1, Ordering::Relaxed);
+    let staged = format!("{META_DIR}/{PACKING_DIR}/{}-{id}", std::process::id());
+    let packed = write_packed(files, key, &staged, header, level).await;
+
+    match packed {
+        Ok(stored) if stored < header.size => {
+            files.rename(&staged, key).await?;
+            Ok(true)
+        }
+        Ok(_) => {
+            files.delete(&staged).await?;
+            Ok(false)
+        }
+        Err(e) => {
+            let _ = files.delete(&staged).await;
+            Err(e)
+        }
+    }
+}
+
+// Writes the packed form of `key` to `staged`, returns its size
+async fn write_packed(
+    files: &dyn FileStore,
+    key: &str,
+    staged: &str,
+    header: &PackedHeader,
+    level: i32,
+) -> common::Result<u64> {
+    let header_json = serde_json::to_vec(header)?;
+    let mut prefix = PACKED_MAGIC.to_vec();
+    prefix.extend_from_slice(&(header_json.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(&header_json);
+
+    let mut input = files.open(key).await?;
+    let mut output = files.create(staged).await?;
+    output.write_all(&prefix).await?;
+    let mut stored = prefix.len() as u64;
+
+    // the encoder compresses into a buffer that is drained after every block
+    let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), level)?;
+    let mut buffer = vec![0u8; BLOCK_SIZE];
+    loop {
+        let bytes_read = input.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        encoder.write_all(&buffer[..bytes_read])?;
+        let compressed = std::mem::take(encoder.get_mut());
+        output.write_all(&compressed).await?;
+        stored += compressed.len() as u64;
+    }
+    let compressed = encoder.finish()?;
+    output.write_all(&compressed).await?;
+    stored += compressed.len() as u64;
+    output.shutdown().await?;
+    Ok(stored)
+}
+
+/// Streams `length` original bytes of a packed file from `offset` on
+///
+/// # Arguments
+/// * 'body' - reader positioned at the compressed bytes, as returned by 'open'
+/// * 'size' - original size of the file
+pub async fn send_unpacked(
+    connection: &mut ProtocolConnection,
+    mut body: Reader,
+    size: u64,
+    offset: u64,
+    length: u64,
+) -> common::Result<()> {
+    let end = offset + length;
+    let mut decoder = zstd::stream::write::Decoder::new(Vec::new())?;
+    let mut buffer = vec![0u8; BLOCK_SIZE];
+    // original bytes decompressed so far
+    let mut position = 0;
+    while position < end {
+        let bytes_read = body.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            // whatever the decoder still holds
+            decoder.flush()?;
+        } else {
+            decoder.write_all(&buffer[..bytes_read])?;
+        }
+        let block = std::mem::take(decoder.get_mut());
+        let block_start = position;
+        position += block.len() as u64;
+        if position > size {
+            return Err(corrupted());
+        }
+
+        // the part of the block inside the range, bytes before the offset are skipped
+        let from = offset.saturating_sub(block_start).min(block.len() as u64) as usize;
+        let to = end.saturating_sub(block_start).min(block.len() as u64) as usize;
+        if from < to {
+            connection
+                .write_file_to_stream(&mut &block[from..to], (to - from) as u64)
+                .await?;
+        }
+        if bytes_read == 0 {
+            break;
+        }
+    }
+    // the peer reads exactly 'length' bytes
+    if position < end {
+        return Err(corrupted());
+    }
+    Ok(())
+}
+
+fn corrupted() -> VeriflowError {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "Packed file doesn't match its header",
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryStore;
+    use common::hashing;
+
+    #[tokio::test]
+    async fn test_pack_in_place() -> common::Result<()> {
+        let files = MemoryStore::default();
+        let text = b"2026-10-19 INFO request served in 3ms\n".repeat(5_000);
+        for (key, content) in [("app.log", &text[..]), ("tiny.txt", &b"abc"[..])] {
+            let mut file = files.create(key).await?;
+            file.write_all(content).await?;
+            file.shutdown().await?;
+        }
+
+        // text shrinks and is packed, the header keeps the original size and hash
+        let header = PackedHeader {
+            size: text.len() as u64,
+            hash: hashing::hash_bytes(&text),
+        };
+        assert!(pack(&files, "app.log", &header, 3).await?);
+        assert!(files.stat("app.log").await?.size < header.size / 10);
+        assert_eq!(read_header(&files, "app.log").await?, header);
+
+        let (_, mut body) = open(&files, "app.log").await?;
+        let mut compressed = Vec::new();
+        body.read_to_end(&mut compressed).await?;
+        assert_eq!(zstd::decode_all(&compressed[..])?, text);
+
+        // a file that would grow stays as it is
+        let tiny = PackedHeader {
+            size: 3,
+            hash: hashing::hash_bytes(b"abc"),
+        };
+        assert!(!pack(&files, "tiny.txt", &tiny, 3).await?);
+        assert!(read_header(&files, "tiny.txt").await.is_err());
+        assert!(files.list(META_DIR).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+//! Parallel uploads
+//!
+//! A large upload can be split into byte ranges sent at once over several connections. The
+//! connection announcing it ('ParallelUpload') waits while the ranges arrive on the others
+//! ('UploadRange'), each written at its offset into a staging file in `.veriflow/parallel`.
+//! Once every byte arrived the whole file is verified against the announced hash and stored.
+//! Uploads only live as long as the announcing connection, leftovers are removed at startup.
+
+use crate::META_DIR;
+use common::hashing::{self, HashAlgorithm};
+use common::VeriflowError;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::Notify;
+
+// Staging directory inside the metadata directory
+const PARALLEL_DIR: &str = "parallel";
+
+/// Parallel uploads in progress
+pub struct ParallelUploads {
+    dir: PathBuf,
+    uploads: Mutex<HashMap<String, Arc<ParallelUpload>>>,
+    // names staging files
+    next_id: AtomicU64,
+}
+
+/// A parallel upload waiting for its ranges
+pub struct ParallelUpload {
+    pub id: String,
+    /// Client that announced the upload, only it may send ranges
+    pub user: String,
+    pub key: String,
+    pub size: u64,
+    /// Algorithm the whole file is hashed with once every range arrived
+    pub algorithm: HashAlgorithm,
+    pub staging: PathBuf,
+    state: Mutex<RangeState>,
+    progress: Notify,
+}
+
+#[derive(Default)]
+struct RangeState {
+    // ranges being received or received (start, end)
+    claimed: Vec<(u64, u64)>,
+    received: u64,
+}
+
+/// Removes an upload and its staging file once dropped
+pub struct UploadGuard<'a> {
+    uploads: &'a ParallelUploads,
+    pub upload: Arc<ParallelUpload>,
+}
+
+impl ParallelUploads {
+    pub fn new(root: &Path) -> ParallelUploads {
+        ParallelUploads {
+            dir: root.join(META_DIR).join(PARALLEL_DIR),
+            uploads: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Arc<ParallelUpload>>> {
+        self.uploads.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Removes staging files left behind by a previous run
+    pub async fn clear(&self) -> common::Result<()> {
+        if tokio::fs::try_exists(&self.dir).await? {
+            tokio::fs::remove_dir_all(&self.dir).await?;
+        }
+        Ok(())
+    }
+
+    /// Starts an upload of `size` bytes to `key`, with a staging file of that size
+    pub async fn begin(
+        &self,
+        user: &str,
+        key: &str,
+        size: u64,
+        algorithm: HashAlgorithm,
+    ) -> common::Result<UploadGuard<'_>> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let count = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let staging = self.dir.join(format!("{}-{count}", std::process::id()));
+
+        // ranges name their upload by id, make it hard to guess for other clients
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos());
+        let seed = format!("{}:{count}:{nanos}:{user}:{key}", std::process::id());
+        let id = hashing::hash_bytes(seed.as_bytes())[..32].to_string();
+
+        let file = tokio::fs::File::create(&staging).await?;
+        file.set_len(size).await?;
+
+        let upload = Arc::new(ParallelUpload {
+            id: id.clone(),
+            user: user.to_string(),
+            key: key.to_string(),
+            size,
+            algorithm,
+            staging,
+            state: Mutex::new(RangeState::default()),
+            progress: Notify::new(),
+        });
+        self.lock().insert(id, Arc::clone(&upload));
+        Ok(UploadGuard {
+            uploads: self,
+            upload,
+        })
+    }
+
+    /// The upload with `id`, if it is still in progress
+    pub fn get(&self, id: &str) -> Option<Arc<ParallelUpload>> {
+        self.lock().get(id).cloned()
+    }
+}
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        self.uploads.lock().remove(&self.upload.id);
+        // gone already if the file was stored
+        let _ = std::fs::remove_file(&self.upload.staging);
+    }
+}
+
+impl ParallelUpload {
+    fn lock(&self) -> std::sync::MutexGuard<'_, RangeState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Reserves the range of `length` bytes at `offset` for a connection about to send it
+    pub fn claim(&self, offset: u64, length: u64) -> common::Result<()> {
+        let end = offset
+            .checked_add(length)
+            .filter(|end| length > 0 && *end <= self.size)
+            .ok_or_else(|| {
+                VeriflowError::UnexpectedFileHeader(format!(
+                    "Range {offset}+{length} is outside of the {} byte upload",
+                    self.size
+                ))
+            })?;
+        let mut state = self.lock();
+        if state
+            .claimed
+            .iter()
+            .any(|(start, stop)| offset < *stop && *start < end)
+        {
+            return Err(VeriflowError::UnexpectedFileHeader(format!(
+                "Range {offset}+{length} overlaps a range already sent"
+            )));
+        }
+        state.claimed.push((offset, end));
+        Ok(())
+    }
+
+    /// Gives a claimed range back after it failed, it may be sent again
+    pub fn release(&self, offset: u64) {
+        self.lock().claimed.retain(|(start, _)| *start != offset);
+    }
+
+    /// Records a claimed range as received
+    pub fn complete(&self, length: u64) {
+        self.lock().received += length;
+        self.progress.notify_waiters();
+    }
+
+    /// Whether every byte of the upload arrived
+    pub fn is_complete(&self) -> bool {
+        self.lock().received >= self.size
+    }
+
+    /// Waits until every byte of the upload arrived
+    pub async fn wait(&self) {
+        loop {
+            // registered before checking, a completion in between isn't missed
+            let progress = self.progress.notified();
+            if self.is_complete() {
+                return;
+            }
+            progress.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parallel_ranges() -> common::Result<()> {
+        let root = std::env::temp_dir().join(format!("veriflow-parallel-{}", std::process::id()));
+        let uploads = ParallelUploads::new(&root);
+        let guard = uploads
+            .begin("10.0.0.1", "big.iso", 100, HashAlgorithm::Sha256)
+            .await?;
+        let upload = Arc::clone(&guard.upload);
+        assert_eq!(tokio::fs::metadata(&upload.staging).await?.len(), 100);
+        assert!(uploads.get(&upload.id).is_some());
+
+        // ranges must be inside the file and sent once
+        upload.claim(0, 60)?;
+        assert!(upload.claim(50, 20).is_err());
+        assert!(upload.claim(60, 41).is_err());
+        assert!(upload.claim(60, 0).is_err());
+        upload.claim(60, 40)?;
+
+        // a failed range can be sent again
+        upload.release(60);
+        upload.claim(60, 40)?;
+
+        let waiting = tokio::spawn({
+            let upload = Arc::clone(&upload);
+            async move { upload.wait().await }
+        });
+        upload.complete(60);
+        assert!(!upload.is_complete());
+        upload.complete(40);
+        waiting.await.map_err(std::io::Error::other)?;
+
+        // the upload and its staging file go with the guard
+        drop(guard);
+        assert!(uploads.get(&upload.id).is_none());
+        assert!(!tokio::fs::try_exists(&upload.staging).await?);
+        uploads.clear().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+//! Upload policy rules (size, names, extensions and content types)
+//!
+//! Name and size rules are checked against the upload header before the client gets the
+//! go-ahead. Content type rules need the first bytes of the file, so the client is asked
+//! for a short preview and has to wait for a verdict before sending the rest.
+
+use common::VeriflowError;
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path};
+
+/// Bytes of the file body used to detect its content type
+pub const PREVIEW_SIZE: u64 = 8192;
+
+/// Policy section of the server config
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)] // to only fill missing blanks
+pub struct Policy {
+    /// Largest accepted file, unlimited when not set
+    pub max_file_bytes: Option<u64>,
+    /// Accepted file extensions (e.g. "csv"), any extension when empty
+    pub allowed_extensions: Vec<String>,
+    /// Rejected file extensions
+    pub blocked_extensions: Vec<String>,
+    /// Accepted content types detected from the file itself (e.g. "image/*"), any type when empty
+    pub allowed_mime_types: Vec<String>,
+    /// Rejected content types detected from the file itself
+    pub blocked_mime_types: Vec<String>,
+    /// Max number of directories and file name in the upload path
+    pub max_path_depth: usize,
+    /// Max length of every name in the upload path
+    pub max_name_length: usize,
+    /// Names that may not be used, compared without extension and case
+    pub reserved_names: Vec<String>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        // device names Windows refuses to create files for
+        let mut reserved_names: Vec<String> = ["CON", "PRN", "AUX", "NUL"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        for i in 1..=9 {
+            reserved_names.push(format!("COM{i}"));
+            reserved_names.push(format!("LPT{i}"));
+        }
+
+        Self {
+            max_file_bytes: None,
+            allowed_extensions: Vec::new(),
+            blocked_extensions: Vec::new(),
+            allowed_mime_types: Vec::new(),
+            blocked_mime_types: Vec::new(),
+            max_path_depth: 16,
+            max_name_length: 255,
+            reserved_names,
+        }
+    }
+}
+
+// Wrap a rejection reason
+fn reject(reason: String) -> VeriflowError {
+    VeriflowError::PolicyViolation(reason)
+}
+
+// case-insensitive match of an extension against a list (leading dots are ignored)
+fn matches_extension(list: &[String], extension: &str) -> bool {
+    list.iter().any(|entry| {
+        entry
+            .trim_start_matches('.')
+            .eq_ignore_ascii_case(extension)
+    })
+}
+
+// content type match supporting "type/*" wildcards
+fn matches_mime(list: &[String], mime: &str) -> bool {
+    list.iter().any(|pattern| match pattern.strip_suffix("/*") {
+        Some(prefix) => mime
+            .split('/')
+            .next()
+            .is_some_and(|kind| kind.eq_ignore_ascii_case(prefix)),
+        None => pattern.eq_ignore_ascii_case(mime),
+    })
+}
+
+// valid UTF-8, allowing the preview to end mid-character
+fn is_text(preview: &[u8]) -> bool {
+    match std::str::from_utf8(preview) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+/// Detects the content type of the first bytes of a file
+pub fn detect_mime(preview: &[u8]) -> &'static str {
+    match infer::get(preview) {
+        Some(kind) => kind.mime_type(),
+        // no magic number, tell text from binary
+        None if is_text(preview) => "text/plain",
+        None => "application/octet-stream",
+    }
+}
+
+impl Policy {
+    /// Checks the path and size of an upload header
+    pub fn check_header(&self, path: &str, size: u64) -> common::Result<()> {
+        if let Some(max) = self.max_file_bytes {
+            if size > max {
+                return Err(reject(format!(
+                    "File size of {size} bytes exceeds the limit of {max} bytes"
+                )));
+            }
+        }
+
+        let names: Vec<&str> = Path::new(path)
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect();
+
+        if names.len() > self.max_path_depth {
+            return Err(reject(format!(
+                "Path is {} levels deep, the limit is {}",
+                names.len(),
+                self.max_path_depth
+            )));
+        }
+
+        for name in &names {
+            if name.chars().count() > self.max_name_length {
+                return Err(reject(format!(
+                    "Name \"{name}\" is longer than {} characters",
+                    self.max_name_length
+                )));
+            }
+            let stem = name.split('.').next().unwrap_or(name).trim_end();
+            if self
+                .reserved_names
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+            {
+                return Err(reject(format!("\"{name}\" is a reserved name")));
+            }
+        }
+
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("");
+        if matches_extension(&self.blocked_extensions, extension) {
+            return Err(reject(format!(
+                "Files of type \".{extension}\" are blocked"
+            )));
+        }
+        if !self.allowed_extensions.is_empty()
+            && !matches_extension(&self.allowed_extensions, extension)
+        {
+            return Err(reject(format!(
+                "Only {} files are allowed",
+                self.allowed_extensions.join(", ")
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Whether uploads have to send a preview for content type detection
+    pub fn needs_preview(&self) -> bool {
+        !self.allowed_mime_types.is_empty() || !self.blocked_mime_types.is_empty()
+    }
+
+    /// Checks the content type detected from the first bytes of an upload
+    pub fn check_content(&self, preview: &[u8]) -> common::Result<()> {
+        let mime = detect_mime(preview);
+        if matches_mime(&self.blocked_mime_types, mime) {
+            return Err(reject(format!("Content of type {mime} is blocked")));
+        }
+        if !self.allowed_mime_types.is_empty() && !matches_mime(&self.allowed_mime_types, mime) {
+            return Err(reject(format!("Content of type {mime} is not allowed")));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_policy() {
+        let policy = Policy {
+            max_file_bytes: Some(1000),
+            blocked_extensions: vec![".exe".to_string()],
+            allowed_mime_types: vec!["image/*".to_string(), "text/plain".to_string()],
+            max_path_depth: 2,
+            max_name_length: 12,
+            ..Policy::default()
+        };
+
+        assert!(policy.check_header("photo.png", 1000).is_ok());
+        assert!(policy.check_header("dir/notes.txt", 10).is_ok());
+        assert!(policy.check_header("photo.png", 1001).is_err());
+        assert!(policy.check_header("setup.EXE", 10).is_err());
+        assert!(policy.check_header("a/b/c.txt", 10).is_err());
+        assert!(policy.check_header("much_too_long_name.txt", 10).is_err());
+        assert!(policy.check_header("con.txt", 10).is_err());
+
+        // content is detected from magic numbers, not from the name
+        let png = [
+            0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0x0D,
+        ];
+        assert!(policy.needs_preview());
+        assert!(policy.check_content(&png).is_ok());
+        assert!(policy.check_content(b"just some text").is_ok());
+        assert!(policy
+            .check_content(b"MZ\x90\x00\x03\x00\x00\x00\x04\x00")
+            .is_err());
+    }
+}
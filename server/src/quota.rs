@@ -0,0 +1,311 @@
+//! Per-user disk quotas and the global free-space reserve
+//!
+//! Users are identified by their IP address until authentication exists.
+//! Stored bytes are tracked per owner in `.veriflow/owners.json`, bytes of uploads
+//! still in flight are reserved up front so concurrent uploads can't race past a limit.
+
+use crate::META_DIR;
+use common::{Usage, VeriflowError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Ownership index inside the metadata directory
+const OWNERS_FILE: &str = "owners.json";
+
+/// Quota section of the server config
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)] // to only fill missing blanks
+pub struct Quota {
+    /// Bytes each user may store, unlimited when not set
+    pub default_user_bytes: Option<u64>,
+    /// Per-user overrides keyed by user (client IP address)
+    pub users: HashMap<String, u64>,
+    /// Free disk space (bytes) uploads may never eat into
+    pub min_free_bytes: u64,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            default_user_bytes: None,
+            users: HashMap::new(),
+            min_free_bytes: 1024 * 1024 * 1024, // 1gb
+        }
+    }
+}
+
+// Who stored a file and how big it is
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Owner {
+    user: String,
+    size: u64,
+}
+
+#[derive(Default)]
+struct QuotaState {
+    // stored files keyed by their path relative to the resource directory
+    owners: HashMap<String, Owner>,
+    // bytes reserved by uploads in progress, per user and in total
+    in_flight: HashMap<String, u64>,
+    in_flight_total: u64,
+}
+
+impl QuotaState {
+    fn used_by(&self, user: &str) -> u64 {
+        self.owners
+            .values()
+            .filter(|owner| owner.user == user)
+            .map(|owner| owner.size)
+            .sum()
+    }
+}
+
+/// Tracks stored and in-flight bytes for every user
+pub struct QuotaManager {
+    config: Quota,
+    root: PathBuf,
+    state: Mutex<QuotaState>,
+    // orders writes of the ownership index
+    persist_lock: tokio::sync::Mutex<()>,
+}
+
+/// Space reserved for an upload in progress, released on drop unless committed
+pub struct Reservation<'a> {
+    manager: &'a QuotaManager,
+    user: String,
+    path: String,
+    size: u64,
+}
+
+impl QuotaManager {
+    /// Loads the ownership index of the resource directory `root`
+    pub async fn load(config: Quota, root: &Path) -> common::Result<QuotaManager> {
+        let index = root.join(META_DIR).join(OWNERS_FILE);
+        let owners = if tokio::fs::try_exists(&index).await? {
+            serde_json::from_slice(&tokio::fs::read(&index).await?)?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(QuotaManager {
+            config,
+            root: root.to_path_buf(),
+            state: Mutex::new(QuotaState {
+                owners,
+                ..QuotaState::default()
+            }),
+            persist_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    // quota of a user, None if unlimited
+    fn limit_for(&self, user: &str) -> Option<u64> {
+        self.config
+            .users
+            .get(user)
+            .copied()
+            .or(self.config.default_user_bytes)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, QuotaState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Reserves `size` bytes for `user` uploading to `path`
+    ///
+    /// # Returns
+    /// A 'Reservation' to commit once the upload succeeded, or 'QuotaExceeded' with the reason
+    pub fn reserve(&self, user: &str, path: &str, size: u64) -> common::Result<Reservation<'_>> {
+        let available = fs4::available_space(&self.root)?;
+        let mut state = self.lock();
+
+        if let Some(limit) = self.limit_for(user) {
+            // overwriting your own file frees its current size
+            let replaced = state
+                .owners
+                .get(path)
+                .filter(|owner| owner.user == user)
+                .map_or(0, |owner| owner.size);
+            let in_flight = state.in_flight.get(user).copied().unwrap_or(0);
+            let used = state.used_by(user) - replaced + in_flight;
+
+            if used.saturating_add(size) > limit {
+                return Err(VeriflowError::QuotaExceeded(format!(
+                    "{size} bytes requested but {user} already uses {used} of {limit} bytes"
+                )));
+            }
+        }
+
+        // bytes already written by in-flight uploads are counted twice, erring on the safe side
+        let required = state
+            .in_flight_total
+            .saturating_add(size)
+            .saturating_add(self.config.min_free_bytes);
+        if required > available {
+            return Err(VeriflowError::QuotaExceeded(format!(
+                "{size} bytes requested but the server is running out of disk space"
+            )));
+        }
+
+        *state.in_flight.entry(user.to_string()).or_insert(0) += size;
+        state.in_flight_total += size;
+
+        Ok(Reservation {
+            manager: self,
+            user: user.to_string(),
+            path: path.to_string(),
+            size,
+        })
+    }
+
+    /// Forgets `path` and everything below it (deleted files)
+    pub async fn remove(&self, path: &str) -> common::Result<()> {
+        {
+            let mut state = self.lock();
+            if path.is_empty() {
+                // the whole resource directory
+                state.owners.clear();
+            } else {
+                let dir_prefix = format!("{}/", path.trim_end_matches('/'));
+                state
+                    .owners
+                    .retain(|key, _| key != path && !key.starts_with(&dir_prefix));
+            }
+        }
+        self.persist().await
+    }
+
+    /// Moves the records of `from` and everything below it to `to` (trashed or restored files)
+    pub async fn rename(&self, from: &str, to: &str) -> common::Result<()> {
+        {
+            let mut state = self.lock();
+            let dir_prefix = format!("{}/", from.trim_end_matches('/'));
+            let moved: Vec<String> = state
+                .owners
+                .keys()
+                .filter(|key| key.as_str() == from || key.starts_with(&dir_prefix))
+                .cloned()
+                .collect();
+            for key in moved {
+                if let Some(owner) = state.owners.remove(&key) {
+                    let new_key = format!("{to}{}", &key[from.len()..]);
+                    state.owners.insert(new_key, owner);
+                }
+            }
+        }
+        self.persist().await
+    }
+
+    /// Storage usage of `user`
+    pub fn usage(&self, user: &str) -> common::Result<Usage> {
+        let available = fs4::available_space(&self.root)?;
+        let state = self.lock();
+        let used = state.used_by(user);
+        let quota = self.limit_for(user);
+
+        // the smaller of the remaining quota and the free disk space above the reserve
+        let disk_left = available
+            .saturating_sub(state.in_flight_total)
+            .saturating_sub(self.config.min_free_bytes);
+        let available = match quota {
+            Some(limit) => limit.saturating_sub(used).min(disk_left),
+            None => disk_left,
+        };
+
+        Ok(Usage {
+            user: user.to_string(),
+            used,
+            quota,
+            available,
+        })
+    }
+
+    // write the ownership index to disk
+    async fn persist(&self) -> common::Result<()> {
+        let _guard = self.persist_lock.lock().await;
+        let snapshot = serde_json::to_vec_pretty(&self.lock().owners)?;
+
+        let meta_dir = self.root.join(META_DIR);
+        tokio::fs::create_dir_all(&meta_dir).await?;
+        // write then rename so a crash never leaves a half written index
+        let tmp = meta_dir.join(format!("{OWNERS_FILE}.tmp"));
+        tokio::fs::write(&tmp, snapshot).await?;
+        tokio::fs::rename(&tmp, meta_dir.join(OWNERS_FILE)).await?;
+        Ok(())
+    }
+}
+
+impl Reservation<'_> {
+    /// Records the uploaded file as owned by the user
+    pub async fn commit(self) -> common::Result<()> {
+        {
+            let mut state = self.manager.lock();
+            state.owners.insert(
+                self.path.clone(),
+                Owner {
+                    user: self.user.clone(),
+                    size: self.size,
+                },
+            );
+        }
+        let manager = self.manager;
+        // drop releases the in-flight bytes
+        drop(self);
+        manager.persist().await
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut state = self.manager.lock();
+        if let Some(in_flight) = state.in_flight.get_mut(&self.user) {
+            *in_flight = in_flight.saturating_sub(self.size);
+            if *in_flight == 0 {
+                state.in_flight.remove(&self.user);
+            }
+        }
+        state.in_flight_total = state.in_flight_total.saturating_sub(self.size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_quota_reservations() -> common::Result<()> {
+        let root = std::env::temp_dir().join(format!("veriflow-quota-{}", std::process::id()));
+        tokio::fs::create_dir_all(&root).await?;
+
+        let config = Quota {
+            default_user_bytes: Some(100),
+            users: HashMap::from([("10.0.0.2".to_string(), 500)]),
+            min_free_bytes: 0,
+        };
+        let quota = QuotaManager::load(config.clone(), &root).await?;
+
+        // concurrent reservations count against the quota
+        let first = quota.reserve("10.0.0.1", "a.bin", 60)?;
+        assert!(matches!(
+            quota.reserve("10.0.0.1", "b.bin", 60),
+            Err(VeriflowError::QuotaExceeded(_))
+        ));
+        first.commit().await?;
+        assert_eq!(quota.usage("10.0.0.1")?.used, 60);
+
+        // overwriting your own file replaces its size, overrides are per user
+        quota.reserve("10.0.0.1", "a.bin", 90)?.commit().await?;
+        assert!(quota.reserve("10.0.0.2", "c.bin", 400).is_ok());
+
+        // the index survives a restart, deletes free the space
+        let reloaded = QuotaManager::load(config, &root).await?;
+        assert_eq!(reloaded.usage("10.0.0.1")?.used, 90);
+        reloaded.remove("a.bin").await?;
+        assert_eq!(reloaded.usage("10.0.0.1")?.used, 0);
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+}
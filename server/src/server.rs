@@ -1,16 +1,155 @@
-use common::{hashing, protocol::ProtocolConnection, FileHeader, VeriflowError};
+use crate::access::AccessControl;
+use crate::audit::{Audit, AuditEntry, AuditLog};
+use crate::backend::{self, Backend, FileStore};
+use crate::chunks::{self, ChunkStore, Recipe};
+use crate::compression::Compression;
+use crate::hashes::{HashStore, Hashing};
+use crate::layouts::{Layout, LayoutIndex};
+use crate::limits::{self, ConnectionLimiter, Limits, Rejection};
+use crate::packed::{self, PackedHeader};
+use crate::parallel::ParallelUploads;
+use crate::policy::{Policy, PREVIEW_SIZE};
+use crate::quota::{Quota, QuotaManager};
+use crate::signatures::SignatureStore;
+use crate::storage::{ObjectStore, Storage};
+use crate::trash::{self, Trash, TrashBin};
+use crate::trees::TreeStore;
+use crate::versions::{VersionStore, Versions};
+use crate::META_DIR;
+use common::chunking::Chunk;
+use common::hashing::{self, Checksum, HashAlgorithm};
+use common::protocol::{Buffers, ProtocolConnection, Timeouts};
+use common::{FileHeader, ListEntry, Signature, VeriflowError};
+use std::cmp;
 use std::io;
 use std::path;
 use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
-use tokio::fs::metadata;
 use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{error, info};
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
+
+// How often expired trash entries and versions are purged
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Rejected connections told why at once, any more are just closed
+const MAX_REJECTIONS: usize = 64;
+
 ///This struct represents the listener that will handle connections
 pub struct Listener {
     //Struct definition
     listener: TcpListener,
+    limiter: Arc<ConnectionLimiter>,
+    rejections: Arc<Semaphore>,
+    access: Arc<AccessControl>,
+    timeouts: Timeouts,
+    buffers: Buffers,
+    quota: Quota,
+    policy: Policy,
+    audit: Audit,
+    trash: Trash,
+    versions: Versions,
+    storage: Storage,
+    backend: Backend,
+    compression: Compression,
+    hashing: Hashing,
+}
+
+///State shared by every client task
+pub struct Context {
+    ///Resource directory served to clients
+    pub root: PathBuf,
+    ///Where the served files are kept, addressed by key
+    pub files: Arc<dyn FileStore>,
+    pub quota: QuotaManager,
+    pub policy: Policy,
+    pub audit: Option<AuditLog>,
+    pub signatures: SignatureStore,
+    pub trees: TreeStore,
+    pub trash: TrashBin,
+    pub versions: VersionStore,
+    pub storage: Storage,
+    pub objects: ObjectStore,
+    pub chunks: ChunkStore,
+    pub compression: Compression,
+    pub hashing: Hashing,
+    pub hashes: HashStore,
+    pub layouts: Arc<LayoutIndex>,
+    pub parallel: ParallelUploads,
+    ///Names the staging copies of restored versions
+    pub restores: AtomicU64,
+}
+
+///Fields of an 'Upload' header
+pub struct Upload {
+    pub size: u64,
+    pub hash: String,
+    pub signature: Option<Signature>,
+    pub compression: Option<i32>,
+    pub integrity: bool,
+    pub algorithm: HashAlgorithm,
+}
+
+///Fields of a 'ChunkedUpload' header
+pub struct ChunkedUpload {
+    pub size: u64,
+    pub hash: String,
+    pub signature: Option<Signature>,
+    pub manifest_len: u64,
+}
+
+///How a handled request ended, recorded in the audit log
+pub enum Outcome {
+    ///Request completed
+    Success,
+    ///File sent to the client
+    Served { size: u64, hash: String },
+    ///Request refused, the reason was sent to the client
+    Rejected(String),
+}
+
+impl Context {
+    ///Path of a file relative to the resource directory, with '/' separators
+    pub fn relative_key(&self, path: &Path) -> String {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        relative.to_string_lossy().replace("\\", "/")
+    }
+    ///Moves the quota, signature, hash and layout records of a key (trashed or restored files)
+    pub async fn relocate(&self, from: &str, to: &str) -> common::Result<()> {
+        self.quota.rename(from, to).await?;
+        self.hashes.rename(from, to).await?;
+        self.layouts.rename(from, to).await?;
+        self.signatures.rename(from, to).await
+    }
+    ///Drops the quota, signature, hash and layout records of a key (files removed for good)
+    pub async fn forget(&self, key: &str) -> common::Result<()> {
+        self.quota.remove(key).await?;
+        self.hashes.remove(key).await?;
+        self.layouts.remove(key).await?;
+        self.signatures.remove(key).await
+    }
+    ///Size and hash of the file stored under `key`, with the algorithm it was uploaded with
+    ///
+    /// Files stored before hashes were recorded are hashed once, then their hash is recorded too
+    pub async fn describe(&self, key: &str) -> common::Result<(u64, String)> {
+        let layout = self.layouts.get(key).await;
+        match self.hashes.load(key).await? {
+            Some(hash) => {
+                let stored = self.files.stat(key).await?.size;
+                let size = chunks::logical_size(&*self.files, key, stored, layout).await?;
+                Ok((size, hash))
+            }
+            None => {
+                let (size, hash) = chunks::describe(&*self.files, key, layout).await?;
+                self.hashes.save(key, &hash, None).await?;
+                Ok((size, hash))
+            }
+        }
+    }
 }
 
 impl Listener {
@@ -32,19 +171,88 @@ impl Listener {
     /// ```
     pub async fn new(host: &str, port: &str) -> common::Result<Listener> {
         //When the host or the port is not present run the server on the local host
-        if host.is_empty() || port.is_empty() {
+        let listener = if host.is_empty() || port.is_empty() {
             let listener = TcpListener::bind("0.0.0.0:8080").await?;
-            let port = listener.local_addr().unwrap().port();
+            let port = listener.local_addr()?.port();
             info!("Listener is running on {}", port);
-            //returns a new listener struct object
-            return Ok(Listener { listener });
-        }
-        //If the host and port is specified the server will be ran with the passed address
-        let addr = format!("{}:{}", host, port);
-        let listener = TcpListener::bind(&addr).await?;
-        info!("Listener is running {}", addr);
-        //returns a new listener struct
-        Ok(Listener { listener })
+            listener
+        } else {
+            //If the host and port is specified the server will be ran with the passed address
+            let addr = format!("{}:{}", host, port);
+            let listener = TcpListener::bind(&addr).await?;
+            info!("Listener is running {}", addr);
+            listener
+        };
+        //returns a new listener struct with default limits
+        Ok(Listener {
+            listener,
+            limiter: Arc::new(ConnectionLimiter::new(Limits::default())),
+            rejections: Arc::new(Semaphore::new(MAX_REJECTIONS)),
+            access: Arc::new(AccessControl::default()),
+            timeouts: Timeouts::default(),
+            buffers: Buffers::default(),
+            quota: Quota::default(),
+            policy: Policy::default(),
+            audit: Audit::default(),
+            trash: Trash::default(),
+            versions: Versions::default(),
+            storage: Storage::default(),
+            backend: Backend::default(),
+            compression: Compression::default(),
+            hashing: Hashing::default(),
+        })
+    }
+    ///Replaces the connection caps and rate limits (defaults are used otherwise)
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limiter = Arc::new(ConnectionLimiter::new(limits));
+    }
+    ///Replaces the read/write timeouts applied to client connections (defaults are used otherwise)
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+    ///Replaces the sizes file bodies are sent, received and hashed in (defaults are used otherwise)
+    pub fn set_buffers(&mut self, buffers: Buffers) {
+        self.buffers = buffers;
+    }
+    ///Replaces the per-user quotas and free space reserve (defaults are used otherwise)
+    pub fn set_quota(&mut self, quota: Quota) {
+        self.quota = quota;
+    }
+    ///Replaces the upload policy rules (defaults are used otherwise)
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+    }
+    ///Replaces the audit log settings (defaults are used otherwise)
+    pub fn set_audit(&mut self, audit: Audit) {
+        self.audit = audit;
+    }
+    ///Replaces the trash settings (defaults are used otherwise)
+    pub fn set_trash(&mut self, trash: Trash) {
+        self.trash = trash;
+    }
+    ///Replaces the version history settings (defaults are used otherwise)
+    pub fn set_versions(&mut self, versions: Versions) {
+        self.versions = versions;
+    }
+    ///Replaces the storage settings (defaults are used otherwise)
+    pub fn set_storage(&mut self, storage: Storage) {
+        self.storage = storage;
+    }
+    ///Replaces the backend the files are kept in (the resource directory otherwise)
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+    ///Replaces the wire compression settings (defaults are used otherwise)
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+    ///Replaces the hash algorithms accepted for uploads (all of them otherwise)
+    pub fn set_hashing(&mut self, hashing: Hashing) {
+        self.hashing = hashing;
+    }
+    ///Returns a handle to the IP allow/deny rules, used to reload them while the server runs
+    pub fn access_control(&self) -> Arc<AccessControl> {
+        Arc::clone(&self.access)
     }
     ///This starts the server loop which accepts a connection and handles the client
     ///
@@ -58,17 +266,102 @@ impl Listener {
     /// }
     /// ```
     pub async fn listen(&mut self, path: PathBuf) -> common::Result<()> {
+        let audit = if self.audit.enabled {
+            Some(AuditLog::open(&self.audit.log_path(&path)).await?)
+        } else {
+            None
+        };
+        // deduplicated paths are hard links, only plain files can be
+        if self.storage.deduplicate && !self.backend.is_local() {
+            return Err(VeriflowError::Config(
+                "storage.deduplicate needs the local backend, use storage.chunking instead"
+                    .to_string(),
+            ));
+        }
+        // shared bodies are linked as they were received
+        if self.storage.deduplicate && self.storage.compress {
+            return Err(VeriflowError::Config(
+                "storage.compress can't be combined with storage.deduplicate".to_string(),
+            ));
+        }
+        self.buffers.validate()?;
+        let files = self.backend.open(&path)?;
+        let layouts = Arc::new(LayoutIndex::load(&path).await?);
+        let parallel = ParallelUploads::new(&path);
+        parallel.clear().await?;
+        let context = Arc::new(Context {
+            quota: QuotaManager::load(self.quota.clone(), &path).await?,
+            policy: self.policy.clone(),
+            audit,
+            signatures: SignatureStore::new(&path),
+            trees: TreeStore::new(&path),
+            trash: TrashBin::load(self.trash.clone(), &path, Arc::clone(&files)).await?,
+            versions: VersionStore::load(
+                self.versions.clone(),
+                &path,
+                Arc::clone(&files),
+                Arc::clone(&layouts),
+            )
+            .await?,
+            storage: self.storage.clone(),
+            objects: ObjectStore::new(&path),
+            chunks: ChunkStore::new(Arc::clone(&files), Arc::clone(&layouts)),
+            compression: self.compression.clone(),
+            hashing: self.hashing.clone(),
+            hashes: HashStore::new(&path),
+            layouts,
+            parallel,
+            restores: AtomicU64::new(0),
+            files,
+            root: path,
+        });
+        // expired trash entries and versions are purged in the background
+        tokio::spawn(Self::sweep(Arc::clone(&context)));
         //infitnite loop this will act as the servers main loop
         loop {
             //The listener.accept() function can possibly throw an error so we handle it using the match keyword
             match self.listener.accept().await {
                 //when a connection is made we deal with it below
                 Ok((mut _stream, addr)) => {
+                    // drop connections from outside the allowed ranges before reading any bytes
+                    if !self.access.is_allowed(addr.ip()) {
+                        warn!("Denied connection from {} by access rules", addr);
+                        continue;
+                    }
+                    // enforce bans, connection caps and the request rate before reading any bytes
+                    let permit = match self.limiter.try_acquire(addr.ip()) {
+                        Ok(permit) => permit,
+                        Err(rejection) => {
+                            warn!("Rejected connection from {}: {}", addr, rejection);
+                            if rejection != Rejection::Banned {
+                                // a flood of rejected connections is closed, not answered
+                                if let Ok(answer) = Arc::clone(&self.rejections).try_acquire_owned()
+                                {
+                                    let timeouts = self.timeouts.clone();
+                                    tokio::spawn(async move {
+                                        let _ = Self::reject(_stream, rejection, timeouts).await;
+                                        drop(answer);
+                                    });
+                                }
+                            }
+                            continue;
+                        }
+                    };
                     info!("User {} has connected.", addr,);
-                    let connection = ProtocolConnection::new(_stream).await?;
-                    let dir = path.clone();
+                    let mut connection = ProtocolConnection::new(_stream).await?;
+                    connection.set_timeouts(self.timeouts.clone());
+                    connection.set_buffers(self.buffers);
+                    let context = Arc::clone(&context);
+                    let limiter = Arc::clone(&self.limiter);
                     tokio::spawn(async move {
-                        let _ = Self::handle_client(connection, dir).await;
+                        if let Err(e) = Self::handle_client(connection, context).await {
+                            warn!("Request from {} failed: {}", addr, e);
+                            // repeated protocol errors get the address banned
+                            if limits::is_protocol_error(&e) && limiter.record_failure(addr.ip()) {
+                                warn!("Banned {} after repeated protocol errors", addr.ip());
+                            }
+                        }
+                        drop(permit);
                     });
                 }
 
@@ -79,18 +372,57 @@ impl Listener {
             }
         }
     }
+    ///Tells a rejected client why before the connection is closed
+    async fn reject(
+        stream: TcpStream,
+        rejection: Rejection,
+        timeouts: Timeouts,
+    ) -> common::Result<()> {
+        let mut connection = ProtocolConnection::new(stream).await?;
+        connection.set_timeouts(timeouts);
+        let header = FileHeader::Error(format!("Connection refused: {rejection}"));
+        let str_header = serde_json::to_string(&header)?;
+        connection.send_header(&str_header).await?;
+        Ok(())
+    }
     ///Used to concurrently handle clients
     async fn handle_client(
         mut connection: ProtocolConnection,
-        path: PathBuf,
+        context: Arc<Context>,
     ) -> common::Result<()> {
         let prefix_len = connection.read_prefix().await?;
         let header: Vec<u8> = connection.read_body(prefix_len).await?;
         let string_header = String::from_utf8_lossy(&header);
         let file_header: FileHeader = serde_json::from_str(&string_header)?;
-        Self::handle_operation(file_header, connection, path).await?;
+
+        let peer = connection.peer_addr()?.to_string();
+        let mut entry = AuditEntry::new(&file_header, peer, Self::identity(&connection)?);
+        let result = Self::handle_operation(file_header, connection, &context).await;
+
+        // every handled header ends up in the audit log, whatever the outcome
+        if let Some(audit) = &context.audit {
+            entry.outcome = match &result {
+                Ok(Outcome::Success) => "success".to_string(),
+                Ok(Outcome::Served { size, hash }) => {
+                    entry.size = Some(*size);
+                    entry.hash = Some(hash.clone());
+                    "success".to_string()
+                }
+                Ok(Outcome::Rejected(reason)) => format!("rejected: {reason}"),
+                Err(e) => format!("error: {e}"),
+            };
+            if let Err(e) = audit.record(entry).await {
+                error!("Failed to write the audit log: {}", e);
+            }
+        }
+
+        result?;
         Ok(())
     }
+    ///Identity of the client, its IP address until user authentication exists
+    fn identity(connection: &ProtocolConnection) -> common::Result<String> {
+        Ok(connection.peer_addr()?.ip().to_canonical().to_string())
+    }
     async fn safe_join(base: &Path, user_input: &str) -> common::Result<path::PathBuf> {
         let path = Path::new(user_input);
         if user_input.is_empty() {
@@ -110,147 +442,1202 @@ impl Listener {
                 )));
             }
         }
+        // the metadata directory is private to the server
+        let first = path.components().find(|c| !matches!(c, Component::CurDir));
+        if first == Some(Component::Normal(META_DIR.as_ref())) {
+            return Err(VeriflowError::Io(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Access to server metadata denied",
+            )));
+        }
         Ok(base.join(path))
     }
     ///Function to manage the client operations
     async fn handle_operation(
         header: FileHeader,
         connection: ProtocolConnection,
-        path: PathBuf,
-    ) -> common::Result<()> {
+        context: &Context,
+    ) -> common::Result<Outcome> {
         // Get path
         let path_var = header.path();
-        let safe_path = Self::safe_join(path.as_path(), path_var).await?;
+        let safe_path = Self::safe_join(context.root.as_path(), path_var).await?;
 
-        match header {
-            FileHeader::Upload { size, hash, .. } => {
-                Self::handle_upload(connection, safe_path, size, hash).await?
+        let outcome = match header {
+            FileHeader::Upload {
+                size,
+                hash,
+                signature,
+                compression,
+                integrity,
+                algorithm,
+                ..
+            } => {
+                let upload = Upload {
+                    size,
+                    hash,
+                    signature,
+                    compression,
+                    integrity,
+                    algorithm,
+                };
+                Self::handle_upload(connection, context, safe_path, upload).await?
+            }
+            FileHeader::ChunkedUpload {
+                size,
+                hash,
+                signature,
+                manifest_len,
+                ..
+            } => {
+                let upload = ChunkedUpload {
+                    size,
+                    hash,
+                    signature,
+                    manifest_len,
+                };
+                Self::handle_chunked_upload(connection, context, safe_path, upload).await?
+            }
+            FileHeader::ParallelUpload {
+                size,
+                hash,
+                signature,
+                ..
+            } => {
+                let upload = Upload {
+                    size,
+                    algorithm: Checksum::algorithm_of(&hash),
+                    hash,
+                    signature,
+                    compression: None,
+                    integrity: false,
+                };
+                Self::handle_parallel_upload(connection, context, safe_path, upload).await?
+            }
+            FileHeader::UploadRange {
+                id,
+                offset,
+                length,
+                compression,
+                integrity,
+            } => {
+                let range = (offset, length);
+                Self::handle_upload_range(connection, context, id, range, compression, integrity)
+                    .await?
+            }
+            FileHeader::DownloadRange {
+                offset,
+                length,
+                compression,
+                integrity,
+                ..
+            } => {
+                Self::handle_download_range(
+                    connection,
+                    context,
+                    safe_path,
+                    (offset, length),
+                    compression,
+                    integrity,
+                )
+                .await?
+            }
+            FileHeader::Download {
+                compression,
+                integrity,
+                ..
+            } => {
+                Self::handle_download(connection, context, safe_path, compression, integrity)
+                    .await?
             }
-            FileHeader::Download { .. } => Self::handle_download(connection, safe_path).await?,
-            FileHeader::Delete { .. } => Self::handle_delete(connection, safe_path).await?,
-            FileHeader::List => Self::handle_list(connection, safe_path).await?,
+            FileHeader::Delete { .. } => {
+                Self::handle_delete(connection, context, safe_path).await?
+            }
+            FileHeader::List => Self::handle_list(connection, context, safe_path).await?,
+            FileHeader::Usage => Self::handle_usage(connection, context).await?,
+            FileHeader::TrashList => Self::handle_trash_list(connection, context).await?,
+            FileHeader::Restore { id } => Self::handle_restore(connection, context, id).await?,
+            FileHeader::Purge { id } => Self::handle_purge(connection, context, id).await?,
+            FileHeader::Versions { .. } => {
+                Self::handle_versions(connection, context, safe_path).await?
+            }
+            FileHeader::DownloadVersion {
+                hash,
+                compression,
+                integrity,
+                ..
+            } => {
+                Self::handle_download_version(
+                    connection,
+                    context,
+                    safe_path,
+                    hash,
+                    compression,
+                    integrity,
+                )
+                .await?
+            }
+            FileHeader::RestoreVersion { hash, .. } => {
+                Self::handle_restore_version(connection, context, safe_path, hash).await?
+            }
+            FileHeader::Tree { .. } => Self::handle_tree(connection, context, safe_path).await?,
             // Error handling for wrong variants
             other => return Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
-        }
-        Ok(())
+        };
+        Ok(outcome)
     }
     ///Handles clients' upload operation
     async fn handle_upload(
         mut connection: ProtocolConnection,
+        context: &Context,
         path: PathBuf,
-        size: u64,
-        expected_hash: String,
-    ) -> common::Result<()> {
-        let mut received_file = File::create(&path).await?;
-        connection
-            .read_file_to_disk(&mut received_file, size)
-            .await?;
-        let received_file_hash = hashing::hash_file(path.as_path(), |_| {}).await?;
+        upload: Upload,
+    ) -> common::Result<Outcome> {
+        let Upload {
+            size,
+            hash: mut expected_hash,
+            mut signature,
+            compression,
+            integrity,
+            algorithm: requested,
+        } = upload;
+        let user = Self::identity(&connection)?;
+        let key = context.relative_key(&path);
+
+        // the client's hash is checked if its algorithm is accepted, otherwise it sends
+        // another one made with the algorithm the server picked in a trailer
+        let hashed_with = if expected_hash.is_empty() {
+            requested
+        } else {
+            Checksum::algorithm_of(&expected_hash)
+        };
+        let algorithm = context.hashing.negotiate(hashed_with);
+        if algorithm != hashed_with {
+            expected_hash.clear();
+            signature = None;
+        }
+
+        // check the upload policy, the user's quota and the free disk space before accepting a single byte
+        let checked = context
+            .policy
+            .check_header(&key, size)
+            .and_then(|_| context.quota.reserve(&user, &key, size));
+        let reservation = match checked {
+            Ok(reservation) => reservation,
+            Err(e) => {
+                warn!("Rejected upload of {:?} from {}: {}", key, user, e);
+                let header = FileHeader::Error(e.to_string());
+                let str_header = serde_json::to_string(&header)?;
+                connection.send_header(&str_header).await?;
+                return Ok(Outcome::Rejected(e.to_string()));
+            }
+        };
+
+        // the server already stores this body, link it instead of transferring it again
+        let deduplicate = context.storage.deduplicate;
+        if deduplicate
+            && context.storage.instant_upload
+            && !expected_hash.is_empty()
+            && context.objects.contains(&expected_hash, size).await?
+        {
+            let object = context.objects.object(&expected_hash);
+            if context.policy.needs_preview() {
+                let mut preview = vec![0u8; cmp::min(size, PREVIEW_SIZE) as usize];
+                File::open(&object).await?.read_exact(&mut preview).await?;
+                if let Err(e) = context.policy.check_content(&preview) {
+                    warn!("Rejected upload of {:?} from {}: {}", key, user, e);
+                    let header = FileHeader::Error(e.to_string());
+                    let str_header = serde_json::to_string(&header)?;
+                    connection.send_header(&str_header).await?;
+                    return Ok(Outcome::Rejected(e.to_string()));
+                }
+            }
+
+            Self::archive_current(context, &key).await?;
+            context.objects.link(&expected_hash, &path).await?;
+            context.layouts.set(&key, Layout::Plain).await?;
+            reservation.commit().await?;
+            // the tree was built when the stored body was verified
+            let root = context
+                .trees
+                .load(&expected_hash)
+                .await?
+                .map(|tree| tree.root());
+            context
+                .hashes
+                .save(&key, &expected_hash, root.as_deref())
+                .await?;
+            context.signatures.save(&key, signature.as_ref()).await?;
+            info!("File already stored, linked {:?} without a transfer", key);
+            let header = FileHeader::Success(
+                "File uploaded instantly, the server already has it!".to_string(),
+            );
+            let str_header = serde_json::to_string(&header)?;
+            connection.send_header(&str_header).await?;
+            return Ok(Outcome::Success);
+        }
+
+        // content type rules need the first bytes of the file before the rest is accepted
+        let preview_len = if context.policy.needs_preview() {
+            cmp::min(size, PREVIEW_SIZE)
+        } else {
+            0
+        };
+        // the body after the preview comes compressed if both sides want it
+        let compression = context.compression.negotiate(&key, compression);
+        let header = FileHeader::Ready {
+            preview: preview_len,
+            compression,
+            integrity,
+            algorithm,
+        };
+        let str_header = serde_json::to_string(&header)?;
+        connection.send_header(&str_header).await?;
+
+        // the body is hashed as it arrives, it isn't read back
+        connection.start_hashing(algorithm);
+        connection.start_tree();
+        let mut preview = Vec::new();
+        if preview_len > 0 {
+            preview = connection.read_payload(preview_len as usize).await?;
+            connection.update_hash(&preview);
+            let verdict = match context.policy.check_content(&preview) {
+                Ok(()) => FileHeader::Success("Content accepted".to_string()),
+                Err(e) => {
+                    warn!("Rejected upload of {:?} from {}: {}", key, user, e);
+                    FileHeader::Error(e.to_string())
+                }
+            };
+            let str_header = serde_json::to_string(&verdict)?;
+            connection.send_header(&str_header).await?;
+            if let FileHeader::Error(reason) = verdict {
+                return Ok(Outcome::Rejected(reason));
+            }
+        }
+
+        // the body is received aside, the stored file is only replaced once it is verified
+        let (incoming, mut received_file) = context.objects.incoming().await?;
+        connection.set_compression(compression);
+        connection.set_integrity(integrity);
+        let received: common::Result<()> = async {
+            received_file.write_all(&preview).await?;
+            connection
+                .read_file_to_disk(&mut received_file, size - preview_len)
+                .await?;
+            received_file.shutdown().await?;
+            Ok(())
+        }
+        .await;
+        drop(received_file);
+        let received_file_hash = connection.take_hash().unwrap_or_default();
+        let tree = connection.take_tree();
+        // the hash follows the body when the client hashed it while sending
+        let trailer = match received {
+            Ok(()) if expected_hash.is_empty() => {
+                Self::read_trailer(&mut connection).await.map(Some)
+            }
+            Ok(()) => Ok(None),
+            Err(e) => Err(e),
+        };
+        // a timed out or dropped upload must not leave a partial file behind
+        let trailer = match trailer {
+            Ok(trailer) => trailer,
+            Err(e) => {
+                Self::discard_upload(&incoming).await;
+                error!("Upload to {:?} aborted, partial file removed: {}", path, e);
+                return Err(e);
+            }
+        };
+        if let Some((hash, trailer_signature)) = trailer {
+            expected_hash = hash;
+            signature = trailer_signature;
+        }
 
         if expected_hash != received_file_hash {
-            fs::remove_file(path).await?;
+            Self::discard_upload(&incoming).await;
             error!("There has been an error when comparing the expected hash to the calculated hash retry sending the file");
             let header = FileHeader::Error("Failure: Hash didn't match!".to_string());
             let str_header = serde_json::to_string(&header)?;
             connection.send_header(&str_header).await?;
+            Ok(Outcome::Rejected("hash mismatch".to_string()))
         } else {
+            if deduplicate {
+                context
+                    .objects
+                    .store(&incoming, &received_file_hash)
+                    .await?;
+                Self::archive_current(context, &key).await?;
+                context.objects.link(&received_file_hash, &path).await?;
+                context.layouts.set(&key, Layout::Plain).await?;
+            } else {
+                Self::archive_current(context, &key).await?;
+                context.files.put_file(&key, &incoming).await?;
+                context.layouts.set(&key, Layout::Plain).await?;
+                Self::compress_at_rest(context, &key, size, &received_file_hash).await?;
+            }
+            if let Some(tree) = &tree {
+                context.trees.save(&received_file_hash, tree).await?;
+            }
+            reservation.commit().await?;
+            let root = tree.as_ref().map(|tree| tree.root());
+            context
+                .hashes
+                .save(&key, &received_file_hash, root.as_deref())
+                .await?;
+            context.signatures.save(&key, signature.as_ref()).await?;
             info!("File successfuly received");
             let header = FileHeader::Success("File uploaded successfully!".to_string());
             let str_header = serde_json::to_string(&header)?;
             connection.send_header(&str_header).await?;
+            Ok(Outcome::Success)
+        }
+    }
+    ///Handles clients' chunked upload operation
+    ///
+    /// The client sends its chunk list, the server answers with the chunks it doesn't store yet
+    /// and only those are transferred. The file is kept as a recipe of its chunks
+    async fn handle_chunked_upload(
+        mut connection: ProtocolConnection,
+        context: &Context,
+        path: PathBuf,
+        upload: ChunkedUpload,
+    ) -> common::Result<Outcome> {
+        let user = Self::identity(&connection)?;
+        let key = context.relative_key(&path);
+
+        let checked = if context.storage.chunking {
+            context
+                .policy
+                .check_header(&key, upload.size)
+                .and_then(|_| Self::check_algorithm(context, &upload.hash))
+                .and_then(|_| context.quota.reserve(&user, &key, upload.size))
+        } else {
+            Err(VeriflowError::PolicyViolation(
+                "chunked uploads are disabled on this server".to_string(),
+            ))
+        };
+        let reservation = match checked {
+            Ok(reservation) => reservation,
+            Err(e) => {
+                warn!("Rejected upload of {:?} from {}: {}", key, user, e);
+                let header = FileHeader::Error(e.to_string());
+                let str_header = serde_json::to_string(&header)?;
+                connection.send_header(&str_header).await?;
+                return Ok(Outcome::Rejected(e.to_string()));
+            }
+        };
+
+        let preview_len = if context.policy.needs_preview() {
+            cmp::min(upload.size, PREVIEW_SIZE)
+        } else {
+            0
+        };
+        let header = FileHeader::Ready {
+            preview: preview_len,
+            compression: None,
+            integrity: false,
+            algorithm: Checksum::algorithm_of(&upload.hash),
+        };
+        let str_header = serde_json::to_string(&header)?;
+        connection.send_header(&str_header).await?;
+
+        if preview_len > 0 {
+            let preview = connection.read_payload(preview_len as usize).await?;
+            let verdict = match context.policy.check_content(&preview) {
+                Ok(()) => FileHeader::Success("Content accepted".to_string()),
+                Err(e) => {
+                    warn!("Rejected upload of {:?} from {}: {}", key, user, e);
+                    FileHeader::Error(e.to_string())
+                }
+            };
+            let str_header = serde_json::to_string(&verdict)?;
+            connection.send_header(&str_header).await?;
+            if let FileHeader::Error(reason) = verdict {
+                return Ok(Outcome::Rejected(reason));
+            }
+        }
+
+        let manifest = connection
+            .read_payload(upload.manifest_len as usize)
+            .await?;
+        let chunk_list: Vec<Chunk> = serde_json::from_slice(&manifest)?;
+        if let Err(e) = chunks::check_manifest(&chunk_list, upload.size) {
+            let header = FileHeader::Error(e.to_string());
+            let str_header = serde_json::to_string(&header)?;
+            connection.send_header(&str_header).await?;
+            return Ok(Outcome::Rejected(e.to_string()));
+        }
+
+        // tell the client which chunks to send
+        let missing = context.chunks.missing(&chunk_list).await?;
+        let payload = serde_json::to_vec(&missing)?;
+        let payload_header = FileHeader::payload("missing", payload.len() as u64);
+        let str_header = serde_json::to_string(&payload_header)?;
+        connection.send_header(&str_header).await?;
+        connection.send_data(&payload).await?;
+
+        for index in &missing {
+            let chunk = &chunk_list[*index as usize];
+            let (incoming, mut file) = context.chunks.incoming().await?;
+            // a timed out or dropped upload must not leave a partial chunk behind
+            let received: common::Result<()> = async {
+                connection.read_file_to_disk(&mut file, chunk.size).await?;
+                file.shutdown().await?;
+                Ok(())
+            }
+            .await;
+            drop(file);
+            if let Err(e) = received {
+                let _ = context.files.delete(&incoming).await;
+                error!("Upload to {:?} aborted, partial chunk removed: {}", path, e);
+                return Err(e);
+            }
+            if !context.chunks.store(&incoming, chunk).await? {
+                error!("Chunk {} of {:?} didn't match its hash", index, key);
+                let header = FileHeader::Error("Failure: Hash didn't match!".to_string());
+                let str_header = serde_json::to_string(&header)?;
+                connection.send_header(&str_header).await?;
+                return Ok(Outcome::Rejected("chunk hash mismatch".to_string()));
+            }
+        }
+
+        // the chunks must add up to the file the client announced
+        let recipe = Recipe {
+            size: upload.size,
+            hash: upload.hash,
+            chunks: chunk_list,
+        };
+        let Some(tree) = context.chunks.verify(&recipe).await? else {
+            error!("Chunks of {:?} don't add up to the announced hash", key);
+            let header = FileHeader::Error("Failure: Hash didn't match!".to_string());
+            let str_header = serde_json::to_string(&header)?;
+            connection.send_header(&str_header).await?;
+            return Ok(Outcome::Rejected("hash mismatch".to_string()));
+        };
+
+        Self::archive_current(context, &key).await?;
+        context.chunks.write_recipe(&recipe, &key).await?;
+        context.trees.save(&recipe.hash, &tree).await?;
+        reservation.commit().await?;
+        context
+            .hashes
+            .save(&key, &recipe.hash, Some(&tree.root()))
+            .await?;
+        context
+            .signatures
+            .save(&key, upload.signature.as_ref())
+            .await?;
+        info!(
+            "File received in chunks, {} of {} chunks transferred",
+            missing.len(),
+            recipe.chunks.len()
+        );
+        let header = FileHeader::Success(format!(
+            "File uploaded successfully, {} of {} chunks were new!",
+            missing.len(),
+            recipe.chunks.len()
+        ));
+        let str_header = serde_json::to_string(&header)?;
+        connection.send_header(&str_header).await?;
+        Ok(Outcome::Success)
+    }
+    ///Refuses uploads whose announced hash is of an algorithm the server doesn't accept
+    fn check_algorithm(context: &Context, hash: &str) -> common::Result<()> {
+        let algorithm = Checksum::algorithm_of(hash);
+        if context.hashing.negotiate(algorithm) != algorithm {
+            return Err(VeriflowError::PolicyViolation(format!(
+                "{algorithm} hashes aren't accepted by this server"
+            )));
+        }
+        Ok(())
+    }
+    ///Reads the 'Trailer' sent after an upload body
+    async fn read_trailer(
+        connection: &mut ProtocolConnection,
+    ) -> common::Result<(String, Option<Signature>)> {
+        let prefix_len = connection.read_prefix().await?;
+        let header: Vec<u8> = connection.read_body(prefix_len).await?;
+        match serde_json::from_slice(&header)? {
+            FileHeader::Trailer { hash, signature } => Ok((hash, signature)),
+            other => Err(VeriflowError::UnexpectedFileHeader(format!("{:?}", other))),
+        }
+    }
+    ///Handles the announcing connection of a parallel upload
+    ///
+    /// Waits while the ranges arrive on other connections, then verifies and stores the file
+    async fn handle_parallel_upload(
+        mut connection: ProtocolConnection,
+        context: &Context,
+        path: PathBuf,
+        upload: Upload,
+    ) -> common::Result<Outcome> {
+        let user = Self::identity(&connection)?;
+        let key = context.relative_key(&path);
+
+        let checked = context
+            .policy
+            .check_header(&key, upload.size)
+            .and_then(|_| Self::check_algorithm(context, &upload.hash))
+            .and_then(|_| context.quota.reserve(&user, &key, upload.size));
+        let reservation = match checked {
+            Ok(reservation) => reservation,
+            Err(e) => {
+                warn!("Rejected upload of {:?} from {}: {}", key, user, e);
+                let header = FileHeader::Error(e.to_string());
+                let str_header = serde_json::to_string(&header)?;
+                connection.send_header(&str_header).await?;
+                return Ok(Outcome::Rejected(e.to_string()));
+            }
+        };
+
+        // the staging file goes with the guard, whatever happens below
+        let guard = context
+            .parallel
+            .begin(&user, &key, upload.size, upload.algorithm)
+            .await?;
+        let staging = guard.upload.staging.clone();
+        let header = FileHeader::Accepted {
+            id: guard.upload.id.clone(),
+        };
+        let str_header = serde_json::to_string(&header)?;
+        connection.send_header(&str_header).await?;
+
+        // the client hanging up abandons the upload
+        tokio::select! {
+            _ = guard.upload.wait() => {}
+            closed = connection.closed() => {
+                closed?;
+                warn!("Parallel upload of {:?} abandoned by {}", key, user);
+                return Ok(Outcome::Rejected("upload abandoned".to_string()));
+            }
+        }
+
+        let buffer_size = connection.buffers().hash_buffer_size;
+        let (received_hash, tree) =
+            hashing::hash_file_tree(&staging, upload.algorithm, buffer_size).await?;
+        if received_hash != upload.hash {
+            error!("Parallel upload of {:?} didn't match its hash", key);
+            let header = FileHeader::Error("Failure: Hash didn't match!".to_string());
+            let str_header = serde_json::to_string(&header)?;
+            connection.send_header(&str_header).await?;
+            return Ok(Outcome::Rejected("hash mismatch".to_string()));
+        }
+
+        // ranges arrive in any order, content rules are checked once the whole file is here
+        if context.policy.needs_preview() {
+            let mut preview = vec![0u8; cmp::min(upload.size, PREVIEW_SIZE) as usize];
+            File::open(&staging).await?.read_exact(&mut preview).await?;
+            if let Err(e) = context.policy.check_content(&preview) {
+                warn!("Rejected upload of {:?} from {}: {}", key, user, e);
+                let header = FileHeader::Error(e.to_string());
+                let str_header = serde_json::to_string(&header)?;
+                connection.send_header(&str_header).await?;
+                return Ok(Outcome::Rejected(e.to_string()));
+            }
+        }
+
+        if context.storage.deduplicate {
+            context.objects.store(&staging, &received_hash).await?;
+            Self::archive_current(context, &key).await?;
+            context.objects.link(&received_hash, &path).await?;
+            context.layouts.set(&key, Layout::Plain).await?;
+        } else {
+            Self::archive_current(context, &key).await?;
+            context.files.put_file(&key, &staging).await?;
+            context.layouts.set(&key, Layout::Plain).await?;
+            Self::compress_at_rest(context, &key, upload.size, &received_hash).await?;
+        }
+        context.trees.save(&received_hash, &tree).await?;
+        drop(guard);
+        reservation.commit().await?;
+        context
+            .hashes
+            .save(&key, &received_hash, Some(&tree.root()))
+            .await?;
+        context
+            .signatures
+            .save(&key, upload.signature.as_ref())
+            .await?;
+        info!("File received over parallel streams");
+        let header = FileHeader::Success("File uploaded successfully!".to_string());
+        let str_header = serde_json::to_string(&header)?;
+        connection.send_header(&str_header).await?;
+        Ok(Outcome::Success)
+    }
+    ///Handles one byte range of a parallel upload
+    async fn handle_upload_range(
+        mut connection: ProtocolConnection,
+        context: &Context,
+        id: String,
+        range: (u64, u64),
+        compression: Option<i32>,
+        integrity: bool,
+    ) -> common::Result<Outcome> {
+        let (offset, length) = range;
+        let user = Self::identity(&connection)?;
+        let claimed = match context.parallel.get(&id) {
+            Some(upload) if upload.user == user => upload.claim(offset, length).map(|_| upload),
+            _ => Err(VeriflowError::UnexpectedFileHeader(format!(
+                "No parallel upload {id} in progress"
+            ))),
+        };
+        let upload = match claimed {
+            Ok(upload) => upload,
+            Err(e) => {
+                let header = FileHeader::Error(e.to_string());
+                let str_header = serde_json::to_string(&header)?;
+                connection.send_header(&str_header).await?;
+                return Ok(Outcome::Rejected(e.to_string()));
+            }
+        };
+
+        let compression = context.compression.negotiate(&upload.key, compression);
+        let header = FileHeader::Ready {
+            preview: 0,
+            compression,
+            integrity,
+            algorithm: upload.algorithm,
+        };
+        let str_header = serde_json::to_string(&header)?;
+        connection.send_header(&str_header).await?;
+        connection.set_compression(compression);
+        connection.set_integrity(integrity);
+
+        let received: common::Result<()> = async {
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .open(&upload.staging)
+                .await?;
+            file.seek(io::SeekFrom::Start(offset)).await?;
+            connection.read_file_to_disk(&mut file, length).await?;
+            file.flush().await?;
+            Ok(())
+        }
+        .await;
+        // a failed range may be sent again
+        if let Err(e) = received {
+            upload.release(offset);
+            return Err(e);
+        }
+        upload.complete(length);
+        let header = FileHeader::Success("Range received".to_string());
+        let str_header = serde_json::to_string(&header)?;
+        connection.send_header(&str_header).await?;
+        Ok(Outcome::Success)
+    }
+    ///Compresses a stored upload if at rest compression is on and its type benefits
+    async fn compress_at_rest(
+        context: &Context,
+        key: &str,
+        size: u64,
+        hash: &str,
+    ) -> common::Result<()> {
+        if !context.storage.compress || context.compression.is_compressed(key) {
+            return Ok(());
+        }
+        let header = PackedHeader {
+            size,
+            hash: hash.to_string(),
+        };
+        let level = context.storage.compression_level;
+        if packed::pack(&*context.files, key, &header, level).await? {
+            context.layouts.set(key, Layout::Packed).await?;
+            info!("Stored {:?} compressed", key);
+        }
+        Ok(())
+    }
+    ///Removes what was received of a failed upload, the stored file was never touched
+    async fn discard_upload(incoming: &Path) {
+        let _ = fs::remove_file(incoming).await;
+    }
+    ///Keeps the contents an upload is about to replace in the file's history
+    async fn archive_current(context: &Context, key: &str) -> common::Result<()> {
+        let is_file = context
+            .files
+            .stat(key)
+            .await
+            .is_ok_and(|stored| !stored.is_dir);
+        if context.versions.is_enabled() && is_file {
+            let signature = context.signatures.load(key).await?;
+            let recorded = context.hashes.load(key).await?;
+            context.versions.archive(key, recorded, signature).await?;
         }
         Ok(())
     }
     ///Handles a clients' download request
     async fn handle_download(
         mut connection: ProtocolConnection,
+        context: &Context,
         path: PathBuf,
-    ) -> common::Result<()> {
+        compression: Option<i32>,
+        integrity: bool,
+    ) -> common::Result<Outcome> {
         // Extract filename from PathBuf
         let filename = path
             .file_name()
             .map(|n| n.to_string_lossy().to_string())
             .unwrap_or_else(|| "download".to_string());
 
-        let mut file_to_send = File::open(&path.as_path()).await?;
-        let file_meta_data = fs::metadata(&path.as_path()).await?;
-        let file_size = file_meta_data.len();
-        let file_hash = hashing::hash_file(path.as_path(), |_| {}).await?; // use saved .sha256 sidecar file in future
+        let key = context.relative_key(&path);
+        let (file_size, file_hash) = context.describe(&key).await?;
+        let signature = context.signatures.load(&key).await?;
+        let root = context.hashes.root(&key).await?;
 
+        let compression = context.compression.negotiate(&key, compression);
         let file_header = FileHeader::Upload {
             name: filename,
             size: file_size,
-            hash: file_hash,
+            hash: file_hash.clone(),
+            signature,
+            root,
+            compression,
+            integrity,
+            algorithm: Checksum::algorithm_of(&file_hash),
         };
 
         let serialized_header = serde_json::to_string(&file_header)?;
         connection.send_header(&serialized_header).await?;
-        connection
-            .write_file_to_stream(&mut file_to_send, file_size)
-            .await?;
+        connection.set_compression(compression);
+        connection.set_integrity(integrity);
+        Self::send_body(&mut connection, context, &key, file_size).await?;
+        Ok(Outcome::Served {
+            size: file_size,
+            hash: file_hash,
+        })
+    }
+    ///Handles a clients' request for a byte range of a file
+    async fn handle_download_range(
+        mut connection: ProtocolConnection,
+        context: &Context,
+        path: PathBuf,
+        range: (u64, u64),
+        compression: Option<i32>,
+        integrity: bool,
+    ) -> common::Result<Outcome> {
+        let (offset, length) = range;
+        let filename = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "download".to_string());
+
+        let key = context.relative_key(&path);
+        let (file_size, file_hash) = context.describe(&key).await?;
+        if offset.checked_add(length).is_none_or(|end| end > file_size) {
+            let reason = format!("Range {offset}+{length} is outside of the {file_size} byte file");
+            let header = FileHeader::Error(reason.clone());
+            let str_header = serde_json::to_string(&header)?;
+            connection.send_header(&str_header).await?;
+            return Ok(Outcome::Rejected(reason));
+        }
+        let signature = context.signatures.load(&key).await?;
+        let root = context.hashes.root(&key).await?;
+
+        let compression = context.compression.negotiate(&key, compression);
+        let file_header = FileHeader::Upload {
+            name: filename,
+            size: file_size,
+            hash: file_hash.clone(),
+            signature,
+            root,
+            compression,
+            integrity,
+            algorithm: Checksum::algorithm_of(&file_hash),
+        };
+        let serialized_header = serde_json::to_string(&file_header)?;
+        connection.send_header(&serialized_header).await?;
+        connection.set_compression(compression);
+        connection.set_integrity(integrity);
+        Self::send_range(&mut connection, context, &key, offset, length).await?;
+        Ok(Outcome::Served {
+            size: length,
+            hash: file_hash,
+        })
+    }
+    ///Streams the contents of a stored file, reassembling chunked files from their chunks
+    async fn send_body(
+        connection: &mut ProtocolConnection,
+        context: &Context,
+        key: &str,
+        size: u64,
+    ) -> common::Result<()> {
+        Self::send_range(connection, context, key, 0, size).await
+    }
+    ///Streams `length` bytes of a stored file from `offset` on
+    async fn send_range(
+        connection: &mut ProtocolConnection,
+        context: &Context,
+        key: &str,
+        offset: u64,
+        length: u64,
+    ) -> common::Result<()> {
+        match context.layouts.get(key).await {
+            Layout::Packed => {
+                let (header, body) = packed::open(&*context.files, key).await?;
+                packed::send_unpacked(connection, body, header.size, offset, length).await?;
+            }
+            Layout::Recipe => {
+                let recipe = chunks::read_recipe(&*context.files, key).await?;
+                let end = offset + length;
+                let mut chunk_start = 0;
+                for chunk in &recipe.chunks {
+                    let chunk_end = chunk_start + chunk.size;
+                    // only the chunks overlapping the range, from where it starts
+                    if chunk_end > offset && chunk_start < end {
+                        let skip = offset.saturating_sub(chunk_start);
+                        let send = chunk_end.min(end) - chunk_start - skip;
+                        let chunk_key = context.chunks.chunk(&chunk.hash);
+                        Self::send_stored(connection, context, &chunk_key, skip, send).await?;
+                    }
+                    chunk_start = chunk_end;
+                }
+            }
+            Layout::Plain => Self::send_stored(connection, context, key, offset, length).await?,
+        }
         Ok(())
     }
+    ///Streams `length` bytes of the file stored under `key` as it is, zero-copy for local files
+    async fn send_stored(
+        connection: &mut ProtocolConnection,
+        context: &Context,
+        key: &str,
+        offset: u64,
+        length: u64,
+    ) -> common::Result<()> {
+        if let Some(path) = context
+            .files
+            .local_path(key)
+            .filter(|_| context.storage.zero_copy)
+        {
+            let file = tokio::fs::File::open(path).await?;
+            return connection.write_file_range(file, offset, length).await;
+        }
+        let mut file = context.files.open_at(key, offset).await?;
+        connection.write_file_to_stream(&mut file, length).await
+    }
 
     ///Handles a list command request
     ///
     /// No return but it walks the resource directory and sends its contents together with the subdirectories to the client
-    async fn handle_list(mut connection: ProtocolConnection, path: PathBuf) -> common::Result<()> {
-        let mut stack = vec![path.clone()];
-        let mut path_list = vec![];
-        while let Some(dir) = stack.pop() {
-            let mut dir_content = fs::read_dir(dir.clone()).await?;
-            while let Some(entry) = dir_content.next_entry().await? {
-                let file_type = entry.file_type().await?;
-                let entry_path = entry.path();
-
-                if file_type.is_file() {
-                    let relative = entry_path.strip_prefix(&path).unwrap_or(&entry_path);
-
-                    let str_path = relative.to_string_lossy().replace("\\", "/");
-                    path_list.push(str_path);
-                } else if file_type.is_dir() {
-                    stack.push(entry_path);
-                }
+    async fn handle_list(
+        mut connection: ProtocolConnection,
+        context: &Context,
+        path: PathBuf,
+    ) -> common::Result<Outcome> {
+        let key = context.relative_key(&path);
+        let mut entries = vec![];
+        for file in context.files.list(&key).await? {
+            // skip the server's metadata directory
+            if file.key == META_DIR || file.key.starts_with(&format!("{META_DIR}/")) {
+                continue;
             }
+            // paths are relative to the listed directory
+            let relative = match file.key.strip_prefix(&key) {
+                Some(rest) if !key.is_empty() => rest.trim_start_matches('/'),
+                _ => &file.key,
+            };
+            // compressed and chunked files report the size of their original content
+            let layout = context.layouts.get(&file.key).await;
+            let size = chunks::logical_size(&*context.files, &file.key, file.size, layout).await?;
+            entries.push(ListEntry {
+                path: relative.to_string(),
+                size,
+                stored: file.size,
+            });
         }
-        info!("{:?}", path_list);
-        let payload = serde_json::to_vec(&path_list)?;
-        let payload_header = FileHeader::Upload {
-            name: "list".to_string(),
-            size: payload.len() as u64,
-            hash: String::new(),
-        };
+        info!("{:?}", entries);
+        let payload = serde_json::to_vec(&entries)?;
+        let payload_header = FileHeader::payload("list", payload.len() as u64);
         let str_header = serde_json::to_string(&payload_header)?;
         connection.send_header(&str_header).await?;
         connection.send_data(&payload).await?;
-        Ok(())
+        Ok(Outcome::Success)
+    }
+    ///Handles a usage request
+    ///
+    /// Sends the storage used and still available to the requesting user
+    async fn handle_usage(
+        mut connection: ProtocolConnection,
+        context: &Context,
+    ) -> common::Result<Outcome> {
+        let user = Self::identity(&connection)?;
+        let usage = context.quota.usage(&user)?;
+        let payload = serde_json::to_vec(&usage)?;
+        let payload_header = FileHeader::payload("usage", payload.len() as u64);
+        let str_header = serde_json::to_string(&payload_header)?;
+        connection.send_header(&str_header).await?;
+        connection.send_data(&payload).await?;
+        Ok(Outcome::Success)
     }
     ///Handles a delete request
+    ///
+    /// Moves the file/folder into the trash, or removes it for good when the trash is disabled
     pub async fn handle_delete(
         mut connection: ProtocolConnection,
+        context: &Context,
         path: PathBuf,
-    ) -> common::Result<()> {
+    ) -> common::Result<Outcome> {
         info!("{:?}", &path);
-        let md = metadata(&path).await?;
+        let key = context.relative_key(&path);
 
-        // combine the fs::remove logic for directories and files
-        let result = if md.is_dir() {
-            fs::remove_dir_all(&path).await
-        } else {
-            fs::remove_file(&path).await
+        let result: common::Result<String> = async {
+            // the resource directory also holds the server metadata
+            if key.is_empty() {
+                return Err(VeriflowError::Io(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "The resource directory itself can't be deleted",
+                )));
+            }
+
+            if context.trash.is_enabled() {
+                let entry = context.trash.trash(&key).await?;
+                // quota and signatures follow the files into the trash
+                context.relocate(&key, &trash::trash_key(&entry)).await?;
+                return Ok(format!(
+                    "Moved {key} to the trash, restore it with id {}",
+                    entry.id
+                ));
+            }
+
+            // removes directories and files alike
+            context.files.delete(&key).await?;
+            // deleted bytes no longer count against their owner's quota
+            context.forget(&key).await?;
+            Ok("Successfully deleted the requested file/folder".to_string())
+        }
+        .await;
+
+        Self::reply(&mut connection, result, "delete").await
+    }
+    ///Handles a trash list request
+    ///
+    /// Sends every entry of the trash to the client
+    async fn handle_trash_list(
+        mut connection: ProtocolConnection,
+        context: &Context,
+    ) -> common::Result<Outcome> {
+        let entries = context.trash.list().await;
+        let payload = serde_json::to_vec(&entries)?;
+        let payload_header = FileHeader::payload("trash", payload.len() as u64);
+        let str_header = serde_json::to_string(&payload_header)?;
+        connection.send_header(&str_header).await?;
+        connection.send_data(&payload).await?;
+        Ok(Outcome::Success)
+    }
+    ///Handles a restore request, moving a trash entry back to its original path
+    async fn handle_restore(
+        mut connection: ProtocolConnection,
+        context: &Context,
+        id: u64,
+    ) -> common::Result<Outcome> {
+        let result: common::Result<String> = async {
+            let entry = context.trash.restore(id).await?;
+            context
+                .relocate(&trash::trash_key(&entry), &entry.path)
+                .await?;
+            Ok(format!("Restored {}", entry.path))
+        }
+        .await;
+
+        Self::reply(&mut connection, result, "restore").await
+    }
+    ///Handles a purge request, removing trash entries for good
+    async fn handle_purge(
+        mut connection: ProtocolConnection,
+        context: &Context,
+        id: Option<u64>,
+    ) -> common::Result<Outcome> {
+        let result: common::Result<String> = async {
+            let purged = context.trash.purge(id).await?;
+            for entry in &purged {
+                context.forget(&trash::trash_key(entry)).await?;
+            }
+            Ok(format!(
+                "Permanently removed {} trash entries",
+                purged.len()
+            ))
+        }
+        .await;
+
+        Self::reply(&mut connection, result, "purge").await
+    }
+    ///Purges expired trash entries, versions and unreferenced bodies every 'SWEEP_INTERVAL'
+    async fn sweep(context: Arc<Context>) {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let expired: common::Result<usize> = async {
+                let purged = context.trash.expire().await?;
+                for entry in &purged {
+                    context.forget(&trash::trash_key(entry)).await?;
+                }
+                context.versions.expire().await?;
+                // bodies only expired entries linked to
+                let freed = context.objects.collect_garbage().await?
+                    + context.chunks.collect_garbage().await?;
+                if freed > 0 {
+                    info!("Freed {} bytes of unreferenced file bodies", freed);
+                }
+                Ok(purged.len())
+            }
+            .await;
+            match expired {
+                Ok(0) => {}
+                Ok(count) => info!("Purged {} expired trash entries", count),
+                Err(e) => error!("Failed to purge expired trash entries and versions: {}", e),
+            }
+        }
+    }
+    ///Handles a versions request
+    ///
+    /// Sends the kept previous versions of a file to the client, newest first
+    async fn handle_versions(
+        mut connection: ProtocolConnection,
+        context: &Context,
+        path: PathBuf,
+    ) -> common::Result<Outcome> {
+        let history = context.versions.list(&context.relative_key(&path)).await;
+        let payload = serde_json::to_vec(&history)?;
+        let payload_header = FileHeader::payload("versions", payload.len() as u64);
+        let str_header = serde_json::to_string(&payload_header)?;
+        connection.send_header(&str_header).await?;
+        connection.send_data(&payload).await?;
+        Ok(Outcome::Success)
+    }
+    ///Handles a request for the Merkle tree of a file
+    ///
+    /// Sends the block hashes of the file, files stored before trees were kept have none.
+    /// Clients check them against the root recorded with the file, sent with the download
+    async fn handle_tree(
+        mut connection: ProtocolConnection,
+        context: &Context,
+        path: PathBuf,
+    ) -> common::Result<Outcome> {
+        let key = context.relative_key(&path);
+        let (_, file_hash) = context.describe(&key).await?;
+        let Some(tree) = context.trees.load(&file_hash).await? else {
+            let reason = format!("No block hashes are stored for {key}");
+            let header = FileHeader::Error(reason.clone());
+            let str_header = serde_json::to_string(&header)?;
+            connection.send_header(&str_header).await?;
+            return Ok(Outcome::Rejected(reason));
         };
 
-        let response_header = match result {
-            Ok(()) => {
-                FileHeader::Success("Successfully deleted the requested file/folder".to_string())
+        let payload = serde_json::to_vec(&tree)?;
+        let payload_header = FileHeader::payload("tree", payload.len() as u64);
+        let str_header = serde_json::to_string(&payload_header)?;
+        connection.send_header(&str_header).await?;
+        connection.send_data(&payload).await?;
+        Ok(Outcome::Success)
+    }
+    ///Handles a download request for a previous version of a file
+    async fn handle_download_version(
+        mut connection: ProtocolConnection,
+        context: &Context,
+        path: PathBuf,
+        hash: String,
+        compression: Option<i32>,
+        integrity: bool,
+    ) -> common::Result<Outcome> {
+        let key = context.relative_key(&path);
+        let (version, blob) = match context.versions.find(&key, &hash).await {
+            Ok(found) => found,
+            Err(e) => {
+                let header = FileHeader::Error(e.to_string());
+                let str_header = serde_json::to_string(&header)?;
+                connection.send_header(&str_header).await?;
+                return Ok(Outcome::Rejected(e.to_string()));
             }
-            Err(e) => FileHeader::Error(format!("Failed to delete: {e}")),
+        };
+
+        let filename = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "download".to_string());
+
+        let compression = context.compression.negotiate(&key, compression);
+        let file_header = FileHeader::Upload {
+            name: filename,
+            size: version.size,
+            hash: version.hash.clone(),
+            signature: version.signature,
+            root: None,
+            compression,
+            integrity,
+            algorithm: Checksum::algorithm_of(&version.hash),
+        };
+        let serialized_header = serde_json::to_string(&file_header)?;
+        connection.send_header(&serialized_header).await?;
+        connection.set_compression(compression);
+        connection.set_integrity(integrity);
+        Self::send_body(&mut connection, context, &blob, version.size).await?;
+        Ok(Outcome::Served {
+            size: version.size,
+            hash: version.hash,
+        })
+    }
+    ///Handles a restore request for a previous version of a file
+    ///
+    /// The current contents are kept as a version themselves
+    async fn handle_restore_version(
+        mut connection: ProtocolConnection,
+        context: &Context,
+        path: PathBuf,
+        hash: String,
+    ) -> common::Result<Outcome> {
+        let user = Self::identity(&connection)?;
+        let key = context.relative_key(&path);
+
+        let result: common::Result<String> = async {
+            let (version, blob) = context.versions.find(&key, &hash).await?;
+            let reservation = context.quota.reserve(&user, &key, version.size)?;
+
+            // copy first, archiving the current contents may prune the version being restored
+            // a staging copy of its own, concurrent restores of the same version mustn't share one
+            let count = context.restores.fetch_add(1, Ordering::Relaxed);
+            let staged = format!("{META_DIR}/{}-{count}.restore", std::process::id());
+            let layout = context.layouts.get(&blob).await;
+            backend::copy(&*context.files, &blob, &staged).await?;
+            if backend::exists(&*context.files, &key).await {
+                let signature = context.signatures.load(&key).await?;
+                let recorded = context.hashes.load(&key).await?;
+                if let Err(e) = context.versions.archive(&key, recorded, signature).await {
+                    let _ = context.files.delete(&staged).await;
+                    return Err(e);
+                }
+            }
+            context.files.rename(&staged, &key).await?;
+            context.layouts.set(&key, layout).await?;
+
+            reservation.commit().await?;
+            // versions don't keep their tree root, the restored file is verified as a whole
+            context.hashes.save(&key, &version.hash, None).await?;
+            context
+                .signatures
+                .save(&key, version.signature.as_ref())
+                .await?;
+            Ok(format!("Restored {key} to version {}", version.hash))
+        }
+        .await;
+
+        Self::reply(&mut connection, result, "restore version").await
+    }
+    ///Sends a Success or Error reply for requests without a payload
+    async fn reply(
+        connection: &mut ProtocolConnection,
+        result: common::Result<String>,
+        action: &str,
+    ) -> common::Result<Outcome> {
+        let (response_header, outcome) = match result {
+            Ok(message) => (FileHeader::Success(message), Outcome::Success),
+            Err(e) => (
+                FileHeader::Error(format!("Failed to {action}: {e}")),
+                Outcome::Rejected(e.to_string()),
+            ),
         };
 
         let str_header = serde_json::to_string(&response_header)?;
         connection.send_header(&str_header).await?;
 
-        Ok(())
+        Ok(outcome)
     }
     ///Accept a single tcp connection
     /// # Returns
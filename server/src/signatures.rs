@@ -0,0 +1,126 @@
+//! Signed upload manifests kept next to the files they cover
+//!
+//! The server doesn't check signatures, it only stores them in `.veriflow/signatures/`
+//! (mirroring the resource directory) and hands them back with downloads.
+//! Clients verify them against their own trusted keys.
+
+use crate::META_DIR;
+use common::Signature;
+use std::path::{Path, PathBuf};
+
+// Signature directory inside the metadata directory
+const SIGNATURES_DIR: &str = "signatures";
+
+/// Signatures of the files in a resource directory
+pub struct SignatureStore {
+    dir: PathBuf,
+}
+
+impl SignatureStore {
+    pub fn new(root: &Path) -> SignatureStore {
+        SignatureStore {
+            dir: root.join(META_DIR).join(SIGNATURES_DIR),
+        }
+    }
+
+    // signature file of the file stored under `key`
+    fn sidecar(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.sig.json"))
+    }
+
+    /// Stores the signature of an uploaded file, an unsigned upload drops the old one
+    pub async fn save(&self, key: &str, signature: Option<&Signature>) -> common::Result<()> {
+        let sidecar = self.sidecar(key);
+        match signature {
+            Some(signature) => {
+                if let Some(parent) = sidecar.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::write(&sidecar, serde_json::to_vec(signature)?).await?;
+            }
+            None => {
+                if tokio::fs::try_exists(&sidecar).await? {
+                    tokio::fs::remove_file(&sidecar).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Signature of the file stored under `key`, None if it was uploaded unsigned
+    pub async fn load(&self, key: &str) -> common::Result<Option<Signature>> {
+        let sidecar = self.sidecar(key);
+        if !tokio::fs::try_exists(&sidecar).await? {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(
+            &tokio::fs::read(&sidecar).await?,
+        )?))
+    }
+
+    /// Moves the signatures of `from` and everything below it to `to` (trashed or restored files)
+    pub async fn rename(&self, from: &str, to: &str) -> common::Result<()> {
+        for (source, destination) in [
+            (self.sidecar(from), self.sidecar(to)),
+            (self.dir.join(from), self.dir.join(to)),
+        ] {
+            if tokio::fs::try_exists(&source).await? {
+                if let Some(parent) = destination.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::rename(&source, &destination).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the signatures of `key` and everything below it (deleted files)
+    pub async fn remove(&self, key: &str) -> common::Result<()> {
+        if key.is_empty() {
+            // the whole resource directory
+            if tokio::fs::try_exists(&self.dir).await? {
+                tokio::fs::remove_dir_all(&self.dir).await?;
+            }
+            return Ok(());
+        }
+        self.save(key, None).await?;
+        let dir = self.dir.join(key);
+        if tokio::fs::try_exists(&dir).await? {
+            tokio::fs::remove_dir_all(&dir).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_signature_sidecars() -> common::Result<()> {
+        let root = std::env::temp_dir().join(format!("veriflow-sigs-{}", std::process::id()));
+        let store = SignatureStore::new(&root);
+        let signature = Signature {
+            public_key: "ab".repeat(32),
+            signature: "cd".repeat(64),
+        };
+
+        store.save("docs/report.pdf", Some(&signature)).await?;
+        store.save("notes.txt", Some(&signature)).await?;
+        assert_eq!(
+            store.load("docs/report.pdf").await?,
+            Some(signature.clone())
+        );
+
+        // unsigned overwrites drop the old signature
+        store.save("notes.txt", None).await?;
+        assert_eq!(store.load("notes.txt").await?, None);
+
+        // deleting a directory drops the signatures below it
+        store.remove("docs").await?;
+        assert_eq!(store.load("docs/report.pdf").await?, None);
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+}
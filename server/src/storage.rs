@@ -0,0 +1,193 @@
+//! Content-addressed storage of file bodies
+//!
+//! In deduplicated mode every uploaded body is stored once in `.veriflow/objects/<sha256>`
+//! and the paths in the resource directory are hard links to it, so identical uploads under
+//! different names cost no extra disk. Bodies are only ever replaced, never written in place,
+//! so a shared object can't change under another path. Objects no path, trash entry or version
+//! links to anymore are removed by the periodic sweep.
+
+use crate::META_DIR;
+use common::hashing::Checksum;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::fs::File;
+
+// Object directory inside the metadata directory
+const OBJECTS_DIR: &str = "objects";
+// Uploads in progress, inside the object directory
+const INCOMING_DIR: &str = "incoming";
+
+/// Storage section of the server config
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)] // to only fill missing blanks
+pub struct Storage {
+    /// Store every distinct file body once, paths link to it
+    pub deduplicate: bool,
+    /// Skip the transfer when the server already stores a body with the uploaded hash
+    ///
+    /// Off by default: knowing a hash is enough to get the body linked under one's own name
+    /// and download it, and whether an upload was instant tells anyone the server stores it.
+    /// Only turn it on when every user may read every other user's files
+    pub instant_upload: bool,
+    /// Accept chunked uploads, stored as chunk lists so unchanged chunks are never sent twice
+    pub chunking: bool,
+    /// Keep uploaded files zstd compressed on disk, downloads decompress them
+    pub compress: bool,
+    /// zstd level files are compressed at rest with
+    pub compression_level: i32,
+    /// Send raw downloads of local files with sendfile (Linux), skipping user-space copies
+    pub zero_copy: bool,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            deduplicate: false,
+            instant_upload: false,
+            chunking: false,
+            compress: false,
+            compression_level: 3,
+            zero_copy: true,
+        }
+    }
+}
+
+/// File bodies of a resource directory keyed by their SHA-256
+pub struct ObjectStore {
+    dir: PathBuf,
+    // names uploads in progress
+    next_incoming: AtomicU64,
+}
+
+impl ObjectStore {
+    pub fn new(root: &Path) -> ObjectStore {
+        ObjectStore {
+            dir: root.join(META_DIR).join(OBJECTS_DIR),
+            next_incoming: AtomicU64::new(0),
+        }
+    }
+
+    /// Location of the body with `hash`
+    pub fn object(&self, hash: &str) -> PathBuf {
+        self.dir.join(hash)
+    }
+
+    /// Whether a body with `hash` and `size` is stored
+    pub async fn contains(&self, hash: &str, size: u64) -> common::Result<bool> {
+        // hashes come from clients, never let them name anything but an object
+        if Checksum::parse(hash).is_err() {
+            return Ok(false);
+        }
+        match tokio::fs::metadata(self.object(hash)).await {
+            Ok(metadata) => Ok(metadata.is_file() && metadata.len() == size),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Creates a file to receive an upload into, returns its path
+    pub async fn incoming(&self) -> common::Result<(PathBuf, File)> {
+        let dir = self.dir.join(INCOMING_DIR);
+        tokio::fs::create_dir_all(&dir).await?;
+        let id = self.next_incoming.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("{}-{id}", std::process::id()));
+        let file = File::create(&path).await?;
+        Ok((path, file))
+    }
+
+    /// Moves a verified upload into the store, dropping it if the body is already stored
+    pub async fn store(&self, incoming: &Path, hash: &str) -> common::Result<PathBuf> {
+        let object = self.object(hash);
+        if tokio::fs::try_exists(&object).await? {
+            tokio::fs::remove_file(incoming).await?;
+        } else {
+            tokio::fs::rename(incoming, &object).await?;
+        }
+        Ok(object)
+    }
+
+    /// Points `path` at the body with `hash`, replacing the file there
+    pub async fn link(&self, hash: &str, path: &Path) -> common::Result<()> {
+        // link aside then rename over the target, the path never goes missing
+        let dir = self.dir.join(INCOMING_DIR);
+        tokio::fs::create_dir_all(&dir).await?;
+        let id = self.next_incoming.fetch_add(1, Ordering::Relaxed);
+        let staged = dir.join(format!("{}-{id}.link", std::process::id()));
+        tokio::fs::hard_link(self.object(hash), &staged).await?;
+        tokio::fs::rename(&staged, path).await?;
+        Ok(())
+    }
+
+    /// Removes the bodies nothing links to anymore
+    ///
+    /// # Returns
+    /// The number of bytes freed
+    pub async fn collect_garbage(&self) -> common::Result<u64> {
+        if !tokio::fs::try_exists(&self.dir).await? {
+            return Ok(0);
+        }
+        let mut freed = 0;
+        let mut objects = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = objects.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if metadata.is_file() && !is_linked(&metadata) {
+                tokio::fs::remove_file(entry.path()).await?;
+                freed += metadata.len();
+            }
+        }
+        Ok(freed)
+    }
+}
+
+// whether anything but the store itself links to the body
+#[cfg(unix)]
+fn is_linked(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink() > 1
+}
+
+// link counts aren't available, keep everything
+#[cfg(not(unix))]
+fn is_linked(_metadata: &std::fs::Metadata) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::hashing;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_deduplicated_objects() -> common::Result<()> {
+        let root = std::env::temp_dir().join(format!("veriflow-objects-{}", std::process::id()));
+        tokio::fs::create_dir_all(&root).await?;
+        let store = ObjectStore::new(&root);
+        let hash = hashing::hash_bytes(b"artifact");
+
+        // the same body uploaded under two names is stored once
+        for name in ["a.bin", "b.bin"] {
+            let (incoming, mut file) = store.incoming().await?;
+            file.write_all(b"artifact").await?;
+            file.flush().await?;
+            store.store(&incoming, &hash).await?;
+            store.link(&hash, &root.join(name)).await?;
+        }
+        assert!(store.contains(&hash, 8).await?);
+        assert!(!store.contains(&hash, 9).await?);
+        assert!(!store.contains("../a.bin", 8).await?);
+        assert_eq!(tokio::fs::read(root.join("b.bin")).await?, b"artifact");
+
+        // the body stays while any path links to it
+        tokio::fs::remove_file(root.join("a.bin")).await?;
+        assert_eq!(store.collect_garbage().await?, 0);
+        tokio::fs::remove_file(root.join("b.bin")).await?;
+        if cfg!(unix) {
+            assert_eq!(store.collect_garbage().await?, 8);
+            assert!(!store.contains(&hash, 8).await?);
+        }
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+}
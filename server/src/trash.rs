@@ -0,0 +1,277 @@
+//! Server-side trash for deleted files and directories
+//!
+//! Deletes move the file/directory to `.veriflow/trash/<id>/` of the storage backend and record
+//! its original path and deletion time in `.veriflow/trash.json`. Entries can be restored until they are purged
+//! or expire after the retention period. Trashed bytes keep counting against their owner's
+//! quota until then.
+
+use crate::backend::{self, FileStore};
+use crate::META_DIR;
+use common::TrashEntry;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Trash directory and index inside the metadata directory
+const TRASH_DIR: &str = "trash";
+const INDEX_FILE: &str = "trash.json";
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Trash section of the server config
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)] // to only fill missing blanks
+pub struct Trash {
+    /// Deletes are permanent when disabled
+    pub enabled: bool,
+    /// Days before trashed files are purged, kept until purged by hand when not set
+    pub retention_days: Option<u64>,
+}
+
+impl Default for Trash {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            retention_days: Some(30),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct TrashIndex {
+    next_id: u64,
+    entries: Vec<TrashEntry>,
+}
+
+/// Trashed files of a resource directory
+pub struct TrashBin {
+    config: Trash,
+    root: PathBuf,
+    files: Arc<dyn FileStore>,
+    index: tokio::sync::Mutex<TrashIndex>,
+}
+
+// unix seconds
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Key of a trashed file/directory, relative to the resource directory like every other key
+pub fn trash_key(entry: &TrashEntry) -> String {
+    let name = entry.path.rsplit('/').next().unwrap_or(&entry.path);
+    format!("{META_DIR}/{TRASH_DIR}/{}/{name}", entry.id)
+}
+
+impl TrashBin {
+    /// Loads the trash index of the resource directory `root`, trashed files stay in `files`
+    pub async fn load(
+        config: Trash,
+        root: &Path,
+        files: Arc<dyn FileStore>,
+    ) -> common::Result<TrashBin> {
+        let index_path = root.join(META_DIR).join(INDEX_FILE);
+        let index = if tokio::fs::try_exists(&index_path).await? {
+            serde_json::from_slice(&tokio::fs::read(&index_path).await?)?
+        } else {
+            TrashIndex::default()
+        };
+
+        Ok(TrashBin {
+            config,
+            root: root.to_path_buf(),
+            files,
+            index: tokio::sync::Mutex::new(index),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Moves the file/directory stored under `key` into the trash
+    pub async fn trash(&self, key: &str) -> common::Result<TrashEntry> {
+        let stored = self.files.stat(key).await?;
+        let size = if stored.is_dir {
+            // total size of the files below the directory
+            self.files
+                .list(key)
+                .await?
+                .iter()
+                .map(|file| file.size)
+                .sum()
+        } else {
+            stored.size
+        };
+
+        let mut index = self.index.lock().await;
+        let deleted_at = now();
+        let entry = TrashEntry {
+            id: index.next_id,
+            path: key.to_string(),
+            is_dir: stored.is_dir,
+            size,
+            deleted_at,
+            expires_at: self
+                .config
+                .retention_days
+                .map(|days| deleted_at.saturating_add(days.saturating_mul(SECS_PER_DAY))),
+        };
+
+        self.files.rename(key, &trash_key(&entry)).await?;
+
+        index.next_id += 1;
+        index.entries.push(entry.clone());
+        self.persist(&index).await?;
+        Ok(entry)
+    }
+
+    /// Moves a trash entry back to its original path
+    pub async fn restore(&self, id: u64) -> common::Result<TrashEntry> {
+        let mut index = self.index.lock().await;
+        let position = index
+            .entries
+            .iter()
+            .position(|entry| entry.id == id)
+            .ok_or_else(|| not_found(id))?;
+        let entry = index.entries[position].clone();
+
+        if backend::exists(&*self.files, &entry.path).await {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists again, delete or move it first", entry.path),
+            )
+            .into());
+        }
+        self.files.rename(&trash_key(&entry), &entry.path).await?;
+        // the emptied entry directory is only left behind on the local disk
+        if let Some(entry_dir) = self.root.join(trash_key(&entry)).parent() {
+            let _ = tokio::fs::remove_dir(entry_dir).await;
+        }
+
+        index.entries.remove(position);
+        self.persist(&index).await?;
+        Ok(entry)
+    }
+
+    /// Permanently removes a trash entry, or every entry if no id is given
+    pub async fn purge(&self, id: Option<u64>) -> common::Result<Vec<TrashEntry>> {
+        if let Some(id) = id {
+            let index = self.index.lock().await;
+            if !index.entries.iter().any(|entry| entry.id == id) {
+                return Err(not_found(id));
+            }
+        }
+        self.purge_where(|entry| id.is_none_or(|id| entry.id == id))
+            .await
+    }
+
+    /// Permanently removes the entries past their retention period
+    pub async fn expire(&self) -> common::Result<Vec<TrashEntry>> {
+        let now = now();
+        self.purge_where(|entry| entry.expires_at.is_some_and(|expires| expires <= now))
+            .await
+    }
+
+    /// Every entry in the trash, oldest first
+    pub async fn list(&self) -> Vec<TrashEntry> {
+        self.index.lock().await.entries.clone()
+    }
+
+    async fn purge_where(
+        &self,
+        predicate: impl Fn(&TrashEntry) -> bool,
+    ) -> common::Result<Vec<TrashEntry>> {
+        let mut index = self.index.lock().await;
+        let (purged, kept): (Vec<TrashEntry>, Vec<TrashEntry>) =
+            index.entries.drain(..).partition(|entry| predicate(entry));
+        index.entries = kept;
+        if purged.is_empty() {
+            return Ok(purged);
+        }
+
+        for entry in &purged {
+            if let Some((entry_dir, _)) = trash_key(entry).rsplit_once('/') {
+                if backend::exists(&*self.files, entry_dir).await {
+                    self.files.delete(entry_dir).await?;
+                }
+            }
+        }
+        self.persist(&index).await?;
+        Ok(purged)
+    }
+
+    // write the trash index to disk
+    async fn persist(&self, index: &TrashIndex) -> common::Result<()> {
+        let snapshot = serde_json::to_vec_pretty(index)?;
+
+        let meta_dir = self.root.join(META_DIR);
+        tokio::fs::create_dir_all(&meta_dir).await?;
+        // write then rename so a crash never leaves a half written index
+        let tmp = meta_dir.join(format!("{INDEX_FILE}.tmp"));
+        tokio::fs::write(&tmp, snapshot).await?;
+        tokio::fs::rename(&tmp, meta_dir.join(INDEX_FILE)).await?;
+        Ok(())
+    }
+}
+
+fn not_found(id: u64) -> common::VeriflowError {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No trash entry with id {id}"),
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::LocalStore;
+
+    #[tokio::test]
+    async fn test_trash_restore_and_purge() -> common::Result<()> {
+        let root = std::env::temp_dir().join(format!("veriflow-trash-{}", std::process::id()));
+        tokio::fs::create_dir_all(root.join("docs")).await?;
+        tokio::fs::write(root.join("docs/a.txt"), b"hello").await?;
+        tokio::fs::write(root.join("b.txt"), b"bye").await?;
+
+        let files: Arc<dyn FileStore> = Arc::new(LocalStore::new(&root));
+        let bin = TrashBin::load(Trash::default(), &root, Arc::clone(&files)).await?;
+        let docs = bin.trash("docs").await?;
+        let b = bin.trash("b.txt").await?;
+        assert!(docs.is_dir && docs.size == 5);
+        assert!(docs.expires_at.is_some());
+        assert!(!tokio::fs::try_exists(root.join("docs")).await?);
+
+        // restore puts the tree back where it was, the index survives a restart
+        let bin = TrashBin::load(Trash::default(), &root, Arc::clone(&files)).await?;
+        bin.restore(docs.id).await?;
+        assert_eq!(tokio::fs::read(root.join("docs/a.txt")).await?, b"hello");
+
+        // restoring over a new file is refused
+        let b_again = bin.trash("docs/a.txt").await?;
+        tokio::fs::write(root.join("docs/a.txt"), b"new").await?;
+        assert!(bin.restore(b_again.id).await.is_err());
+
+        let purged = bin.purge(Some(b.id)).await?;
+        assert_eq!(purged, vec![b.clone()]);
+        assert!(bin.restore(b.id).await.is_err());
+        assert!(!tokio::fs::try_exists(root.join(trash_key(&b))).await?);
+
+        // zero days retention expires straight away
+        let expiring = Trash {
+            enabled: true,
+            retention_days: Some(0),
+        };
+        let bin = TrashBin::load(expiring, &root, files).await?;
+        bin.trash("docs").await?;
+        assert_eq!(bin.expire().await?.len(), 1);
+        assert_eq!(bin.list().await, vec![b_again]);
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+}
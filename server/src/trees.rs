@@ -0,0 +1,77 @@
+//! Merkle trees of stored files
+//!
+//! Every verified upload leaves the block hashes of its content in `.veriflow/trees/`, named by
+//! the hash of the content. Files with the same content share a tree, so moving, versioning
+//! or restoring a file keeps it. Clients fetch it to verify ranged and resumed downloads block
+//! by block.
+
+use crate::META_DIR;
+use common::hashing::{Checksum, MerkleTree};
+use std::path::{Path, PathBuf};
+
+// Tree directory inside the metadata directory
+const TREES_DIR: &str = "trees";
+
+/// Merkle trees of the contents stored in a resource directory
+pub struct TreeStore {
+    dir: PathBuf,
+}
+
+impl TreeStore {
+    pub fn new(root: &Path) -> TreeStore {
+        TreeStore {
+            dir: root.join(META_DIR).join(TREES_DIR),
+        }
+    }
+
+    // tree file of the content with `hash`
+    fn sidecar(&self, hash: &str) -> PathBuf {
+        self.dir.join(format!("{hash}.json"))
+    }
+
+    /// Stores the tree of the content with `hash`
+    pub async fn save(&self, hash: &str, tree: &MerkleTree) -> common::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        tokio::fs::write(self.sidecar(hash), serde_json::to_vec(tree)?).await?;
+        Ok(())
+    }
+
+    /// Tree of the content with `hash`, None if it was never stored
+    pub async fn load(&self, hash: &str) -> common::Result<Option<MerkleTree>> {
+        // hashes name files, never let them name anything else
+        if Checksum::parse(hash).is_err() {
+            return Ok(None);
+        }
+        let sidecar = self.sidecar(hash);
+        if !tokio::fs::try_exists(&sidecar).await? {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(
+            &tokio::fs::read(&sidecar).await?,
+        )?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::hashing::{self, MerkleBuilder};
+
+    #[tokio::test]
+    async fn test_tree_sidecars() -> common::Result<()> {
+        let root = std::env::temp_dir().join(format!("veriflow-trees-{}", std::process::id()));
+        let store = TreeStore::new(&root);
+        let mut builder = MerkleBuilder::new();
+        builder.update(b"report");
+        let tree = builder.finish();
+        let hash = hashing::hash_bytes(b"report");
+
+        store.save(&hash, &tree).await?;
+        assert_eq!(store.load(&hash).await?, Some(tree));
+        assert_eq!(store.load(&hashing::hash_bytes(b"other")).await?, None);
+        assert_eq!(store.load("../../etc/passwd").await?, None);
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+}
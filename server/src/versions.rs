@@ -0,0 +1,286 @@
+//! Previous versions of overwritten files
+//!
+//! Before an upload replaces a file its old contents are moved to `.veriflow/versions/<sha256>`
+//! of the storage backend and recorded in `.veriflow/versions.json` under the file's path. Contents are stored once per
+//! hash, however many paths or versions share them. Versions past the configured count or
+//! retention window are pruned. Kept versions don't count against user quotas.
+
+use crate::backend::{self, FileStore};
+use crate::layouts::LayoutIndex;
+use crate::{chunks, META_DIR};
+use common::{FileVersion, Signature};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Version contents and index inside the metadata directory
+const VERSIONS_DIR: &str = "versions";
+const INDEX_FILE: &str = "versions.json";
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Versions section of the server config
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)] // to only fill missing blanks
+pub struct Versions {
+    /// Previous versions kept per file, unlimited when not set, 0 disables versioning
+    pub max_versions: Option<usize>,
+    /// Days previous versions are kept, forever when not set
+    pub retention_days: Option<u64>,
+}
+
+impl Default for Versions {
+    fn default() -> Self {
+        Self {
+            max_versions: Some(10),
+            retention_days: None,
+        }
+    }
+}
+
+/// Previous versions of every file in a resource directory
+pub struct VersionStore {
+    config: Versions,
+    root: PathBuf,
+    files: Arc<dyn FileStore>,
+    layouts: Arc<LayoutIndex>,
+    // versions keyed by file path, oldest first
+    index: tokio::sync::Mutex<HashMap<String, Vec<FileVersion>>>,
+}
+
+// unix seconds
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+impl VersionStore {
+    /// Loads the version index of the resource directory `root`, contents are kept in `files`
+    pub async fn load(
+        config: Versions,
+        root: &Path,
+        files: Arc<dyn FileStore>,
+        layouts: Arc<LayoutIndex>,
+    ) -> common::Result<VersionStore> {
+        let index_path = root.join(META_DIR).join(INDEX_FILE);
+        let index = if tokio::fs::try_exists(&index_path).await? {
+            serde_json::from_slice(&tokio::fs::read(&index_path).await?)?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(VersionStore {
+            config,
+            root: root.to_path_buf(),
+            files,
+            layouts,
+            index: tokio::sync::Mutex::new(index),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.max_versions != Some(0)
+    }
+
+    // key the contents with `hash` are kept under
+    fn blob(&self, hash: &str) -> String {
+        format!("{META_DIR}/{VERSIONS_DIR}/{hash}")
+    }
+
+    /// Moves the current contents of the file stored under `key` into its history
+    ///
+    /// # Arguments
+    /// * 'recorded' - hash the contents were uploaded with, SHA256 is computed if 'None'
+    pub async fn archive(
+        &self,
+        key: &str,
+        recorded: Option<String>,
+        signature: Option<Signature>,
+    ) -> common::Result<FileVersion> {
+        // chunked files are kept as their chunk list, under the hash of their contents
+        let layout = self.layouts.get(key).await;
+        let (size, hash) = match recorded {
+            Some(hash) => {
+                let stored = self.files.stat(key).await?.size;
+                let size = chunks::logical_size(&*self.files, key, stored, layout).await?;
+                (size, hash)
+            }
+            None => chunks::describe(&*self.files, key, layout).await?,
+        };
+
+        let mut index = self.index.lock().await;
+        let blob = self.blob(&hash);
+        if backend::exists(&*self.files, &blob).await {
+            // same contents are already kept
+            self.files.delete(key).await?;
+            self.layouts.remove(key).await?;
+        } else {
+            self.files.rename(key, &blob).await?;
+            self.layouts.rename(key, &blob).await?;
+        }
+
+        let version = FileVersion {
+            hash,
+            size,
+            saved_at: now(),
+            signature,
+        };
+        let history = index.entry(key.to_string()).or_default();
+        // the same contents saved again only move to the front
+        history.retain(|kept| kept.hash != version.hash);
+        history.push(version.clone());
+
+        self.prune(&mut index).await?;
+        self.persist(&index).await?;
+        Ok(version)
+    }
+
+    /// Versions of the file stored under `key`, newest first
+    pub async fn list(&self, key: &str) -> Vec<FileVersion> {
+        let index = self.index.lock().await;
+        let mut history = index.get(key).cloned().unwrap_or_default();
+        history.reverse();
+        history
+    }
+
+    /// Finds a version of `key` by its hash or a unique prefix of it
+    ///
+    /// # Returns
+    /// The version and the key its contents are stored under
+    pub async fn find(&self, key: &str, hash: &str) -> common::Result<(FileVersion, String)> {
+        let index = self.index.lock().await;
+        let matches: Vec<&FileVersion> = index
+            .get(key)
+            .map(|history| {
+                history
+                    .iter()
+                    .filter(|version| !hash.is_empty() && version.hash.starts_with(hash))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        match matches.as_slice() {
+            [version] => Ok(((*version).clone(), self.blob(&version.hash))),
+            [] => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No version {hash} of {key}"),
+            )
+            .into()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Version {hash} of {key} is ambiguous, use more of the hash"),
+            )
+            .into()),
+        }
+    }
+
+    /// Drops versions past the retention window, for the periodic sweep
+    pub async fn expire(&self) -> common::Result<()> {
+        let mut index = self.index.lock().await;
+        if self.prune(&mut index).await? {
+            self.persist(&index).await?;
+        }
+        Ok(())
+    }
+
+    // drop versions past the count or age limit and contents no version refers to anymore
+    async fn prune(&self, index: &mut HashMap<String, Vec<FileVersion>>) -> common::Result<bool> {
+        let cutoff = self
+            .config
+            .retention_days
+            .map(|days| now().saturating_sub(days.saturating_mul(SECS_PER_DAY)));
+
+        let mut dropped = Vec::new();
+        for history in index.values_mut() {
+            if let Some(cutoff) = cutoff {
+                let (old, young): (Vec<FileVersion>, Vec<FileVersion>) = history
+                    .drain(..)
+                    .partition(|version| version.saved_at < cutoff);
+                *history = young;
+                dropped.extend(old);
+            }
+            if let Some(max) = self.config.max_versions {
+                if history.len() > max {
+                    let excess = history.len() - max;
+                    dropped.extend(history.drain(..excess));
+                }
+            }
+        }
+        index.retain(|_, history| !history.is_empty());
+
+        for version in &dropped {
+            let referenced = index
+                .values()
+                .flatten()
+                .any(|kept| kept.hash == version.hash);
+            let blob = self.blob(&version.hash);
+            if !referenced && backend::exists(&*self.files, &blob).await {
+                self.files.delete(&blob).await?;
+                self.layouts.remove(&blob).await?;
+            }
+        }
+        Ok(!dropped.is_empty())
+    }
+
+    // write the version index to disk
+    async fn persist(&self, index: &HashMap<String, Vec<FileVersion>>) -> common::Result<()> {
+        let snapshot = serde_json::to_vec_pretty(index)?;
+
+        let meta_dir = self.root.join(META_DIR);
+        tokio::fs::create_dir_all(&meta_dir).await?;
+        // write then rename so a crash never leaves a half written index
+        let tmp = meta_dir.join(format!("{INDEX_FILE}.tmp"));
+        tokio::fs::write(&tmp, snapshot).await?;
+        tokio::fs::rename(&tmp, meta_dir.join(INDEX_FILE)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::LocalStore;
+
+    #[tokio::test]
+    async fn test_version_history() -> common::Result<()> {
+        let root = std::env::temp_dir().join(format!("veriflow-versions-{}", std::process::id()));
+        tokio::fs::create_dir_all(&root).await?;
+        let path = root.join("notes.txt");
+        let config = Versions {
+            max_versions: Some(2),
+            retention_days: None,
+        };
+        let files: Arc<dyn FileStore> = Arc::new(LocalStore::new(&root));
+        let layouts = Arc::new(LayoutIndex::load(&root).await?);
+        let store = VersionStore::load(
+            config.clone(),
+            &root,
+            Arc::clone(&files),
+            Arc::clone(&layouts),
+        )
+        .await?;
+
+        let mut saved = Vec::new();
+        for content in ["one", "two", "three"] {
+            tokio::fs::write(&path, content).await?;
+            saved.push(store.archive("notes.txt", None, None).await?);
+        }
+
+        // only the newest two are kept, their contents stay addressable by hash
+        let store = VersionStore::load(config, &root, files, layouts).await?;
+        let history = store.list("notes.txt").await;
+        assert_eq!(history, vec![saved[2].clone(), saved[1].clone()]);
+        let (version, blob) = store.find("notes.txt", &saved[1].hash[..8]).await?;
+        assert_eq!(version.size, 3);
+        assert_eq!(tokio::fs::read(root.join(blob)).await?, b"two");
+        assert!(store.find("notes.txt", &saved[0].hash).await.is_err());
+        assert!(!tokio::fs::try_exists(root.join(store.blob(&saved[0].hash))).await?);
+
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+}